@@ -3,8 +3,10 @@ set INPUT (realpath $argv[1])
 set DIR (cd (dirname (status -f)); and pwd)
 cp $DIR/takobl/target/x86_64-unknown-uefi/release/takobl.efi $DIR/esp/efi/boot/bootx64.efi
 cp $INPUT $DIR/esp/kernel.elf
+sha256sum $DIR/esp/kernel.elf | cut -d" " -f1 > $DIR/esp/kernel.elf.sha256
 qemu-system-x86_64 \
     -m 4G -s \
+    -serial stdio \
     -enable-kvm \
     -cpu host \
     -drive if=pflash,format=raw,readonly=on,file=/usr/share/qemu/ovmf-x86_64.bin \
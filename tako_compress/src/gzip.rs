@@ -0,0 +1,117 @@
+use alloc::vec::Vec;
+
+use crate::inflate::inflate;
+use crate::Error;
+
+const FLAG_HCRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+/// Deflate can't expand by more than this, which bounds what a corrupt size
+/// in the trailer can make us reserve.
+const MAX_RATIO: usize = 1032;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn skip_string(data: &[u8], offset: usize) -> Result<usize, Error> {
+    let length = data
+        .get(offset..)
+        .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+        .ok_or(Error::Truncated)?;
+    Ok(offset + length + 1)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = data.get(offset..offset + 4).ok_or(Error::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Returns the offset of the DEFLATE stream in a member starting at `data`.
+fn parse_header(data: &[u8]) -> Result<usize, Error> {
+    if data.len() < 10 {
+        return Err(Error::Truncated);
+    }
+    if data[0] != 0x1F || data[1] != 0x8B {
+        return Err(Error::InvalidData("not a gzip member"));
+    }
+    if data[2] != 8 {
+        return Err(Error::InvalidData("unknown gzip compression method"));
+    }
+    let flags = data[3];
+    let mut offset = 10;
+    if flags & FLAG_EXTRA != 0 {
+        let bytes = data.get(offset..offset + 2).ok_or(Error::Truncated)?;
+        offset += 2 + u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    }
+    if flags & FLAG_NAME != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & FLAG_COMMENT != 0 {
+        offset = skip_string(data, offset)?;
+    }
+    if flags & FLAG_HCRC != 0 {
+        offset += 2;
+    }
+    Ok(offset)
+}
+
+/// Decompresses every member of a gzip file; `cat a.gz b.gz` is valid gzip.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    // The last four bytes are the size of the (last) member mod 2^32.
+    let size_hint = data
+        .len()
+        .checked_sub(4)
+        .map_or(Ok(0), |end| read_u32(data, end))?;
+    let mut output = Vec::with_capacity((size_hint as usize).min(data.len() * MAX_RATIO));
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let member = &data[offset..];
+        let start = parse_header(member)?;
+        let output_start = output.len();
+        let length = inflate(member.get(start..).ok_or(Error::Truncated)?, &mut output)?;
+
+        let trailer = start + length;
+        let crc = read_u32(member, trailer)?;
+        let size = read_u32(member, trailer + 4)?;
+        let decompressed = &output[output_start..];
+        if crc32(decompressed) != crc || decompressed.len() as u32 != size {
+            return Err(Error::ChecksumMismatch);
+        }
+        offset += trailer + 8;
+
+        // Some tools pad the file with zeroes.
+        if data[offset..].iter().all(|&byte| byte == 0) {
+            break;
+        }
+    }
+    Ok(output)
+}
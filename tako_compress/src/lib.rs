@@ -0,0 +1,146 @@
+#![no_std]
+
+extern crate alloc;
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+mod gzip;
+mod inflate;
+mod lz4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    /// LZ4 frame format, as written by `lz4`.
+    Lz4,
+    /// The older LZ4 format written by `lz4 -l`, common for Linux initrds.
+    Lz4Legacy,
+}
+
+/// zstd isn't supported, so `detect` leaves it alone; loaders can check for
+/// this to warn that a module stays compressed.
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Truncated,
+    InvalidData(&'static str),
+    ChecksumMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "compressed data is truncated"),
+            Error::InvalidData(reason) => write!(f, "invalid compressed data: {}", reason),
+            Error::ChecksumMismatch => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+/// Recognises compressed data by its magic number.
+pub fn detect(data: &[u8]) -> Option<Format> {
+    match data {
+        [0x1F, 0x8B, ..] => Some(Format::Gzip),
+        [0x04, 0x22, 0x4D, 0x18, ..] => Some(Format::Lz4),
+        [0x02, 0x21, 0x4C, 0x18, ..] => Some(Format::Lz4Legacy),
+        _ => None,
+    }
+}
+
+pub fn decompress(format: Format, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::Gzip => gzip::decompress(data),
+        Format::Lz4 => lz4::decompress_frame(data),
+        Format::Lz4Legacy => lz4::decompress_legacy(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: &[u8] = b"tako tako tako tako takos boots from a ramdisk\ntako tako tako tako\n";
+
+    // `gzip -9 -n`, `lz4 -9 --content-size` and `lz4 -9 -l` of `PLAIN`.
+    const GZIP: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x2b, 0x49, 0xcc, 0xce, 0x57,
+        0x28, 0xc1, 0x24, 0x8a, 0x15, 0x92, 0xf2, 0xf3, 0x4b, 0x8a, 0x15, 0xd2, 0x8a, 0xf2, 0x73,
+        0x15, 0x12, 0x15, 0x8a, 0x12, 0x73, 0x53, 0x32, 0x8b, 0xb3, 0xb9, 0xb0, 0xa8, 0xe4, 0x02,
+        0x00, 0xf6, 0x32, 0x8a, 0x41, 0x43, 0x00, 0x00, 0x00,
+    ];
+    const LZ4: &[u8] = &[
+        0x04, 0x22, 0x4d, 0x18, 0x6c, 0x40, 0x43, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x74,
+        0x2a, 0x00, 0x00, 0x00, 0x5f, 0x74, 0x61, 0x6b, 0x6f, 0x20, 0x05, 0x00, 0x00, 0xfb, 0x08,
+        0x73, 0x20, 0x62, 0x6f, 0x6f, 0x74, 0x73, 0x20, 0x66, 0x72, 0x6f, 0x6d, 0x20, 0x61, 0x20,
+        0x72, 0x61, 0x6d, 0x64, 0x69, 0x73, 0x6b, 0x0a, 0x2a, 0x00, 0x50, 0x74, 0x61, 0x6b, 0x6f,
+        0x0a, 0x00, 0x00, 0x00, 0x00, 0x01, 0xca, 0x5c, 0x80,
+    ];
+    const LZ4_LEGACY: &[u8] = &[
+        0x02, 0x21, 0x4c, 0x18, 0x2a, 0x00, 0x00, 0x00, 0x5f, 0x74, 0x61, 0x6b, 0x6f, 0x20, 0x05,
+        0x00, 0x00, 0xfb, 0x08, 0x73, 0x20, 0x62, 0x6f, 0x6f, 0x74, 0x73, 0x20, 0x66, 0x72, 0x6f,
+        0x6d, 0x20, 0x61, 0x20, 0x72, 0x61, 0x6d, 0x64, 0x69, 0x73, 0x6b, 0x0a, 0x2a, 0x00, 0x50,
+        0x74, 0x61, 0x6b, 0x6f, 0x0a,
+    ];
+
+    #[test]
+    fn round_trip() {
+        for (data, format) in [
+            (GZIP, Format::Gzip),
+            (LZ4, Format::Lz4),
+            (LZ4_LEGACY, Format::Lz4Legacy),
+        ] {
+            assert_eq!(detect(data), Some(format));
+            assert_eq!(decompress(format, data).unwrap(), PLAIN, "{:?}", format);
+        }
+        let mut twice = GZIP.to_vec();
+        twice.extend_from_slice(GZIP);
+        assert_eq!(decompress(Format::Gzip, &twice).unwrap(), PLAIN.repeat(2));
+    }
+
+    #[test]
+    fn truncated() {
+        for end in 1..GZIP.len() {
+            assert!(decompress(Format::Gzip, &GZIP[..end]).is_err(), "{}", end);
+        }
+        for end in 1..LZ4.len() {
+            assert!(decompress(Format::Lz4, &LZ4[..end]).is_err(), "{}", end);
+        }
+        // Only the magic number is a valid, empty stream.
+        for end in 5..LZ4_LEGACY.len() {
+            assert!(
+                decompress(Format::Lz4Legacy, &LZ4_LEGACY[..end]).is_err(),
+                "{}",
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt_sizes() {
+        let mut lz4 = LZ4.to_vec();
+        lz4[6..14].fill(0xFF);
+        // The header checksum isn't checked, so this decodes.
+        assert_eq!(decompress(Format::Lz4, &lz4).unwrap(), PLAIN);
+
+        let mut gzip = GZIP.to_vec();
+        let end = gzip.len();
+        gzip[end - 4..].fill(0xFF);
+        assert_eq!(
+            decompress(Format::Gzip, &gzip),
+            Err(Error::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn corrupt_data() {
+        let mut gzip = GZIP.to_vec();
+        gzip[20] ^= 0x55;
+        assert!(decompress(Format::Gzip, &gzip).is_err());
+        let mut lz4 = LZ4.to_vec();
+        lz4[40] ^= 0x55;
+        assert_eq!(decompress(Format::Lz4, &lz4), Err(Error::ChecksumMismatch));
+    }
+}
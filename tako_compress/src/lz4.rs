@@ -0,0 +1,236 @@
+use alloc::vec::Vec;
+
+use crate::Error;
+
+const FRAME_MAGIC: u32 = 0x184D_2204;
+const LEGACY_MAGIC: u32 = 0x184C_2102;
+const SKIPPABLE_MAGIC_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const LEGACY_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+const FLAG_VERSION_MASK: u8 = 0b1100_0000;
+const FLAG_VERSION: u8 = 0b0100_0000;
+const FLAG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLAG_CONTENT_SIZE: u8 = 1 << 3;
+const FLAG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLAG_DICTIONARY_ID: u8 = 1 << 0;
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+/// A block can't expand by more than this, which bounds what a corrupt
+/// content size can make us reserve.
+const MAX_RATIO: usize = 255;
+
+const PRIME32_1: u32 = 0x9E37_79B1;
+const PRIME32_2: u32 = 0x85EB_CA77;
+const PRIME32_3: u32 = 0xC2B2_AE3D;
+const PRIME32_4: u32 = 0x27D4_EB2F;
+const PRIME32_5: u32 = 0x1656_67B1;
+
+fn xxh32_round(accumulator: u32, lane: u32) -> u32 {
+    accumulator
+        .wrapping_add(lane.wrapping_mul(PRIME32_2))
+        .rotate_left(13)
+        .wrapping_mul(PRIME32_1)
+}
+
+fn read_le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+/// xxHash32 with seed 0, used for the frame's content checksum.
+fn xxh32(data: &[u8]) -> u32 {
+    let mut offset = 0;
+    let mut hash = if data.len() >= 16 {
+        let mut lanes = [
+            PRIME32_1.wrapping_add(PRIME32_2),
+            PRIME32_2,
+            0,
+            0u32.wrapping_sub(PRIME32_1),
+        ];
+        while offset + 16 <= data.len() {
+            for (i, lane) in lanes.iter_mut().enumerate() {
+                *lane = xxh32_round(*lane, read_le32(data, offset + i * 4));
+            }
+            offset += 16;
+        }
+        lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18))
+    } else {
+        PRIME32_5
+    };
+    hash = hash.wrapping_add(data.len() as u32);
+
+    while offset + 4 <= data.len() {
+        hash = hash
+            .wrapping_add(read_le32(data, offset).wrapping_mul(PRIME32_3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME32_4);
+        offset += 4;
+    }
+    for &byte in &data[offset..] {
+        hash = hash
+            .wrapping_add((byte as u32).wrapping_mul(PRIME32_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME32_1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME32_2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME32_3);
+    hash ^ (hash >> 16)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = data.get(offset..offset + 4).ok_or(Error::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_length(data: &[u8], offset: &mut usize, mut length: usize) -> Result<usize, Error> {
+    loop {
+        let byte = *data.get(*offset).ok_or(Error::Truncated)?;
+        *offset += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decompresses one LZ4 block. Matches may reach back into earlier blocks
+/// already in `output`.
+fn decompress_block(block: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+    let mut offset = 0;
+    while offset < block.len() {
+        let token = block[offset];
+        offset += 1;
+
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = read_length(block, &mut offset, literals)?;
+        }
+        let bytes = block
+            .get(offset..offset + literals)
+            .ok_or(Error::Truncated)?;
+        output.extend_from_slice(bytes);
+        offset += literals;
+        // The last sequence has only literals.
+        if offset == block.len() {
+            break;
+        }
+
+        let distance = block
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or(Error::Truncated)?;
+        offset += 2;
+        if distance == 0 || distance > output.len() {
+            return Err(Error::InvalidData("invalid match offset"));
+        }
+        let mut length = (token & 0xF) as usize;
+        if length == 15 {
+            length = read_length(block, &mut offset, length)?;
+        }
+        let start = output.len() - distance;
+        for i in 0..length + MIN_MATCH {
+            let byte = output[start + i];
+            output.push(byte);
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses an LZ4 frame, possibly followed by more frames. The content
+/// checksum is verified; block and header checksums are skipped.
+pub fn decompress_frame(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let magic = read_u32(data, offset)?;
+        offset += 4;
+        if magic & SKIPPABLE_MAGIC_MASK == SKIPPABLE_MAGIC {
+            offset += 4 + read_u32(data, offset)? as usize;
+            continue;
+        }
+        if magic != FRAME_MAGIC {
+            return Err(Error::InvalidData("not an LZ4 frame"));
+        }
+
+        let flags = *data.get(offset).ok_or(Error::Truncated)?;
+        if flags & FLAG_VERSION_MASK != FLAG_VERSION {
+            return Err(Error::InvalidData("unknown LZ4 frame version"));
+        }
+        // Flags and block descriptor.
+        offset += 2;
+        if flags & FLAG_CONTENT_SIZE != 0 {
+            let bytes = data.get(offset..offset + 8).ok_or(Error::Truncated)?;
+            let size = u64::from_le_bytes(bytes.try_into().unwrap());
+            output.reserve((size as usize).min(data.len() * MAX_RATIO));
+            offset += 8;
+        }
+        if flags & FLAG_DICTIONARY_ID != 0 {
+            return Err(Error::InvalidData("LZ4 dictionaries are not supported"));
+        }
+        // Header checksum.
+        offset += 1;
+
+        let frame_start = output.len();
+        loop {
+            let header = read_u32(data, offset)?;
+            offset += 4;
+            if header == 0 {
+                break;
+            }
+            let size = (header & !BLOCK_UNCOMPRESSED) as usize;
+            let block = data.get(offset..offset + size).ok_or(Error::Truncated)?;
+            if header & BLOCK_UNCOMPRESSED != 0 {
+                output.extend_from_slice(block);
+            } else {
+                decompress_block(block, &mut output)?;
+            }
+            offset += size;
+            if flags & FLAG_BLOCK_CHECKSUM != 0 {
+                offset += 4;
+            }
+        }
+        if flags & FLAG_CONTENT_CHECKSUM != 0 {
+            if read_u32(data, offset)? != xxh32(&output[frame_start..]) {
+                return Err(Error::ChecksumMismatch);
+            }
+            offset += 4;
+        }
+    }
+    Ok(output)
+}
+
+/// Decompresses the legacy format: blocks of up to 8 MiB without checksums.
+/// Concatenated streams repeat the magic number.
+pub fn decompress_legacy(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = read_u32(data, offset)?;
+        offset += 4;
+        if header == LEGACY_MAGIC {
+            continue;
+        }
+        let size = header as usize;
+        if size > LEGACY_BLOCK_SIZE * 2 {
+            // Whatever follows isn't part of this stream.
+            break;
+        }
+        let block = data.get(offset..offset + size).ok_or(Error::Truncated)?;
+        decompress_block(block, &mut output)?;
+        offset += size;
+    }
+    Ok(output)
+}
@@ -0,0 +1,145 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
+
+use elf::endian::AnyEndian;
+use elf::ElfBytes;
+use log::info;
+use takobl_api::tags::{
+    align_tag, AcpiTag, MemoryMapEntry, ModuleHeader, SmbiosTag, SymbolsHeader, TagHeader,
+    TAG_ACPI, TAG_COMMAND_LINE, TAG_DEVICE_PATH, TAG_END, TAG_MEMORY_MAP, TAG_MODULE, TAG_SMBIOS,
+    TAG_SYMBOLS,
+};
+use takobl_api::{BootSlice, PHYSICAL_MEMORY_OFFSET};
+use uefi::prelude::*;
+use uefi::table::boot::PAGE_SIZE;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID, SMBIOS3_GUID, SMBIOS_GUID};
+use uefi::Guid;
+
+use crate::paging::{get_memory_map, runtime_virtual_address, PageTableBuilder};
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Collects the tags that follow `BootData`, see `takobl_api::tags`.
+pub struct TagBuilder {
+    data: Vec<u8>,
+}
+
+impl TagBuilder {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    fn push(&mut self, kind: u32, parts: &[&[u8]]) {
+        let size = size_of::<TagHeader>() + parts.iter().map(|part| part.len()).sum::<usize>();
+        let header = TagHeader {
+            kind,
+            size: size as u32,
+        };
+        self.data.extend_from_slice(as_bytes(&header));
+        for part in parts {
+            self.data.extend_from_slice(part);
+        }
+        self.data.resize(align_tag(self.data.len()), 0);
+    }
+
+    pub fn command_line(&mut self, command_line: &str) {
+        self.push(TAG_COMMAND_LINE, &[command_line.as_bytes()]);
+    }
+
+    pub fn module(&mut self, name: &str, data: BootSlice) {
+        let header = ModuleHeader { data };
+        self.push(TAG_MODULE, &[as_bytes(&header), name.as_bytes()]);
+    }
+
+    pub fn device_path(&mut self, device_path: &[u8]) {
+        self.push(TAG_DEVICE_PATH, &[device_path]);
+    }
+
+    pub fn firmware_tables(&mut self, st: &SystemTable<Boot>) {
+        let find = |guid: Guid| {
+            st.config_table()
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map(|entry| entry.address as u64)
+        };
+        let acpi = match find(ACPI2_GUID) {
+            Some(rsdp) => Some(AcpiTag { rsdp, revision: 2 }),
+            None => find(ACPI_GUID).map(|rsdp| AcpiTag { rsdp, revision: 0 }),
+        };
+        if let Some(acpi) = acpi {
+            info!("ACPI RSDP: {:016X}", acpi.rsdp);
+            self.push(TAG_ACPI, &[as_bytes(&acpi)]);
+        }
+        let smbios = match find(SMBIOS3_GUID) {
+            Some(entry_point) => Some(SmbiosTag {
+                entry_point,
+                major_version: 3,
+            }),
+            None => find(SMBIOS_GUID).map(|entry_point| SmbiosTag {
+                entry_point,
+                major_version: 2,
+            }),
+        };
+        if let Some(smbios) = smbios {
+            info!("SMBIOS entry point: {:016X}", smbios.entry_point);
+            self.push(TAG_SMBIOS, &[as_bytes(&smbios)]);
+        }
+    }
+
+    pub fn symbols(&mut self, elf: &ElfBytes<AnyEndian>) {
+        let Ok(Some(symtab)) = elf.section_header_by_name(".symtab") else {
+            info!("Kernel has no symbol table");
+            return;
+        };
+        let strtab = elf
+            .section_headers()
+            .and_then(|headers| headers.get(symtab.sh_link as usize).ok())
+            .expect("Symbol table without string table");
+        let (symtab, _) = elf.section_data(&symtab).expect("Couldn't read .symtab");
+        let (strtab, _) = elf.section_data(&strtab).expect("Couldn't read .strtab");
+        let header = SymbolsHeader {
+            symtab_size: symtab.len() as u64,
+            strtab_size: strtab.len() as u64,
+        };
+        self.push(TAG_SYMBOLS, &[as_bytes(&header), symtab, strtab]);
+    }
+
+    pub fn memory_map(&mut self, bs: &BootServices) {
+        let mut buffer = Vec::new();
+        let memory_map = get_memory_map(bs, &mut buffer);
+        let entries: Vec<MemoryMapEntry> = memory_map
+            .entries()
+            .map(|entry| MemoryMapEntry {
+                kind: entry.ty.0,
+                padding: 0,
+                physical_start: entry.phys_start,
+                virtual_start: runtime_virtual_address(entry).unwrap_or(entry.virt_start),
+                pages: entry.page_count,
+                attribute: entry.att.bits(),
+            })
+            .collect();
+        let entries = unsafe {
+            slice::from_raw_parts(
+                entries.as_ptr() as *const u8,
+                entries.len() * size_of::<MemoryMapEntry>(),
+            )
+        };
+        self.push(TAG_MEMORY_MAP, &[entries]);
+    }
+
+    /// Copies the tags to pages the kernel won't reuse and returns their
+    /// address in the physical memory map.
+    pub fn finish(mut self, page_table_builder: &mut PageTableBuilder) -> u64 {
+        self.push(TAG_END, &[]);
+        let pages = ((self.data.len() + PAGE_SIZE - 1) / PAGE_SIZE) as u64;
+        let addr = page_table_builder.allocate_physical(pages);
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.data.as_ptr(), addr as *mut u8, self.data.len());
+        }
+        info!("Boot tags: {} bytes at {:08X}", self.data.len(), addr);
+        addr + PHYSICAL_MEMORY_OFFSET
+    }
+}
@@ -0,0 +1,232 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use log::{info, warn, LevelFilter};
+use uefi::fs::{self, Path};
+use uefi::prelude::*;
+
+use crate::verify::Verify;
+
+/// A file loaded next to the kernel, found by takos under `name`.
+#[derive(Debug, Clone)]
+pub struct BootModule {
+    pub name: String,
+    pub path: String,
+}
+
+impl BootModule {
+    fn new(name: &str, path: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            path: path.to_string(),
+        }
+    }
+}
+
+/// A kernel, its modules and command line, selectable in the boot menu.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub name: String,
+    pub kernel: String,
+    pub modules: Vec<BootModule>,
+    pub cmdline: String,
+    pub verify: Verify,
+}
+
+impl Default for BootEntry {
+    fn default() -> Self {
+        Self {
+            name: "TakOS".to_string(),
+            kernel: "kernel.elf".to_string(),
+            modules: vec![BootModule::new("initrd", "ramdisk.img")],
+            cmdline: String::new(),
+            verify: Verify::None,
+        }
+    }
+}
+
+/// Settings read from `takobl.cfg` on the ESP. The file is a list of
+/// `key = value` lines; `#` starts a comment. Every `[name]` header starts a
+/// boot menu entry, which takes its kernel, modules and cmdline from the
+/// keys before the first header unless it sets its own. `module = name path`
+/// adds a module; `ramdisk = path` is short for `module = initrd path`.
+/// `verify = none | sha256 | signature` checks the kernel before booting it,
+/// see `Verify`; a takobl built with a kernel key always checks signatures,
+/// of the modules too.
+/// `show_crash_report = false` boots without showing the report a crashed
+/// kernel left; it's still copied to `takos-crash.txt`.
+///
+/// ```text
+/// timeout = 5
+/// resolution = 1280x720
+/// loglevel = info
+/// kernel = kernel.elf
+/// ramdisk = ramdisk.img
+/// module = font fonts/default.psf
+/// verify = signature
+///
+/// [TakOS]
+/// cmdline = loglevel=debug
+///
+/// [TakOS (testing)]
+/// kernel = kernel-testing.elf
+/// cmdline = test=threads
+/// ```
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    pub entries: Vec<BootEntry>,
+    /// Entry selected when no choice is remembered.
+    pub default: Option<String>,
+    /// Seconds before the selected entry boots; 0 skips the menu.
+    pub timeout: usize,
+    /// Path of a UEFI shell on the ESP, offered in the boot menu.
+    pub shell: Option<String>,
+    pub resolution: Option<(usize, usize)>,
+    pub log_level: LevelFilter,
+    pub show_crash_report: bool,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            entries: vec![BootEntry::default()],
+            default: None,
+            timeout: 5,
+            shell: None,
+            resolution: None,
+            log_level: LevelFilter::Info,
+            show_crash_report: true,
+        }
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+// Modules listed in a section replace the inherited ones rather than adding
+// to them.
+struct EntryBuilder {
+    entry: BootEntry,
+    modules: Vec<BootModule>,
+}
+
+impl EntryBuilder {
+    fn new(entry: BootEntry) -> Self {
+        Self {
+            entry,
+            modules: Vec::new(),
+        }
+    }
+
+    fn build(mut self) -> BootEntry {
+        if !self.modules.is_empty() {
+            self.entry.modules = self.modules;
+        }
+        self.entry
+    }
+}
+
+impl BootConfig {
+    pub fn parse(text: &str) -> Self {
+        let mut config = BootConfig::default();
+        let mut global = EntryBuilder::new(BootEntry::default());
+        let mut sections: Vec<EntryBuilder> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let mut entry = global.entry.clone();
+                if !global.modules.is_empty() {
+                    entry.modules = global.modules.clone();
+                }
+                entry.name = name.trim().to_string();
+                sections.push(EntryBuilder::new(entry));
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("takobl.cfg:{}: expected `key = value`", number + 1);
+                continue;
+            };
+            let value = value.trim();
+            let current = sections.last_mut().unwrap_or(&mut global);
+            match key.trim() {
+                "kernel" => current.entry.kernel = value.to_string(),
+                "ramdisk" => current.modules.push(BootModule::new("initrd", value)),
+                "module" => match value.split_once(char::is_whitespace) {
+                    Some((name, path)) => current.modules.push(BootModule::new(name, path.trim())),
+                    None => warn!("takobl.cfg:{}: expected `module = name path`", number + 1),
+                },
+                "cmdline" => current.entry.cmdline = value.to_string(),
+                "verify" => match Verify::parse(value) {
+                    Some(verify) => current.entry.verify = verify,
+                    None => warn!("takobl.cfg:{}: invalid verify mode", number + 1),
+                },
+                "default" => config.default = Some(value.to_string()),
+                "timeout" => match value.parse() {
+                    Ok(timeout) => config.timeout = timeout,
+                    Err(_) => warn!("takobl.cfg:{}: invalid timeout", number + 1),
+                },
+                "shell" => config.shell = Some(value.to_string()),
+                "resolution" => match parse_resolution(value) {
+                    Some(resolution) => config.resolution = Some(resolution),
+                    None => warn!("takobl.cfg:{}: invalid resolution", number + 1),
+                },
+                "loglevel" => match value.parse() {
+                    Ok(level) => config.log_level = level,
+                    Err(_) => warn!("takobl.cfg:{}: invalid log level", number + 1),
+                },
+                "show_crash_report" => match value.parse() {
+                    Ok(show) => config.show_crash_report = show,
+                    Err(_) => warn!("takobl.cfg:{}: expected true or false", number + 1),
+                },
+                key => warn!("takobl.cfg:{}: unknown key `{}`", number + 1, key),
+            }
+        }
+        config.entries = if sections.is_empty() {
+            vec![global.build()]
+        } else {
+            sections.into_iter().map(EntryBuilder::build).collect()
+        };
+        config
+    }
+
+    /// The command line handed to the kernel. The loader's log level is
+    /// passed on unless the configured command line sets its own.
+    pub fn kernel_command_line(&self, entry: &BootEntry) -> String {
+        if entry
+            .cmdline
+            .split_whitespace()
+            .any(|option| option.starts_with("loglevel="))
+        {
+            return entry.cmdline.clone();
+        }
+        let level = self.log_level.as_str().to_ascii_lowercase();
+        if entry.cmdline.is_empty() {
+            format!("loglevel={}", level)
+        } else {
+            format!("{} loglevel={}", entry.cmdline, level)
+        }
+    }
+
+    pub fn load(image_handle: Handle, bs: &BootServices) -> Self {
+        let mut fs: fs::FileSystem<'_> = bs
+            .get_image_file_system(image_handle)
+            .expect("Couldn't get filesystem");
+        match fs.read(Path::new(cstr16!("takobl.cfg"))) {
+            Ok(data) => {
+                let config = BootConfig::parse(&String::from_utf8_lossy(&data));
+                info!("Config: {:?}", config);
+                config
+            }
+            Err(_) => {
+                info!("No takobl.cfg, using defaults");
+                BootConfig::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use core::fmt::Write;
+
+use log::{info, warn};
+use uefi::fs::Path;
+use uefi::prelude::*;
+use uefi::proto::console::text::Color;
+use uefi::{cstr16, CStr16};
+
+use crate::menu::TAKOBL_VENDOR;
+
+/// takos keeps its reports a bit smaller than this.
+const MAX_CRASH_REPORT: usize = 8 * 1024;
+/// Where the report is kept on the ESP; each crash replaces it.
+const CRASH_REPORT_FILE: &CStr16 = cstr16!("takos-crash.txt");
+
+/// takos has no disk driver, so it leaves its report in a UEFI variable.
+/// This copies the report to `takos-crash.txt` on the ESP and, if `show` is
+/// set, shows it and waits for a key. takos deletes the variable once it
+/// boots, so a report is only handled once.
+pub fn handle_crash_report(image_handle: Handle, st: &mut SystemTable<Boot>, show: bool) {
+    let mut buffer = [0u8; MAX_CRASH_REPORT];
+    let report = match st.runtime_services().get_variable(
+        cstr16!("TakosCrashReport"),
+        &TAKOBL_VENDOR,
+        &mut buffer,
+    ) {
+        Ok((data, _)) => data,
+        Err(error) if error.status() == Status::NOT_FOUND => return,
+        Err(error) => {
+            warn!("Couldn't read the crash report: {:?}", error);
+            return;
+        }
+    };
+    warn!("The kernel crashed during the previous boot");
+    match st
+        .boot_services()
+        .get_image_file_system(image_handle)
+        .map(|mut fs| fs.write(Path::new(CRASH_REPORT_FILE), &*report))
+    {
+        Ok(Ok(())) => info!("Saved the crash report to {}", CRASH_REPORT_FILE),
+        Ok(Err(error)) => warn!("Couldn't write {}: {:?}", CRASH_REPORT_FILE, error),
+        Err(error) => warn!("Couldn't open the ESP: {:?}", error),
+    }
+    if !show {
+        return;
+    }
+    // Only the log at the end can be cut off mid-character.
+    let report = match core::str::from_utf8(report) {
+        Ok(report) => report,
+        Err(error) => core::str::from_utf8(&report[..error.valid_up_to()]).unwrap(),
+    };
+    let stdout = st.stdout();
+    let _ = stdout.set_color(Color::LightRed, Color::Black);
+    let _ = writeln!(
+        stdout,
+        "\ntakobl: the kernel crashed during the previous boot"
+    );
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    // The log would scroll the rest off the screen; `lastcrash` shows it all.
+    for line in report.lines().take_while(|line| *line != "Recent log:") {
+        let _ = writeln!(stdout, "{}", line);
+    }
+    let _ = writeln!(
+        stdout,
+        "\nThe full report is in {} on the ESP and `lastcrash` in takos shows it",
+        CRASH_REPORT_FILE
+    );
+    let _ = writeln!(stdout, "Press any key to continue");
+    let mut events = unsafe { [st.stdin().wait_for_key_event().unsafe_clone()] };
+    let _ = st.boot_services().wait_for_event(&mut events);
+    let _ = st.stdin().read_key();
+}
@@ -0,0 +1,93 @@
+use core::arch::x86_64::_rdtsc;
+
+use elf::abi::{DT_RELA, DT_RELAENT, DT_RELASZ, ET_DYN, PT_LOAD, R_X86_64_RELATIVE};
+use elf::endian::AnyEndian;
+use elf::relocation::RelaIterator;
+use elf::ElfBytes;
+use log::{info, warn};
+use uefi::prelude::BootServices;
+use uefi::proto::rng::Rng;
+
+/// The kernel is linked with code at `KERNEL_LINK_BASE` and data 1 GiB above
+/// it; slides up to 512 MiB keep both below the kernel stack.
+const SLIDE_ALIGN: u64 = 2 * 1024 * 1024;
+const SLIDE_SLOTS: u64 = 256;
+
+fn random_u64(bs: &BootServices) -> u64 {
+    let mut bytes = [0u8; 8];
+    let result = bs
+        .get_handle_for_protocol::<Rng>()
+        .and_then(|handle| bs.open_protocol_exclusive::<Rng>(handle))
+        .and_then(|mut rng| rng.get_rng(None, &mut bytes));
+    match result {
+        Ok(()) => u64::from_le_bytes(bytes),
+        Err(error) => {
+            warn!("No EFI_RNG_PROTOCOL ({:?}), falling back to TSC", error);
+            unsafe { _rdtsc() }
+        }
+    }
+}
+
+pub fn choose_slide(bs: &BootServices, elf: &ElfBytes<AnyEndian>) -> u64 {
+    if elf.ehdr.e_type != ET_DYN {
+        info!("Kernel isn't relocatable, loading at its link address");
+        return 0;
+    }
+    let slide = random_u64(bs) % SLIDE_SLOTS * SLIDE_ALIGN;
+    info!("Kernel slide: {:X}", slide);
+    slide
+}
+
+fn virtual_to_file_offset(elf: &ElfBytes<AnyEndian>, address: u64) -> Option<usize> {
+    elf.segments()?
+        .iter()
+        .filter(|segment| segment.p_type == PT_LOAD)
+        .find(|segment| segment.p_vaddr <= address && address < segment.p_vaddr + segment.p_filesz)
+        .map(|segment| (address - segment.p_vaddr + segment.p_offset) as usize)
+}
+
+/// Applies the `R_X86_64_RELATIVE` relocations listed in the dynamic section.
+/// `write` receives the (slid) virtual address and the value to store there.
+pub fn apply_relocations(
+    elf: &ElfBytes<AnyEndian>,
+    data: &[u8],
+    slide: u64,
+    mut write: impl FnMut(u64, u64),
+) {
+    let Some(dynamic) = elf.dynamic().expect("Couldn't parse dynamic section") else {
+        return;
+    };
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_entry_size = 0;
+    for entry in dynamic.iter() {
+        match entry.d_tag {
+            DT_RELA => rela = Some(entry.d_ptr()),
+            DT_RELASZ => rela_size = entry.d_val() as usize,
+            DT_RELAENT => rela_entry_size = entry.d_val() as usize,
+            _ => {}
+        }
+    }
+    let Some(rela) = rela else {
+        return;
+    };
+    assert_eq!(rela_entry_size, 24, "Unexpected relocation entry size");
+    let offset = virtual_to_file_offset(elf, rela).expect("Relocations aren't loaded");
+    let relocations = RelaIterator::new(
+        elf.ehdr.endianness,
+        elf.ehdr.class,
+        &data[offset..offset + rela_size],
+    );
+
+    let mut count = 0;
+    for relocation in relocations {
+        assert_eq!(
+            relocation.r_type, R_X86_64_RELATIVE,
+            "Unsupported kernel relocation type"
+        );
+        let value = (relocation.r_addend as u64).wrapping_add(slide);
+        write(relocation.r_offset + slide, value);
+        count += 1;
+    }
+    info!("Applied {} relocations", count);
+}
@@ -3,7 +3,13 @@
 #![feature(ascii_char)]
 #![feature(pointer_byte_offsets)]
 
+mod bootinfo;
+mod config;
+mod crash;
+mod kaslr;
+mod menu;
 mod paging;
+mod verify;
 
 extern crate alloc;
 
@@ -11,15 +17,17 @@ use core::arch::asm;
 use core::mem::{size_of, transmute, MaybeUninit};
 
 use alloc::format;
-use alloc::string::ToString;
 use alloc::vec::Vec;
 use alloc::{string::String, vec};
 
-use elf::abi::{PF_X, PT_LOAD};
+use elf::abi::{PF_W, PF_X, PT_LOAD};
 use elf::endian::AnyEndian;
 use elf::ElfBytes;
-use log::info;
-use takobl_api::{BootData, FrameBufferData, PHYSICAL_MEMORY_OFFSET};
+use log::{info, warn};
+use takobl_api::{
+    BootData, BootSlice, FrameBufferData, BOOT_DATA_MAGIC, BOOT_DATA_VERSION,
+    BOOT_DATA_VERSION_SECTION, KERNEL_LINK_BASE, PHYSICAL_MEMORY_OFFSET, RUNTIME_AREA_START,
+};
 use uefi::data_types::PhysicalAddress;
 use uefi::fs::{self, Path};
 use uefi::prelude::*;
@@ -27,45 +35,112 @@ use uefi::proto::console::gop::GraphicsOutput;
 use uefi::proto::device_path::text::{AllowShortcuts, DisplayOnly};
 use uefi::proto::device_path::DevicePath;
 use uefi::proto::loaded_image::LoadedImage;
-use uefi::table::boot::{MemoryType, PAGE_SIZE};
-use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use uefi::table::boot::{MemoryDescriptor, MemoryMap, MemoryType, PAGE_SIZE};
+use uefi::table::runtime::RuntimeServices;
+use uefi::table::Runtime;
+use uefi::CString16;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PageTableFlags};
 
-use crate::paging::{PageTableBuilder, KERNEL_STACK_END};
+use crate::bootinfo::TagBuilder;
+use crate::config::BootConfig;
+use crate::paging::{
+    runtime_virtual_address, PageTableBuilder, DATA_PAGE_FLAGS, KERNEL_STACK_END, MODULE_AREA_START,
+};
 
 #[entry]
 fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).unwrap();
     info!("Hello world testing 3!");
+    let config = BootConfig::load(image_handle, system_table.boot_services());
+    log::set_max_level(config.log_level);
+    crash::handle_crash_report(image_handle, &mut system_table, config.show_crash_report);
+    let entry = menu::choose_entry(image_handle, &mut system_table, &config);
+    let command_line = config.kernel_command_line(&entry);
+    info!("Kernel command line: {:?}", command_line);
     //print_memory_map(&system_table);
     //test_filesystem(image_handle, &system_table);
     let mut page_table_builder = PageTableBuilder::new(system_table.boot_services());
     page_table_builder.map_physical_mem();
     page_table_builder.allocate_stack();
     let boot_data = allocate_boot_data(system_table.boot_services());
-    let kernel_entry = load_kernel(
+    let mut tags = TagBuilder::new();
+    tags.command_line(&command_line);
+    tags.firmware_tables(&system_table);
+    let kernel = read_file(image_handle, system_table.boot_services(), &entry.kernel);
+    if let Err(error) = verify::verify_kernel(
         image_handle,
         system_table.boot_services(),
-        &mut page_table_builder,
-    );
-    let ramdisk = load_ramdisk(
-        image_handle,
+        entry.verify,
+        &entry.kernel,
+        &kernel,
+    ) {
+        verify::refuse_to_boot(&mut system_table, &entry.kernel, &error);
+        return Status::SECURITY_VIOLATION;
+    }
+    if let Err(error) = check_boot_data_version(&kernel) {
+        verify::refuse_to_boot(&mut system_table, &entry.kernel, &error);
+        return Status::INCOMPATIBLE_VERSION;
+    }
+    let (kernel_entry, kernel_base) = load_kernel(
+        &kernel,
         system_table.boot_services(),
         &mut page_table_builder,
+        &mut tags,
     );
+    let mut module_address = MODULE_AREA_START;
+    for module in entry.modules.iter() {
+        let data = read_file(image_handle, system_table.boot_services(), &module.path);
+        if let Err(error) = verify::verify_module(
+            image_handle,
+            system_table.boot_services(),
+            &module.path,
+            &data,
+        ) {
+            verify::refuse_to_boot(&mut system_table, &module.path, &error);
+            return Status::SECURITY_VIOLATION;
+        }
+        let data = load_module(
+            data,
+            &module.path,
+            module_address,
+            system_table.boot_services(),
+            &mut page_table_builder,
+        );
+        info!(
+            "Module {} ({}) at {:016X}, {} bytes",
+            module.name, module.path, data.addr, data.len
+        );
+        tags.module(&module.name, data);
+        // Leave an unmapped page between modules.
+        module_address += (data.len + 2 * PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+    }
 
-    let device_path = get_storage_device_path(image_handle, system_table.boot_services());
+    page_table_builder.map_runtime_services(&system_table);
+
+    tags.device_path(&get_boot_device_path(
+        image_handle,
+        system_table.boot_services(),
+    ));
 
     // info!("Boot Data ptr: {:?}", boot_data as *mut BootData);
     // info!("Boot Data: {:?}", boot_data);
     // print_memory_map(image_handle, &system_table);
+    let frame_buffer = get_gop_data(system_table.boot_services(), config.resolution);
+    tags.memory_map(system_table.boot_services());
+    let tags = tags.finish(&mut page_table_builder);
     let (mut page_table, free_memory_map, loader_code) = page_table_builder.deconstruct();
     unsafe {
         boot_data.as_mut_ptr().write(BootData {
-            frame_buffer: get_gop_data(system_table.boot_services()),
+            magic: BOOT_DATA_MAGIC,
+            version: BOOT_DATA_VERSION,
+            size: size_of::<BootData>() as u32,
+            frame_buffer,
             free_memory_map,
             loader_code,
-            image_device_path: convert_to_physical(device_path.leak()),
-            ramdisk,
+            kernel_base,
+            tags,
+            runtime_services: 0,
         });
     }
     info!(
@@ -76,12 +151,48 @@ fn main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     //let rip = x86_64::registers::read_rip();
     //info!("Rip: {:016X}", rip);
     //system_table.boot_services().stall(10_000_000);
-    let _ = system_table.exit_boot_services();
+    // Both tables live in runtime memory, so they move with it.
+    let runtime_services =
+        system_table.runtime_services() as *const RuntimeServices as u64 + RUNTIME_AREA_START;
+    let map_size = system_table.boot_services().memory_map_size();
+    let mut runtime_map = Vec::with_capacity(map_size.map_size / map_size.entry_size + 8);
+    let (system_table, memory_map) = system_table.exit_boot_services();
+    let system_table_address = system_table.get_current_system_table_addr() + RUNTIME_AREA_START;
     //info!("Success!", "{}");
+    if enter_virtual_mode(
+        system_table,
+        &memory_map,
+        &mut runtime_map,
+        system_table_address,
+    ) {
+        unsafe { (*boot_data.as_mut_ptr()).runtime_services = runtime_services };
+    }
     let boot_data = unsafe { convert_to_physical(boot_data).assume_init_mut() };
     jump_to(kernel_entry, boot_data, &mut page_table);
 }
 
+/// Tells the firmware where its runtime regions will be mapped. Runs after
+/// `ExitBootServices`, so it must neither allocate nor log; `runtime_map` has
+/// to come with enough capacity.
+fn enter_virtual_mode(
+    system_table: SystemTable<Runtime>,
+    memory_map: &MemoryMap,
+    runtime_map: &mut Vec<MemoryDescriptor>,
+    system_table_address: u64,
+) -> bool {
+    for entry in memory_map.entries() {
+        if let Some(virtual_start) = runtime_virtual_address(entry) {
+            if runtime_map.len() == runtime_map.capacity() {
+                return false;
+            }
+            let mut entry = *entry;
+            entry.virt_start = virtual_start;
+            runtime_map.push(entry);
+        }
+    }
+    unsafe { system_table.set_virtual_address_map(runtime_map, system_table_address) }.is_ok()
+}
+
 #[allow(dead_code)]
 fn test_filesystem(image_handle: Handle, system_table: &SystemTable<Boot>) {
     let mut fs = system_table
@@ -94,23 +205,42 @@ fn test_filesystem(image_handle: Handle, system_table: &SystemTable<Boot>) {
     info!("File contents: {}", s);
 }
 
-fn load_ramdisk(
-    image_handle: Handle,
-    bs: &BootServices,
-    page_table_builder: &mut PageTableBuilder,
-) -> &'static mut [u8] {
+fn read_file(image_handle: Handle, bs: &BootServices, path: &str) -> Vec<u8> {
     let mut fs: fs::FileSystem<'_> = bs
         .get_image_file_system(image_handle)
         .expect("Couldn't get filesystem");
-    let path = Path::new(cstr16!("ramdisk.img"));
-    let data = fs.read(path).expect("Couldn't read file");
+    let path = CString16::try_from(path).expect("Invalid path");
+    fs.read(Path::new(&path))
+        .unwrap_or_else(|_| panic!("Couldn't read {}", path))
+}
+
+fn load_module(
+    mut data: Vec<u8>,
+    path: &str,
+    start_virtual_address: u64,
+    bs: &BootServices,
+    page_table_builder: &mut PageTableBuilder,
+) -> BootSlice {
+    if let Some(format) = tako_compress::detect(&data) {
+        let decompressed = tako_compress::decompress(format, &data)
+            .unwrap_or_else(|error| panic!("Couldn't decompress {}: {}", path, error));
+        info!(
+            "{}: {:?}, {} bytes decompressed to {}",
+            path,
+            format,
+            data.len(),
+            decompressed.len()
+        );
+        data = decompressed;
+    } else if data.starts_with(&tako_compress::ZSTD_MAGIC) {
+        warn!("{}: zstd isn't supported, passing it on compressed", path);
+    }
 
     let mut offset = 0u64;
-    const START_VIRTUAL_ADDRESS: u64 = 0xFFFF_E800_0000_0000;
     while offset < data.len() as u64 {
         let size = (data.len() as u64 - offset).min(PAGE_SIZE as u64);
-        let virtual_addr = START_VIRTUAL_ADDRESS + offset;
-        let physical_addr = page_table_builder.allocate_page(virtual_addr, false);
+        let virtual_addr = start_virtual_address + offset;
+        let physical_addr = page_table_builder.allocate_page(virtual_addr, DATA_PAGE_FLAGS);
         unsafe {
             let dest = physical_addr as *mut u8;
             let src = data.as_ptr().add(offset as usize);
@@ -118,23 +248,53 @@ fn load_ramdisk(
         }
         offset += size;
     }
-    unsafe { core::slice::from_raw_parts_mut(START_VIRTUAL_ADDRESS as *mut u8, data.len()) }
+    BootSlice::new(start_virtual_address, data.len() as u64)
+}
+
+/// takos can only report a mismatch on a serial port, so it's caught here
+/// while the UEFI console still works. Kernels from before the version
+/// section are booted as before.
+fn check_boot_data_version(kernel: &[u8]) -> Result<(), String> {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(kernel)
+        .map_err(|_| String::from("the kernel isn't an ELF file"))?;
+    let version = elf
+        .section_header_by_name(BOOT_DATA_VERSION_SECTION)
+        .ok()
+        .flatten()
+        .and_then(|header| elf.section_data(&header).ok())
+        .and_then(|(data, _)| data.get(..4)?.try_into().ok())
+        .map(u32::from_le_bytes);
+    match version {
+        Some(version) if version != BOOT_DATA_VERSION => Err(format!(
+            "the kernel expects boot data version {}, takobl speaks {}; update {}",
+            version,
+            BOOT_DATA_VERSION,
+            if version > BOOT_DATA_VERSION {
+                "takobl"
+            } else {
+                "the kernel"
+            }
+        )),
+        Some(_) => Ok(()),
+        None => {
+            warn!("The kernel doesn't record its boot data version");
+            Ok(())
+        }
+    }
 }
 
 fn load_kernel(
-    image_handle: Handle,
+    data: &[u8],
     bs: &BootServices,
     page_table_builder: &mut PageTableBuilder,
-) -> PhysicalAddress {
-    let mut fs: fs::FileSystem<'_> = bs
-        .get_image_file_system(image_handle)
-        .expect("Couldn't get filesystem");
-    let path = Path::new(cstr16!("kernel.elf"));
-    let data = fs.read(path).expect("Couldn't read file");
-    let elf = ElfBytes::<AnyEndian>::minimal_parse(data.as_slice()).expect("Couldn't parse elf");
+    tags: &mut TagBuilder,
+) -> (PhysicalAddress, u64) {
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(data).expect("Couldn't parse elf");
+    tags.symbols(&elf);
     let segments = elf.segments().expect("Couldn't get segments");
+    let slide = kaslr::choose_slide(bs, &elf);
 
-    let mut allocated_pages: Vec<(u64, u64)> = Vec::new();
+    let mut allocated_pages: Vec<(u64, u64, PageTableFlags)> = Vec::new();
 
     for segment in segments {
         if segment.p_type != PT_LOAD {
@@ -142,8 +302,8 @@ fn load_kernel(
         }
         let file_size = segment.p_filesz as usize;
         let mem_size = segment.p_memsz as usize;
-        let start_virt_address = segment.p_vaddr;
-        let end_virt_address = segment.p_vaddr + segment.p_memsz;
+        let start_virt_address = segment.p_vaddr + slide;
+        let end_virt_address = start_virt_address + segment.p_memsz;
         info!(
             "Address: {:016X}-{:016X}, size: 0x{:X}",
             start_virt_address, end_virt_address, mem_size
@@ -153,9 +313,22 @@ fn load_kernel(
             .expect("Couldn't get segment data");
 
         let is_executable = segment.p_flags & PF_X != 0;
+        let is_writable = segment.p_flags & PF_W != 0;
+        assert!(
+            !(is_executable && is_writable),
+            "Kernel segment at {:016X} is both writable and executable",
+            start_virt_address
+        );
+        let mut flags = PageTableFlags::PRESENT;
+        if is_writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !is_executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
 
-        let mut page_address = segment.p_vaddr & !0xFFF;
-        let mut memory_offset = segment.p_vaddr & 0xFFF;
+        let mut page_address = start_virt_address & !0xFFF;
+        let mut memory_offset = start_virt_address & 0xFFF;
         let mut data_offset: usize = 0;
         while data_offset < mem_size {
             info!("data_offset: {:X}", data_offset);
@@ -167,15 +340,24 @@ fn load_kernel(
                 0x1000
             };
             let size = (0x1000 - memory_offset as usize).min(data_left);
-            let physical_address = allocated_pages
+            let physical_address = match allocated_pages
                 .iter()
-                .find(|(virt, _)| *virt == page_address)
-                .map(|(_, phys)| *phys)
-                .unwrap_or_else(|| {
-                    let new_page = page_table_builder.allocate_page(page_address, is_executable);
-                    allocated_pages.push((page_address, new_page));
+                .find(|(virt, _, _)| *virt == page_address)
+            {
+                Some((_, phys, page_flags)) => {
+                    assert!(
+                        *page_flags == flags,
+                        "Kernel segments with different permissions share page {:016X}",
+                        page_address
+                    );
+                    *phys
+                }
+                None => {
+                    let new_page = page_table_builder.allocate_page(page_address, flags);
+                    allocated_pages.push((page_address, new_page, flags));
                     new_page
-                });
+                }
+            };
             unsafe {
                 let dest = (physical_address as *mut u8).add(memory_offset as usize);
                 if data_offset < file_size {
@@ -195,8 +377,22 @@ fn load_kernel(
         }
         info!("Success!");
     }
+
+    kaslr::apply_relocations(&elf, data, slide, |address, value| {
+        assert!(
+            address % 8 == 0,
+            "Misaligned relocation at {:016X}",
+            address
+        );
+        let physical_page = allocated_pages
+            .iter()
+            .find(|(virt, _, _)| *virt == address & !0xFFF)
+            .map(|(_, phys, _)| *phys)
+            .expect("Relocation outside of the kernel");
+        unsafe { ((physical_page + (address & 0xFFF)) as *mut u64).write(value) };
+    });
     info!("Kernel loading into memory... OK!");
-    elf.ehdr.e_entry
+    (elf.ehdr.e_entry + slide, KERNEL_LINK_BASE + slide)
 }
 
 fn jump_to(
@@ -206,6 +402,10 @@ fn jump_to(
 ) -> ! {
     let cr3 = page_table.level_4_table() as *mut PageTable as u64;
     let stack_ptr = KERNEL_STACK_END;
+    // The new page table relies on NO_EXECUTE, which is reserved without NXE.
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
     unsafe {
         asm!(
             "mov cr3, {}; mov rsp, {}; push 0; jmp {}",
@@ -218,7 +418,12 @@ fn jump_to(
     unreachable!();
 }
 
-fn get_storage_device_path(image_handle: Handle, bs: &BootServices) -> String {
+const END_OF_PATH_TYPE: u8 = 0x7F;
+const END_ENTIRE_NODE: [u8; 4] = [END_OF_PATH_TYPE, 0xFF, 4, 0];
+
+/// Raw device path of the disk the loader came from, with the loader's own
+/// file path appended.
+fn get_boot_device_path(image_handle: Handle, bs: &BootServices) -> Vec<u8> {
     let image = bs
         .open_protocol_exclusive::<LoadedImage>(image_handle)
         .expect("Couldn't open image protocol");
@@ -229,12 +434,32 @@ fn get_storage_device_path(image_handle: Handle, bs: &BootServices) -> String {
         .to_string(bs, DisplayOnly(false), AllowShortcuts(false))
         .expect("Couldn't convert to string")
         .unwrap();
-    let s = s.to_string();
     info!("{}", s);
-    s
+
+    let mut result = device_path_nodes(&device_path);
+    if let Some(file_path) = image.file_path() {
+        result.extend_from_slice(&device_path_nodes(file_path));
+    }
+    result.extend_from_slice(&END_ENTIRE_NODE);
+    result
 }
 
-fn get_gop_data(bt: &BootServices) -> FrameBufferData {
+/// Copies the nodes of `device_path`, leaving out the end node.
+fn device_path_nodes(device_path: &DevicePath) -> Vec<u8> {
+    let start = device_path.as_ffi_ptr() as *const u8;
+    let mut length = 0;
+    loop {
+        let header = unsafe { core::slice::from_raw_parts(start.add(length), 4) };
+        let node_length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if header[0] == END_OF_PATH_TYPE || node_length < 4 {
+            break;
+        }
+        length += node_length;
+    }
+    unsafe { core::slice::from_raw_parts(start, length) }.to_vec()
+}
+
+fn get_gop_data(bt: &BootServices, resolution: Option<(usize, usize)>) -> FrameBufferData {
     info!("Getting handle");
     let handle = bt
         .get_handle_for_protocol::<GraphicsOutput>()
@@ -244,9 +469,15 @@ fn get_gop_data(bt: &BootServices) -> FrameBufferData {
         .open_protocol_exclusive::<GraphicsOutput>(handle)
         .expect("Couldn't open protocol");
 
-    //info!("Printing modes!");
-    //let mode = gop.query_mode(0).unwrap();
-    //gop.set_mode(&mode).unwrap();
+    if let Some(resolution) = resolution {
+        match gop
+            .modes()
+            .find(|mode| mode.info().resolution() == resolution)
+        {
+            Some(mode) => gop.set_mode(&mode).expect("Couldn't set mode"),
+            None => warn!("No {}x{} video mode", resolution.0, resolution.1),
+        }
+    }
 
     let current = gop.current_mode_info();
     info!("Current mode: {:?}", current);
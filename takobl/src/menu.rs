@@ -0,0 +1,248 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use log::{info, warn};
+use uefi::fs::{self, Path};
+use uefi::prelude::*;
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::boot::{EventType, LoadImageSource, TimerTrigger, Tpl};
+use uefi::table::runtime::{ResetType, VariableAttributes, VariableVendor};
+use uefi::{cstr16, guid, CStr16, CString16, Event};
+
+use crate::config::{BootConfig, BootEntry};
+use crate::verify::Verify;
+
+pub const TAKOBL_VENDOR: VariableVendor =
+    VariableVendor(guid!("5f0b8ad0-6c3e-4d53-9b0a-7a4a2c6e1d91"));
+const OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 1;
+const MAX_ENTRY_NAME: usize = 128;
+/// One second in the 100 ns units of UEFI timers.
+const TIMER_SECOND: u64 = 10_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum MenuItem {
+    Entry(usize),
+    Shell,
+    FirmwareSetup,
+}
+
+fn variable_attributes() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS
+}
+
+fn read_u64_variable(st: &SystemTable<Boot>, name: &CStr16) -> Option<u64> {
+    let mut buffer = [0u8; 8];
+    let (data, _) = st
+        .runtime_services()
+        .get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut buffer)
+        .ok()?;
+    Some(u64::from_le_bytes(data.try_into().ok()?))
+}
+
+fn firmware_setup_supported(st: &SystemTable<Boot>) -> bool {
+    read_u64_variable(st, cstr16!("OsIndicationsSupported")).map_or(false, |supported| {
+        supported & OS_INDICATIONS_BOOT_TO_FW_UI != 0
+    })
+}
+
+fn reboot_to_firmware_setup(st: &SystemTable<Boot>) -> ! {
+    let indications = read_u64_variable(st, cstr16!("OsIndications")).unwrap_or(0);
+    let rt = st.runtime_services();
+    if let Err(error) = rt.set_variable(
+        cstr16!("OsIndications"),
+        &VariableVendor::GLOBAL_VARIABLE,
+        variable_attributes(),
+        &(indications | OS_INDICATIONS_BOOT_TO_FW_UI).to_le_bytes(),
+    ) {
+        warn!("Couldn't set OsIndications: {:?}", error);
+    }
+    rt.reset(ResetType::COLD, Status::SUCCESS, None)
+}
+
+fn run_shell(image_handle: Handle, bs: &BootServices, path: &str) {
+    let mut fs: fs::FileSystem<'_> = bs
+        .get_image_file_system(image_handle)
+        .expect("Couldn't get filesystem");
+    let Ok(path) = CString16::try_from(path) else {
+        warn!("Invalid shell path");
+        return;
+    };
+    let data = match fs.read(Path::new(&path)) {
+        Ok(data) => data,
+        Err(error) => {
+            warn!("Couldn't read {}: {:?}", path, error);
+            return;
+        }
+    };
+    let source = LoadImageSource::FromBuffer {
+        buffer: &data,
+        file_path: None,
+    };
+    let result = bs
+        .load_image(image_handle, source)
+        .and_then(|shell| bs.start_image(shell));
+    if let Err(error) = result {
+        warn!("Couldn't start the shell: {:?}", error);
+    }
+}
+
+fn last_entry(st: &SystemTable<Boot>, config: &BootConfig) -> Option<usize> {
+    let mut buffer = [0u8; MAX_ENTRY_NAME];
+    let (data, _) = st
+        .runtime_services()
+        .get_variable(cstr16!("TakoblLastEntry"), &TAKOBL_VENDOR, &mut buffer)
+        .ok()?;
+    let name = core::str::from_utf8(data).ok()?;
+    config.entries.iter().position(|entry| entry.name == name)
+}
+
+fn save_last_entry(st: &SystemTable<Boot>, config: &BootConfig, index: usize) {
+    // Saves a flash write when the choice didn't change.
+    if last_entry(st, config) == Some(index) {
+        return;
+    }
+    let name = &config.entries[index].name;
+    if let Err(error) = st.runtime_services().set_variable(
+        cstr16!("TakoblLastEntry"),
+        &TAKOBL_VENDOR,
+        variable_attributes(),
+        &name.as_bytes()[..name.len().min(MAX_ENTRY_NAME)],
+    ) {
+        warn!("Couldn't remember the boot entry: {:?}", error);
+    }
+}
+
+fn default_entry(st: &SystemTable<Boot>, config: &BootConfig) -> usize {
+    last_entry(st, config)
+        .or_else(|| {
+            let default = config.default.as_ref()?;
+            config
+                .entries
+                .iter()
+                .position(|entry| &entry.name == default)
+        })
+        .unwrap_or(0)
+}
+
+fn draw(
+    st: &mut SystemTable<Boot>,
+    config: &BootConfig,
+    items: &[MenuItem],
+    selected: usize,
+    timeout: Option<usize>,
+) {
+    let stdout = st.stdout();
+    let _ = stdout.clear();
+    let _ = writeln!(stdout, "takobl boot menu\n");
+    for (index, item) in items.iter().enumerate() {
+        let marker = if index == selected { '>' } else { ' ' };
+        let name = match item {
+            MenuItem::Entry(entry) => config.entries[*entry].name.as_str(),
+            MenuItem::Shell => "UEFI shell",
+            MenuItem::FirmwareSetup => "Firmware setup",
+        };
+        let _ = writeln!(stdout, " {} {}. {}", marker, index + 1, name);
+    }
+    let _ = writeln!(stdout);
+    match timeout {
+        Some(seconds) => {
+            let _ = writeln!(stdout, "Booting in {} s, press any key to stop", seconds);
+        }
+        None => {
+            let _ = writeln!(stdout, "Up/Down to select, Enter to boot");
+        }
+    }
+}
+
+/// Waits for a key press, or for one second if `timer` is given, in which
+/// case `None` is returned when nothing was pressed.
+fn wait_for_key(st: &mut SystemTable<Boot>, timer: Option<&Event>) -> Option<Key> {
+    let key_event = unsafe { st.stdin().wait_for_key_event().unsafe_clone() };
+    let bs = st.boot_services();
+    let mut events = vec![key_event];
+    if let Some(timer) = timer {
+        bs.set_timer(timer, TimerTrigger::Relative(TIMER_SECOND))
+            .expect("Couldn't set timer");
+        events.push(unsafe { timer.unsafe_clone() });
+    }
+    let index = bs
+        .wait_for_event(&mut events)
+        .expect("Couldn't wait for events");
+    if index != 0 {
+        return None;
+    }
+    st.stdin().read_key().ok().flatten()
+}
+
+/// Shows the boot menu and returns the entry to boot. The menu comes back
+/// when the UEFI shell exits; firmware setup resets the machine.
+pub fn choose_entry(
+    image_handle: Handle,
+    st: &mut SystemTable<Boot>,
+    config: &BootConfig,
+) -> BootEntry {
+    let mut items: Vec<MenuItem> = (0..config.entries.len()).map(MenuItem::Entry).collect();
+    // An unsigned shell could start an unsigned kernel.
+    if config.shell.is_some() && Verify::minimum() == Verify::None {
+        items.push(MenuItem::Shell);
+    }
+    if firmware_setup_supported(st) {
+        items.push(MenuItem::FirmwareSetup);
+    }
+
+    let mut selected = default_entry(st, config);
+    let mut timeout = Some(config.timeout);
+    let timer = unsafe {
+        st.boot_services()
+            .create_event(EventType::TIMER, Tpl::APPLICATION, None, None)
+            .expect("Couldn't create timer")
+    };
+
+    let index = loop {
+        if timeout == Some(0) {
+            match items[selected] {
+                MenuItem::Entry(index) => break index,
+                _ => timeout = None,
+            }
+        }
+        draw(st, config, &items, selected, timeout);
+        let Some(key) = wait_for_key(st, timeout.map(|_| &timer)) else {
+            timeout = timeout.map(|seconds| seconds - 1);
+            continue;
+        };
+        timeout = None;
+        match key {
+            Key::Special(ScanCode::UP) => {
+                selected = selected.checked_sub(1).unwrap_or(items.len() - 1);
+            }
+            Key::Special(ScanCode::DOWN) => selected = (selected + 1) % items.len(),
+            Key::Printable(c) if char::from(c) == '\r' => match items[selected] {
+                MenuItem::Entry(index) => break index,
+                MenuItem::Shell => run_shell(
+                    image_handle,
+                    st.boot_services(),
+                    config.shell.as_ref().unwrap(),
+                ),
+                MenuItem::FirmwareSetup => reboot_to_firmware_setup(st),
+            },
+            Key::Printable(c) => {
+                if let Some(digit) = char::from(c).to_digit(10) {
+                    if (1..=items.len()).contains(&(digit as usize)) {
+                        selected = digit as usize - 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    };
+    let _ = st.boot_services().close_event(timer);
+    let _ = st.stdout().clear();
+
+    save_last_entry(st, config, index);
+    let entry = config.entries[index].clone();
+    info!("Booting {}", entry.name);
+    entry
+}
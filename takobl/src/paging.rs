@@ -1,9 +1,11 @@
 use alloc::vec::Vec;
-use log::info;
-use takobl_api::{FreeMemoryMap, MemoryRegion, PHYSICAL_MEMORY_OFFSET};
+use log::{info, warn};
+use takobl_api::{FreeMemoryMap, MemoryRegion, PHYSICAL_MEMORY_OFFSET, RUNTIME_AREA_START};
 use uefi::{
-    prelude::BootServices,
-    table::boot::{AllocateType, MemoryMap, MemoryType},
+    guid,
+    prelude::{Boot, BootServices, SystemTable},
+    table::boot::{AllocateType, MemoryAttribute, MemoryDescriptor, MemoryMap, MemoryType},
+    Guid,
 };
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
@@ -34,10 +36,15 @@ impl<'a> UefiFrameAllocator<'a> {
         });
     }
 
+    /// Allocates `pages` physically contiguous pages.
     fn allocate(&mut self, pages: u64) -> Option<u64> {
         let addr = self
             .bs
-            .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, 1)
+            .allocate_pages(
+                AllocateType::AnyPages,
+                MemoryType::LOADER_DATA,
+                pages as usize,
+            )
             .ok()?;
         self.register(addr, pages);
         Some(addr)
@@ -51,8 +58,6 @@ fn create_free_memory_map(map: &MemoryMap<'_>) -> FreeMemoryMap {
         match entry.ty {
             MemoryType::BOOT_SERVICES_CODE
             | MemoryType::BOOT_SERVICES_DATA
-            | MemoryType::RUNTIME_SERVICES_CODE
-            | MemoryType::RUNTIME_SERVICES_DATA
             | MemoryType::LOADER_CODE
             | MemoryType::LOADER_DATA
             | MemoryType::CONVENTIONAL => {
@@ -83,7 +88,7 @@ fn create_free_memory_map(map: &MemoryMap<'_>) -> FreeMemoryMap {
     result
 }
 
-fn get_memory_map<'a>(bs: &BootServices, buffer: &'a mut Vec<u8>) -> MemoryMap<'a> {
+pub fn get_memory_map<'a>(bs: &BootServices, buffer: &'a mut Vec<u8>) -> MemoryMap<'a> {
     let memory_map_size = bs.memory_map_size();
     info!("Memory map size: {}", memory_map_size.map_size);
     let buffer_size = memory_map_size.map_size + 4 * memory_map_size.entry_size;
@@ -103,6 +108,75 @@ unsafe impl<'a> FrameAllocator<Size4KiB> for UefiFrameAllocator<'a> {
     }
 }
 
+pub const DATA_PAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Boot modules are mapped one after another from here.
+pub const MODULE_AREA_START: u64 = 0xFFFF_E800_0000_0000;
+
+const MEMORY_ATTRIBUTES_TABLE_GUID: Guid = guid!("dcfa911d-26eb-469f-a220-38b7dc461220");
+
+/// `EFI_MEMORY_ATTRIBUTES_TABLE`, followed by `entry_count` descriptors.
+#[repr(C)]
+struct MemoryAttributesTable {
+    version: u32,
+    entry_count: u32,
+    descriptor_size: u32,
+    flags: u32,
+}
+
+/// How the firmware split its runtime images into code and data pages.
+/// Empty if it doesn't say.
+fn memory_attributes(st: &SystemTable<Boot>) -> Vec<MemoryDescriptor> {
+    let Some(entry) = st
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == MEMORY_ATTRIBUTES_TABLE_GUID)
+    else {
+        return Vec::new();
+    };
+    let table = unsafe { &*(entry.address as *const MemoryAttributesTable) };
+    let descriptors = unsafe { (table as *const MemoryAttributesTable).add(1) as *const u8 };
+    (0..table.entry_count as usize)
+        .map(|i| unsafe {
+            descriptors
+                .add(i * table.descriptor_size as usize)
+                .cast::<MemoryDescriptor>()
+                .read_unaligned()
+        })
+        .collect()
+}
+
+/// Code pages are read-only and data pages non-executable, as the memory
+/// attributes table says. Without one runtime drivers may keep their data
+/// inside the code region, so it has to stay writable and executable.
+fn runtime_code_flags(attributes: &[MemoryDescriptor], physical: u64) -> PageTableFlags {
+    let Some(descriptor) = attributes.iter().find(|descriptor| {
+        (descriptor.phys_start..descriptor.phys_start + descriptor.page_count * 0x1000)
+            .contains(&physical)
+    }) else {
+        return PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    };
+    let mut flags = PageTableFlags::PRESENT;
+    if !descriptor.att.contains(MemoryAttribute::READ_ONLY) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if descriptor.att.contains(MemoryAttribute::EXECUTE_PROTECT) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Where `entry` lives after `SetVirtualAddressMap`, or `None` if the
+/// firmware doesn't need it at runtime.
+pub fn runtime_virtual_address(entry: &MemoryDescriptor) -> Option<u64> {
+    entry
+        .att
+        .contains(MemoryAttribute::RUNTIME)
+        .then_some(RUNTIME_AREA_START + entry.phys_start)
+}
+
 pub const KERNEL_STACK_GUARD_PAGE: u64 = 0xFFFF_FFFF_FFF0_0000;
 pub const KERNEL_STACK_START: u64 = KERNEL_STACK_GUARD_PAGE + 0x1000;
 pub const KERNEL_STACK_END: u64 = 0xFFFF_FFFF_FFFF_FFF0;
@@ -147,16 +221,6 @@ impl<'a> PageTableBuilder<'a> {
         }
     }
 
-    pub fn map_writeable_page(&mut self, virtual_addr: u64, physical_addr: u64, executable: bool) {
-        let flags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
-        let flags = if executable {
-            flags
-        } else {
-            flags.union(PageTableFlags::NO_EXECUTE)
-        };
-        self.map_page(virtual_addr, physical_addr, flags);
-    }
-
     pub fn map_physical_mem(&mut self) {
         for i in 0..1024 {
             // 1 TB of physical memory (I wish...)
@@ -178,26 +242,62 @@ impl<'a> PageTableBuilder<'a> {
         info!("Offset physical memory map... OK!");
     }
 
-    pub fn allocate_pages(&mut self, start_virtual_addr: u64, pages: u64, executable: bool) {
+    pub fn allocate_pages(&mut self, start_virtual_addr: u64, pages: u64, flags: PageTableFlags) {
         let addr = self.frame_allocator.allocate(pages).unwrap();
         for i in 0..pages {
             let physical_addr = addr + i * 0x1000;
             let virtual_addr = start_virtual_addr + i * 0x1000;
-            self.map_writeable_page(virtual_addr, physical_addr, executable);
+            self.map_page(virtual_addr, physical_addr, flags);
         }
     }
 
-    pub fn allocate_page(&mut self, virtual_addr: u64, executable: bool) -> u64 {
+    pub fn allocate_page(&mut self, virtual_addr: u64, flags: PageTableFlags) -> u64 {
         let addr = self.frame_allocator.allocate(1).unwrap();
-        self.map_writeable_page(virtual_addr, addr, executable);
+        self.map_page(virtual_addr, addr, flags);
         addr
     }
 
+    /// Allocates physically contiguous pages without mapping them.
+    pub fn allocate_physical(&mut self, pages: u64) -> u64 {
+        self.frame_allocator.allocate(pages).unwrap()
+    }
+
     pub fn allocate_stack(&mut self) {
-        self.allocate_pages(KERNEL_STACK_START, KERNEL_STACK_PAGES - 1, false);
+        self.allocate_pages(KERNEL_STACK_START, KERNEL_STACK_PAGES - 1, DATA_PAGE_FLAGS);
         info!("Kernel stack allocation... OK!");
     }
 
+    /// Maps everything the firmware marked as needed at runtime, so takos
+    /// can call runtime services.
+    pub fn map_runtime_services(&mut self, st: &SystemTable<Boot>) {
+        let attributes = memory_attributes(st);
+        if attributes.is_empty() {
+            warn!("No memory attributes table, runtime code stays writable");
+        }
+        let mut buffer = Vec::new();
+        let memory_map = get_memory_map(st.boot_services(), &mut buffer);
+        for entry in memory_map.entries() {
+            let Some(virtual_start) = runtime_virtual_address(entry) else {
+                continue;
+            };
+            info!(
+                "Runtime region {:?}: {:08X} ({} pages) at {:016X}",
+                entry.ty, entry.phys_start, entry.page_count, virtual_start
+            );
+            for page in 0..entry.page_count {
+                let physical = entry.phys_start + page * 0x1000;
+                let flags = match entry.ty {
+                    MemoryType::RUNTIME_SERVICES_CODE => runtime_code_flags(&attributes, physical),
+                    MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => {
+                        DATA_PAGE_FLAGS | PageTableFlags::NO_CACHE
+                    }
+                    _ => DATA_PAGE_FLAGS,
+                };
+                self.map_page(virtual_start + page * 0x1000, physical, flags);
+            }
+        }
+    }
+
     fn identity_map_loader_code(&mut self, memory_map: &MemoryMap) {
         for entry in memory_map.entries() {
             match entry.ty {
@@ -210,9 +310,11 @@ impl<'a> PageTableBuilder<'a> {
                         start: entry.phys_start,
                         pages: entry.page_count,
                     });
+                    // Only needed to execute the jump into the kernel, so it
+                    // doesn't have to be writable.
                     for page in 0..entry.page_count {
                         let addr = entry.phys_start + page * 0x1000;
-                        self.map_writeable_page(addr, addr, true);
+                        self.map_page(addr, addr, PageTableFlags::PRESENT);
                     }
                 }
                 _ => {}
@@ -0,0 +1,190 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use uefi::fs::{self, Path};
+use uefi::prelude::*;
+use uefi::proto::console::text::Color;
+use uefi::CString16;
+
+/// Hex Ed25519 public key that kernel signatures are checked against, baked
+/// in when takobl is built.
+const KERNEL_PUBLIC_KEY: Option<&str> = option_env!("TAKOBL_KERNEL_KEY");
+
+/// How takobl makes sure the kernel is the one that was installed, weakest
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verify {
+    #[default]
+    None,
+    /// `<kernel>.sha256` holds the kernel's SHA-256 digest in hex. Only
+    /// catches corruption; whoever can replace the kernel can replace it too.
+    Sha256,
+    /// `<kernel>.sig` holds a raw 64 byte Ed25519 signature of the kernel,
+    /// made with the key matching `TAKOBL_KERNEL_KEY`.
+    Signature,
+}
+
+impl Verify {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Verify::None),
+            "sha256" => Some(Verify::Sha256),
+            "signature" => Some(Verify::Signature),
+            _ => None,
+        }
+    }
+
+    /// The weakest mode `takobl.cfg` may ask for. The file sits next to the
+    /// kernel, so whoever can swap the kernel can edit it too; with a key
+    /// built in, only a signature will do.
+    pub fn minimum() -> Self {
+        match KERNEL_PUBLIC_KEY {
+            Some(_) => Verify::Signature,
+            None => Verify::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    MissingFile(String),
+    BadDigestFile(String),
+    DigestMismatch,
+    NoPublicKey,
+    BadPublicKey,
+    BadSignatureFile(String),
+    BadSignature,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::MissingFile(path) => write!(f, "{} is missing", path),
+            VerifyError::BadDigestFile(path) => {
+                write!(f, "{} doesn't hold a SHA-256 digest", path)
+            }
+            VerifyError::DigestMismatch => write!(f, "the kernel doesn't match its digest"),
+            VerifyError::NoPublicKey => write!(f, "takobl was built without a kernel key"),
+            VerifyError::BadPublicKey => write!(f, "takobl's kernel key is invalid"),
+            VerifyError::BadSignatureFile(path) => {
+                write!(f, "{} isn't a 64 byte Ed25519 signature", path)
+            }
+            VerifyError::BadSignature => write!(f, "the signature is invalid"),
+        }
+    }
+}
+
+fn read_file(image_handle: Handle, bs: &BootServices, path: &str) -> Result<Vec<u8>, VerifyError> {
+    let mut fs: fs::FileSystem<'_> = bs
+        .get_image_file_system(image_handle)
+        .expect("Couldn't get filesystem");
+    let file_path = CString16::try_from(path).expect("Invalid path");
+    fs.read(Path::new(&file_path))
+        .map_err(|_| VerifyError::MissingFile(path.into()))
+}
+
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    let text = text.as_bytes();
+    if text.len() != N * 2 {
+        return None;
+    }
+    let mut result = [0u8; N];
+    for (i, byte) in result.iter_mut().enumerate() {
+        let pair = core::str::from_utf8(&text[i * 2..i * 2 + 2]).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(result)
+}
+
+fn check_digest(
+    image_handle: Handle,
+    bs: &BootServices,
+    path: &str,
+    kernel: &[u8],
+) -> Result<(), VerifyError> {
+    let digest_path = format!("{}.sha256", path);
+    let text = read_file(image_handle, bs, &digest_path)?;
+    // Also accepts `sha256sum` output, which is followed by the file name.
+    let expected = String::from_utf8_lossy(&text)
+        .split_whitespace()
+        .next()
+        .and_then(parse_hex::<32>)
+        .ok_or(VerifyError::BadDigestFile(digest_path))?;
+    if Sha256::digest(kernel).as_slice() != expected {
+        return Err(VerifyError::DigestMismatch);
+    }
+    Ok(())
+}
+
+fn check_signature(
+    image_handle: Handle,
+    bs: &BootServices,
+    path: &str,
+    data: &[u8],
+) -> Result<(), VerifyError> {
+    let key = KERNEL_PUBLIC_KEY.ok_or(VerifyError::NoPublicKey)?;
+    let key = parse_hex::<32>(key.trim())
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+        .ok_or(VerifyError::BadPublicKey)?;
+    let signature_path = format!("{}.sig", path);
+    let signature = read_file(image_handle, bs, &signature_path)?;
+    let signature = Signature::from_slice(&signature)
+        .map_err(|_| VerifyError::BadSignatureFile(signature_path))?;
+    key.verify_strict(data, &signature)
+        .map_err(|_| VerifyError::BadSignature)
+}
+
+/// Checks `kernel`, read from `path`, the way the boot entry asks for but at
+/// least as strictly as `Verify::minimum`.
+pub fn verify_kernel(
+    image_handle: Handle,
+    bs: &BootServices,
+    mode: Verify,
+    path: &str,
+    kernel: &[u8],
+) -> Result<(), VerifyError> {
+    let mode = mode.max(Verify::minimum());
+    match mode {
+        Verify::None => return Ok(()),
+        Verify::Sha256 => check_digest(image_handle, bs, path, kernel)?,
+        Verify::Signature => check_signature(image_handle, bs, path, kernel)?,
+    }
+    info!("{}: {:?} verified", path, mode);
+    Ok(())
+}
+
+/// Checks a boot module, as read from `path`, when takobl has a kernel key.
+/// Modules include the initrd, which holds the objects `kmod=` loads into
+/// the kernel, so they need a `<path>.sig` made with the same key.
+pub fn verify_module(
+    image_handle: Handle,
+    bs: &BootServices,
+    path: &str,
+    data: &[u8],
+) -> Result<(), VerifyError> {
+    if Verify::minimum() != Verify::Signature {
+        return Ok(());
+    }
+    check_signature(image_handle, bs, path, data)?;
+    info!("{}: {:?} verified", path, Verify::Signature);
+    Ok(())
+}
+
+/// Tells the user why the kernel won't boot and waits for a key, after which
+/// takobl returns to the firmware.
+pub fn refuse_to_boot(st: &mut SystemTable<Boot>, path: &str, error: &dyn fmt::Display) {
+    error!("Refusing to boot {}: {}", path, error);
+    let stdout = st.stdout();
+    let _ = stdout.set_color(Color::LightRed, Color::Black);
+    let _ = writeln!(stdout, "\ntakobl: refusing to boot {}", path);
+    let _ = writeln!(stdout, "{}\n", error);
+    let _ = stdout.set_color(Color::LightGray, Color::Black);
+    let _ = writeln!(stdout, "Press any key to return to the firmware");
+    let mut events = unsafe { [st.stdin().wait_for_key_event().unsafe_clone()] };
+    let _ = st.boot_services().wait_for_event(&mut events);
+    let _ = st.stdin().read_key();
+}
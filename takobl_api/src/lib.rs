@@ -1,9 +1,25 @@
 #![no_main]
 #![no_std]
 
+use core::fmt;
+use core::mem::size_of;
+
+use tags::TagIter;
+
+pub mod tags;
+
 const PAGE_SIZE: u64 = 4096;
 const MAX_FREE_MEMORY: usize = 63;
 
+pub const BOOT_DATA_MAGIC: u64 = u64::from_le_bytes(*b"TAKOBOOT");
+/// Bumped whenever the layout of `BootData` or of a tag changes.
+pub const BOOT_DATA_VERSION: u32 = 4;
+/// Section of the kernel ELF holding the `BOOT_DATA_VERSION` it was built
+/// with, as a little-endian `u32`. takobl checks it before booting, while it
+/// can still show a message on the screen.
+pub const BOOT_DATA_VERSION_SECTION: &str = ".boot_data_version";
+
+#[repr(C)]
 #[derive(Debug, Clone)]
 pub struct FrameBufferData {
     pub buffer_addr: *mut u8,
@@ -12,15 +28,117 @@ pub struct FrameBufferData {
     pub stride: usize,
 }
 
+/// A range of bytes in the kernel's address space.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootSlice {
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl BootSlice {
+    pub fn new(addr: u64, len: u64) -> Self {
+        Self { addr, len }
+    }
+
+    /// # Safety
+    /// The range has to be mapped for as long as the slice is used.
+    pub unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+        core::slice::from_raw_parts(self.addr as *const u8, self.len as usize)
+    }
+
+    /// # Safety
+    /// As `as_slice`, and nothing else may reference the range.
+    pub unsafe fn as_mut_slice<'a>(&self) -> &'a mut [u8] {
+        core::slice::from_raw_parts_mut(self.addr as *mut u8, self.len as usize)
+    }
+
+    /// # Safety
+    /// As `as_slice`.
+    pub unsafe fn as_str<'a>(&self) -> Option<&'a str> {
+        core::str::from_utf8(self.as_slice()).ok()
+    }
+}
+
+/// Handed to the kernel's `_start` in `rdi`. The header fields come first and
+/// never move, so a kernel can recognise a loader built from another commit.
+#[repr(C)]
 #[derive(Debug)]
 pub struct BootData {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<BootData>()` in the loader.
+    pub size: u32,
     pub frame_buffer: FrameBufferData,
     pub free_memory_map: FreeMemoryMap,
     pub loader_code: MemoryRegion,
-    pub image_device_path: &'static str,
-    pub ramdisk: &'static mut [u8],
+    /// Address the kernel's first segment was loaded at, `KERNEL_LINK_BASE`
+    /// plus the random slide.
+    pub kernel_base: u64,
+    /// Address of the first tag, see `tags`. 0 if there are none.
+    pub tags: u64,
+    /// Virtual address of the UEFI runtime services table. 0 if the firmware
+    /// refused `SetVirtualAddressMap`.
+    pub runtime_services: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootDataError {
+    BadMagic(u64),
+    VersionMismatch { loader: u32, kernel: u32 },
+    SizeMismatch { loader: u32, kernel: u32 },
+}
+
+impl fmt::Display for BootDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootDataError::BadMagic(magic) => write!(
+                f,
+                "boot data magic is {:016X}, expected {:016X}; not loaded by takobl?",
+                magic, BOOT_DATA_MAGIC
+            ),
+            BootDataError::VersionMismatch { loader, kernel } => write!(
+                f,
+                "loader speaks boot data version {}, kernel expects {}; update takobl",
+                loader, kernel
+            ),
+            BootDataError::SizeMismatch { loader, kernel } => write!(
+                f,
+                "boot data is {} bytes, kernel expects {}; loader and kernel are out of sync",
+                loader, kernel
+            ),
+        }
+    }
+}
+
+impl BootData {
+    pub fn check(&self) -> Result<(), BootDataError> {
+        if self.magic != BOOT_DATA_MAGIC {
+            return Err(BootDataError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_DATA_VERSION {
+            return Err(BootDataError::VersionMismatch {
+                loader: self.version,
+                kernel: BOOT_DATA_VERSION,
+            });
+        }
+        let size = size_of::<BootData>() as u32;
+        if self.size != size {
+            return Err(BootDataError::SizeMismatch {
+                loader: self.size,
+                kernel: size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Only meaningful once `check` has passed.
+    pub fn tags(&self) -> TagIter<'_> {
+        unsafe { TagIter::new(self.tags) }
+    }
 }
 
+#[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct MemoryRegion {
     pub start: u64,
@@ -39,6 +157,7 @@ impl MemoryRegion {
     }
 }
 
+#[repr(C)]
 #[derive(Debug, Clone)]
 pub struct FreeMemoryMap {
     pub count: usize,
@@ -125,3 +244,8 @@ impl FreeMemoryMap {
 }
 
 pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_C000_0000_0000;
+pub const KERNEL_LINK_BASE: u64 = 0xFFFF_FFFF_8000_0000;
+/// Firmware runtime regions are mapped here, at their physical address plus
+/// `RUNTIME_AREA_START`, for `SetVirtualAddressMap`.
+pub const RUNTIME_AREA_START: u64 = 0xFFFF_FE00_0000_0000;
+pub const RUNTIME_AREA_END: u64 = 0xFFFF_FF00_0000_0000;
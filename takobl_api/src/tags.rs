@@ -0,0 +1,189 @@
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::slice;
+
+use crate::BootSlice;
+
+// Optional boot information follows `BootData` as a list of tags. Every tag
+// starts with a `TagHeader` whose size covers the header and payload; the
+// next tag starts at the following 8 byte boundary. `TAG_END` terminates the
+// list. Kernels skip tags they don't know.
+
+pub const TAG_END: u32 = 0;
+/// Array of `MemoryMapEntry`.
+pub const TAG_MEMORY_MAP: u32 = 1;
+/// `AcpiTag`.
+pub const TAG_ACPI: u32 = 2;
+/// `SmbiosTag`.
+pub const TAG_SMBIOS: u32 = 3;
+/// `SymbolsHeader`, then the kernel's `.symtab` and `.strtab`.
+pub const TAG_SYMBOLS: u32 = 4;
+/// UTF-8 string.
+pub const TAG_COMMAND_LINE: u32 = 5;
+/// `ModuleHeader`, then the UTF-8 module name.
+pub const TAG_MODULE: u32 = 6;
+/// Raw UEFI device path of the disk takobl was loaded from, followed by the
+/// loader's file path and an end node.
+pub const TAG_DEVICE_PATH: u32 = 7;
+
+pub const TAG_ALIGN: usize = 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TagHeader {
+    pub kind: u32,
+    pub size: u32,
+}
+
+/// A UEFI memory descriptor as reported before `ExitBootServices`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapEntry {
+    /// UEFI memory type.
+    pub kind: u32,
+    pub padding: u32,
+    pub physical_start: u64,
+    /// For runtime regions, the address takobl passed to
+    /// `SetVirtualAddressMap`.
+    pub virtual_start: u64,
+    pub pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTag {
+    /// Physical address of the RSDP.
+    pub rsdp: u64,
+    /// 0 for ACPI 1.0, 2 for the extended RSDP.
+    pub revision: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SmbiosTag {
+    /// Physical address of the entry point structure.
+    pub entry_point: u64,
+    /// 2 or 3, which entry point format it is.
+    pub major_version: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolsHeader {
+    pub symtab_size: u64,
+    pub strtab_size: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleHeader {
+    /// Where the module is mapped in the kernel's address space.
+    pub data: BootSlice,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Tag<'a> {
+    MemoryMap(&'a [MemoryMapEntry]),
+    Acpi(&'a AcpiTag),
+    Smbios(&'a SmbiosTag),
+    Symbols { symtab: &'a [u8], strtab: &'a [u8] },
+    CommandLine(&'a str),
+    Module { name: &'a str, data: BootSlice },
+    DevicePath(&'a [u8]),
+    Unknown { kind: u32, payload: &'a [u8] },
+}
+
+pub const fn align_tag(size: usize) -> usize {
+    (size + TAG_ALIGN - 1) & !(TAG_ALIGN - 1)
+}
+
+unsafe fn read<T: Copy>(payload: &[u8]) -> Option<&T> {
+    if payload.len() < size_of::<T>() {
+        return None;
+    }
+    Some(&*(payload.as_ptr() as *const T))
+}
+
+fn parse(kind: u32, payload: &[u8]) -> Option<Tag<'_>> {
+    let tag = match kind {
+        TAG_MEMORY_MAP => Tag::MemoryMap(unsafe {
+            slice::from_raw_parts(
+                payload.as_ptr() as *const MemoryMapEntry,
+                payload.len() / size_of::<MemoryMapEntry>(),
+            )
+        }),
+        TAG_ACPI => Tag::Acpi(unsafe { read(payload)? }),
+        TAG_SMBIOS => Tag::Smbios(unsafe { read(payload)? }),
+        TAG_SYMBOLS => {
+            let header: &SymbolsHeader = unsafe { read(payload)? };
+            let rest = &payload[size_of::<SymbolsHeader>()..];
+            let symtab_size = header.symtab_size as usize;
+            let strtab_size = header.strtab_size as usize;
+            if rest.len() < symtab_size + strtab_size {
+                return None;
+            }
+            Tag::Symbols {
+                symtab: &rest[..symtab_size],
+                strtab: &rest[symtab_size..symtab_size + strtab_size],
+            }
+        }
+        TAG_COMMAND_LINE => Tag::CommandLine(core::str::from_utf8(payload).ok()?),
+        TAG_MODULE => {
+            let header: &ModuleHeader = unsafe { read(payload)? };
+            let name = &payload[size_of::<ModuleHeader>()..];
+            Tag::Module {
+                name: core::str::from_utf8(name).ok()?,
+                data: header.data,
+            }
+        }
+        TAG_DEVICE_PATH => Tag::DevicePath(payload),
+        kind => Tag::Unknown { kind, payload },
+    };
+    Some(tag)
+}
+
+pub struct TagIter<'a> {
+    next: *const u8,
+    _marker: PhantomData<&'a u8>,
+}
+
+impl<'a> TagIter<'a> {
+    /// # Safety
+    /// `addr` has to point at a tag list terminated by `TAG_END`, or be 0.
+    pub unsafe fn new(addr: u64) -> Self {
+        Self {
+            next: addr as *const u8,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for TagIter<'a> {
+    type Item = Tag<'a>;
+
+    fn next(&mut self) -> Option<Tag<'a>> {
+        loop {
+            if self.next.is_null() {
+                return None;
+            }
+            let header = unsafe { *(self.next as *const TagHeader) };
+            let size = header.size as usize;
+            if header.kind == TAG_END || size < size_of::<TagHeader>() {
+                self.next = core::ptr::null();
+                return None;
+            }
+            let payload = unsafe {
+                slice::from_raw_parts(
+                    self.next.add(size_of::<TagHeader>()),
+                    size - size_of::<TagHeader>(),
+                )
+            };
+            self.next = unsafe { self.next.add(align_tag(size)) };
+            // Malformed tags are skipped rather than ending the list.
+            if let Some(tag) = parse(header.kind, payload) {
+                return Some(tag);
+            }
+        }
+    }
+}
@@ -21,9 +21,28 @@ SECTIONS
     . = 0xFFFFFFFFC0000000 ;
 
     .rodata : { *(.rodata) *(.rodata.*) }
+    .dynsym : { *(.dynsym) }
+    .dynstr : { *(.dynstr) }
+    .hash : { *(.hash) }
+    .gnu.hash : { *(.gnu.hash) }
+    .rela.dyn : { *(.rela.dyn) *(.rela.*) }
     .eh_frame_hdr : { *(.eh_frame_hdr) }
     .eh_frame : { *(.eh_frame) }
     .data.rel.ro : { *(.data.rel.ro) *(.data.rel.ro.*) }
+    .kernel_symbols :
+    {
+        __start_kernel_symbols = . ;
+        KEEP(*(.kernel_symbols))
+        __stop_kernel_symbols = . ;
+    }
+    .initcalls :
+    {
+        __start_initcalls = . ;
+        KEEP(*(.initcalls))
+        __stop_initcalls = . ;
+    }
+    .boot_data_version : { KEEP(*(.boot_data_version)) }
+    .dynamic : { *(.dynamic) }
     .got : { *(.got) }
     .data : { *(.data) *(.data.*) }
     .bss : { *(.bss) *(.bss.*) }
@@ -0,0 +1,25 @@
+# Kernel module for the kmod tests. Rebuild with
+# `as test_module.s -o test_module.o && strip -d -R .note.GNU-stack test_module.o`.
+    .text
+    .globl module_init
+module_init:
+    # A kernel export through the GOT.
+    mov takos_alloc@GOTPCREL(%rip), %rax
+    test %rax, %rax
+    jz 1f
+    # Data through an absolute pointer, patched by R_X86_64_64.
+    mov pointer(%rip), %rax
+    cmpl $42, (%rax)
+    jne 1f
+    xor %eax, %eax
+    ret
+1:
+    mov $1, %eax
+    ret
+
+    .data
+answer:
+    .long 42
+    .align 8
+pointer:
+    .quad answer
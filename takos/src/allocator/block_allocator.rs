@@ -7,16 +7,19 @@ use log::info;
 use spin::{Mutex, MutexGuard};
 use x86_64::structures::paging::FrameAllocator;
 
+use crate::initcall::{initcall, InitResult};
 use crate::paging::map_writable_page;
+use crate::random::random_u64;
 
 use super::frame_allocator::FRAME_ALLOCATOR;
 
 const BLOCK_SIZES: &[u64] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 const BLOCK_COUNTS: &[u64] = &[512, 256, 128, 64, 32, 16, 8, 4, 2];
 
-const HEAP_START: u64 = 0xFFFF_D000_0000_0000;
+const HEAP_REGION_START: u64 = 0xFFFF_D000_0000_0000;
+const HEAP_REGION_SIZE: u64 = 0x1000_0000_0000;
 const HEAP_SIZE: u64 = 128 * 1024 * 1024;
-const HEAP_END: u64 = HEAP_START + HEAP_SIZE;
+const HEAP_ALIGN: u64 = 2 * 1024 * 1024;
 
 struct FreeListNode {
     next: Option<&'static mut FreeListNode>,
@@ -26,6 +29,7 @@ struct FreeList {
     first: Option<&'static mut FreeListNode>,
 }
 pub struct BlockAllocator {
+    heap_start: u64,
     next_page_addr: u64,
     free_lists: [FreeList; BLOCK_SIZES.len()],
     page_free_list: FreeList,
@@ -35,15 +39,20 @@ impl BlockAllocator {
     const fn new() -> Self {
         const EMPTY: FreeList = FreeList { first: None };
         Self {
-            next_page_addr: HEAP_START,
+            heap_start: HEAP_REGION_START,
+            next_page_addr: HEAP_REGION_START,
             free_lists: [EMPTY; BLOCK_SIZES.len()],
             page_free_list: EMPTY,
         }
     }
 
+    fn heap_end(&self) -> u64 {
+        self.heap_start + HEAP_SIZE
+    }
+
     fn get_new_page(&mut self) -> Option<u64> {
         if self.page_free_list.first.is_none() {
-            if self.next_page_addr >= HEAP_END {
+            if self.next_page_addr >= self.heap_end() {
                 return None;
             }
 
@@ -103,7 +112,7 @@ impl BlockAllocator {
     }
 
     fn allocate_big(&mut self, pages: u64) -> Option<u64> {
-        if self.next_page_addr + (pages - 1) * 0x1000 >= HEAP_END {
+        if self.next_page_addr + (pages - 1) * 0x1000 >= self.heap_end() {
             return None;
         }
 
@@ -177,3 +186,27 @@ unsafe impl GlobalAlloc for Locked<BlockAllocator> {
 
 #[global_allocator]
 pub static ALLOCATOR: Locked<BlockAllocator> = Locked::new(BlockAllocator::new());
+
+/// Moves the heap to a random 2 MiB aligned base inside the heap region.
+/// Must run before the first allocation.
+pub fn init_heap() -> InitResult {
+    let slots = (HEAP_REGION_SIZE - HEAP_SIZE) / HEAP_ALIGN;
+    let heap_start = HEAP_REGION_START + random_u64() % slots * HEAP_ALIGN;
+    let mut allocator = ALLOCATOR.lock();
+    if allocator.next_page_addr != allocator.heap_start {
+        // Building the error mustn't happen with the allocator locked.
+        drop(allocator);
+        return Err("heap already in use".into());
+    }
+    allocator.heap_start = heap_start;
+    allocator.next_page_addr = heap_start;
+    Ok(())
+}
+
+initcall! {
+    name: "heap",
+    stage: Memory,
+    depends_on: ["frame-allocator"],
+    optional: false,
+    run: |_| init_heap(),
+}
@@ -5,6 +5,8 @@ use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Si
 use super::{fresh_frame_allocator::FreshFrameAllocator, used_frame_allocator::UsedFrameAllocator};
 use lazy_static::lazy_static;
 
+use crate::initcall::{initcall, InitResult};
+
 pub struct TakosFrameAllocator {
     fresh_frame_allocator: FreshFrameAllocator,
     used_frame_allocator: UsedFrameAllocator,
@@ -44,8 +46,17 @@ lazy_static! {
         Mutex::new(TakosFrameAllocator::new());
 }
 
-pub fn init_frame_allocator(free_memory_map: FreeMemoryMap) {
+pub fn init_frame_allocator(free_memory_map: FreeMemoryMap) -> InitResult {
     FRAME_ALLOCATOR.lock().set_free_memory_map(free_memory_map);
+    Ok(())
+}
+
+initcall! {
+    name: "frame-allocator",
+    stage: Memory,
+    depends_on: [],
+    optional: false,
+    run: |boot_data| init_frame_allocator(boot_data.free_memory_map.clone()),
 }
 
 #[test_case]
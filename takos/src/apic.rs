@@ -0,0 +1,76 @@
+use conquer_once::spin::OnceCell;
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu::{has_feature, CpuFeatures};
+use crate::initcall::{initcall, InitResult};
+use crate::paging::map_mmio;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS_VECTOR: usize = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Physical address that MSI messages are written to; the destination APIC
+/// ID goes into bits 12..20.
+pub const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+struct LocalApic {
+    base: u64,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ((self.base as usize + register) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ((self.base as usize + register) as *mut u32).write_volatile(value) }
+    }
+}
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+pub fn init_local_apic() -> InitResult {
+    if !has_feature(CpuFeatures::APIC) {
+        return Ok(());
+    }
+    let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+    let base_value = unsafe { base_msr.read() };
+    unsafe { base_msr.write(base_value | APIC_BASE_ENABLE) };
+    let physical_base = base_value & 0x000F_FFFF_FFFF_F000;
+
+    let apic = LOCAL_APIC.get_or_init(|| LocalApic {
+        base: map_mmio(physical_base, 0x1000),
+    });
+    apic.write(
+        LAPIC_SPURIOUS_VECTOR,
+        LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    Ok(())
+}
+
+initcall! {
+    name: "apic",
+    stage: Interrupts,
+    depends_on: [],
+    optional: false,
+    run: |_| init_local_apic(),
+}
+
+pub fn local_apic_id() -> u8 {
+    LOCAL_APIC
+        .get()
+        .map(|apic| (apic.read(LAPIC_ID) >> 24) as u8)
+        .unwrap_or(0)
+}
+
+pub fn end_of_interrupt() {
+    if let Some(apic) = LOCAL_APIC.get() {
+        apic.write(LAPIC_EOI, 0);
+    }
+}
@@ -0,0 +1,67 @@
+use core::fmt::Write;
+
+use log::info;
+use takobl_api::tags::Tag;
+use takobl_api::{BootData, BOOT_DATA_VERSION};
+
+use crate::hlt_loop;
+use crate::serial::{ComPort, SerialPort};
+
+/// Read by takobl, which refuses to boot a kernel of another version. Its
+/// name is `BOOT_DATA_VERSION_SECTION`.
+#[used]
+#[link_section = ".boot_data_version"]
+static BOOT_DATA_VERSION_NOTE: [u8; 4] = BOOT_DATA_VERSION.to_le_bytes();
+
+/// Halts with a message if `boot_data` comes from an incompatible loader.
+/// takobl catches mismatches itself, so this is only reached with a loader
+/// from before the version section, or something else entirely.
+pub fn check_boot_data(boot_data: &BootData) {
+    if let Err(error) = boot_data.check() {
+        // Nothing in BootData can be trusted when the check fails, not even
+        // the frame buffer, so the message goes to the first serial port.
+        if let Some(mut serial) = SerialPort::probe(ComPort::Com1) {
+            let _ = writeln!(serial, "takos: refusing to boot: {}", error);
+        }
+        hlt_loop();
+    }
+}
+
+pub fn command_line(boot_data: &BootData) -> &str {
+    boot_data
+        .tags()
+        .find_map(|tag| match tag {
+            Tag::CommandLine(command_line) => Some(command_line),
+            _ => None,
+        })
+        .unwrap_or("")
+}
+
+pub fn log_boot_tags(boot_data: &BootData) {
+    for tag in boot_data.tags() {
+        match tag {
+            Tag::MemoryMap(entries) => info!("Boot tag: memory map, {} entries", entries.len()),
+            Tag::Acpi(acpi) => info!(
+                "Boot tag: ACPI RSDP at {:016X}, revision {}",
+                acpi.rsdp, acpi.revision
+            ),
+            Tag::Smbios(smbios) => info!(
+                "Boot tag: SMBIOS {} entry point at {:016X}",
+                smbios.major_version, smbios.entry_point
+            ),
+            Tag::Symbols { symtab, strtab } => info!(
+                "Boot tag: symbols, {} bytes of symbols, {} bytes of strings",
+                symtab.len(),
+                strtab.len()
+            ),
+            Tag::CommandLine(_) | Tag::DevicePath(_) => {}
+            Tag::Module { name, data } => info!(
+                "Boot tag: module {} at {:016X}, {} bytes",
+                name, data.addr, data.len
+            ),
+            Tag::Unknown { kind, payload } => {
+                info!("Boot tag: unknown kind {}, {} bytes", kind, payload.len())
+            }
+        }
+    }
+}
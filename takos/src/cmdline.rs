@@ -0,0 +1,153 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use log::{warn, LevelFilter};
+
+use crate::serial::ComPort;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    FrameBuffer,
+    Serial,
+}
+
+/// Options passed by takobl, e.g. `loglevel=debug console=serial nosmp`.
+/// `kmod=` may be repeated.
+#[derive(Debug, Clone)]
+pub struct CommandLine {
+    pub log_level: LevelFilter,
+    /// From `loglevel=<module>=<level>`, e.g. `loglevel=takos::pci=trace`.
+    pub module_log_levels: Vec<(String, LevelFilter)>,
+    /// From `dmesglevel=`; dmesg also keeps whatever the console shows.
+    pub dmesg_level: LevelFilter,
+    /// From `dmesglevel=<module>=<level>`. By default the ramdisk and FAT
+    /// trace lines, logged on every read, are left out.
+    pub dmesg_module_levels: Vec<(String, LevelFilter)>,
+    pub console: Console,
+    pub init: Option<String>,
+    /// Cleared by `nosmp`; there's only the boot CPU so far.
+    pub smp: bool,
+    pub test: Option<String>,
+    pub kmods: Vec<String>,
+    pub gdb: Option<ComPort>,
+    pub unknown: Vec<String>,
+}
+
+impl Default for CommandLine {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Info,
+            module_log_levels: Vec::new(),
+            dmesg_level: LevelFilter::Trace,
+            dmesg_module_levels: vec![
+                ("takos::filesystem::ramdisk".to_string(), LevelFilter::Debug),
+                ("takos::filesystem::fat".to_string(), LevelFilter::Debug),
+            ],
+            console: Console::FrameBuffer,
+            init: None,
+            smp: true,
+            test: None,
+            kmods: Vec::new(),
+            gdb: None,
+            unknown: Vec::new(),
+        }
+    }
+}
+
+impl CommandLine {
+    pub fn parse(text: &str) -> Self {
+        let mut result = CommandLine::default();
+        for option in text.split_whitespace() {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            };
+            match (key, value) {
+                ("loglevel", Some(level)) => match level.split_once('=') {
+                    Some((module, level)) => match level.parse() {
+                        Ok(level) => result.module_log_levels.push((module.to_string(), level)),
+                        Err(_) => result.unknown.push(option.to_string()),
+                    },
+                    None => match level.parse() {
+                        Ok(level) => result.log_level = level,
+                        Err(_) => result.unknown.push(option.to_string()),
+                    },
+                },
+                ("dmesglevel", Some(level)) => match level.split_once('=') {
+                    Some((module, level)) => match level.parse() {
+                        Ok(level) => result.dmesg_module_levels.push((module.to_string(), level)),
+                        Err(_) => result.unknown.push(option.to_string()),
+                    },
+                    None => match level.parse() {
+                        Ok(level) => result.dmesg_level = level,
+                        Err(_) => result.unknown.push(option.to_string()),
+                    },
+                },
+                ("console", Some("serial")) => result.console = Console::Serial,
+                ("console", Some("fb")) => result.console = Console::FrameBuffer,
+                ("init", Some(path)) => result.init = Some(path.to_string()),
+                ("nosmp", None) => result.smp = false,
+                ("test", Some(name)) => result.test = Some(name.to_string()),
+                ("kmod", Some(path)) => result.kmods.push(path.to_string()),
+                ("gdb", Some("com1")) => result.gdb = Some(ComPort::Com1),
+                ("gdb", Some("com2")) => result.gdb = Some(ComPort::Com2),
+                _ => result.unknown.push(option.to_string()),
+            }
+        }
+        result
+    }
+}
+
+static COMMAND_LINE: OnceCell<CommandLine> = OnceCell::uninit();
+
+/// Needs the heap; runs before the logger so it can pick up `loglevel=`.
+pub fn init_cmdline(text: &str) {
+    COMMAND_LINE.init_once(|| CommandLine::parse(text));
+}
+
+pub fn cmdline() -> &'static CommandLine {
+    COMMAND_LINE.get().expect("Command line isn't parsed yet")
+}
+
+pub fn warn_unknown_options() {
+    for option in cmdline().unknown.iter() {
+        warn!("Unknown kernel option `{}`", option);
+    }
+}
+
+#[test_case]
+fn test_parse_cmdline() {
+    use crate::{print, println};
+    print!("test_parse_cmdline... ");
+
+    let cmdline = CommandLine::parse(
+        "loglevel=debug  console=serial init=/bin/sh nosmp test=cat kmod=/a.ko kmod=/b.ko gdb=com2 loglevel=takos::pci=trace dmesglevel=debug dmesglevel=takos::filesystem::fat=trace x=1",
+    );
+    assert_eq!(cmdline.log_level, LevelFilter::Debug);
+    assert_eq!(
+        cmdline.module_log_levels,
+        [("takos::pci".to_string(), LevelFilter::Trace)]
+    );
+    assert_eq!(cmdline.dmesg_level, LevelFilter::Debug);
+    assert_eq!(
+        cmdline.dmesg_module_levels.last(),
+        Some(&("takos::filesystem::fat".to_string(), LevelFilter::Trace))
+    );
+    assert_eq!(cmdline.console, Console::Serial);
+    assert_eq!(cmdline.init.as_deref(), Some("/bin/sh"));
+    assert!(!cmdline.smp);
+    assert_eq!(cmdline.test.as_deref(), Some("cat"));
+    assert_eq!(cmdline.kmods, ["/a.ko", "/b.ko"]);
+    assert_eq!(cmdline.gdb, Some(ComPort::Com2));
+    assert_eq!(cmdline.unknown, ["x=1"]);
+
+    let cmdline = CommandLine::parse("");
+    assert_eq!(cmdline.log_level, LevelFilter::Info);
+    assert_eq!(cmdline.dmesg_level, LevelFilter::Trace);
+    assert_eq!(cmdline.dmesg_module_levels.len(), 2);
+    assert!(cmdline.smp);
+    assert_eq!(cmdline.test, None);
+
+    println!("[ok]");
+}
@@ -1,16 +1,22 @@
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
+use futures::StreamExt;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
+use crate::initcall::{initcall, InitResult};
 use crate::{
+    diagnostics,
     display::{ColorRGB, FrameBuffer},
     keyboard::{
         self,
         keycodes::{KeyCode, KeyState},
     },
+    serial::{self, SerialStream},
 };
 
 const TEXT_BUFFER_SIZE: usize = 64;
@@ -151,8 +157,21 @@ lazy_static! {
         Mutex::new(ConsoleWriter::new(FrameBuffer::empty()));
 }
 
-pub fn init_writer(frame_buffer: FrameBuffer) {
+pub fn init_writer(frame_buffer: FrameBuffer) -> InitResult {
     *WRITER.lock() = ConsoleWriter::new(frame_buffer);
+    Ok(())
+}
+
+initcall! {
+    name: "framebuffer",
+    stage: Console,
+    depends_on: ["heap"],
+    optional: false,
+    run: |boot_data| {
+        let frame_buffer = FrameBuffer::new(&boot_data.frame_buffer);
+        frame_buffer.fill(ColorRGB::from_hex(0x000000));
+        init_writer(frame_buffer)
+    },
 }
 
 impl Write for ConsoleWriter {
@@ -164,13 +183,39 @@ impl Write for ConsoleWriter {
     }
 }
 
+static FRAME_BUFFER_OUTPUT: AtomicBool = AtomicBool::new(true);
+
+/// With `console=serial`, output only goes to the serial port.
+pub fn set_frame_buffer_output(enabled: bool) {
+    FRAME_BUFFER_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // Serial first, so a panic with `WRITER` held still gets out.
+        serial::_serial_print(args);
+        if FRAME_BUFFER_OUTPUT.load(Ordering::Relaxed) {
+            WRITER.lock().write_fmt(args).unwrap();
+        }
     });
 }
 
+/// Like `_print`, but drops output instead of waiting for a lock, for NMIs
+/// that can arrive while the console is in use.
+pub fn try_print(args: core::fmt::Arguments) {
+    if let Some(mut serial) = serial::SERIAL.try_lock() {
+        if let Some(serial) = serial.as_mut() {
+            let _ = serial.write_fmt(args);
+        }
+    }
+    if FRAME_BUFFER_OUTPUT.load(Ordering::Relaxed) {
+        if let Some(mut writer) = WRITER.try_lock() {
+            let _ = writer.write_fmt(args);
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {{
@@ -199,9 +244,43 @@ pub async fn console_scroll_handler() {
             KeyCode::DownArrow | KeyCode::S => WRITER.lock().scroll_down(),
             KeyCode::PageUp => WRITER.lock().page_up(),
             KeyCode::PageDown => WRITER.lock().page_down(),
+            key if diagnostics::run_hotkey(key) => {}
             _ => {
                 println!("{:?}", event);
             }
         }
     }
 }
+
+/// Runs diagnostic commands typed on the serial console, for machines without
+/// a keyboard or screen.
+pub async fn serial_command_handler() {
+    let Some(port) = serial::console_port() else {
+        return;
+    };
+    let mut bytes = SerialStream::new(port);
+    let mut line = String::new();
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            b'\r' | b'\n' => {
+                println!();
+                let command = line.trim();
+                if !command.is_empty() && !diagnostics::run_command(command) {
+                    println!("Unknown command `{}`, try `help`", command);
+                }
+                line.clear();
+            }
+            // Backspace and DEL
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    serial::_serial_print(format_args!("\x08 \x08"));
+                }
+            }
+            byte if byte.is_ascii_graphic() || byte == b' ' => {
+                line.push(byte as char);
+                serial::_serial_print(format_args!("{}", byte as char));
+            }
+            _ => {}
+        }
+    }
+}
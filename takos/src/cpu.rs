@@ -0,0 +1,397 @@
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+use core::fmt;
+use core::str;
+
+use bitflags::bitflags;
+use conquer_once::spin::OnceCell;
+
+use crate::initcall::{initcall, InitResult};
+use crate::println;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        // Leaf 1, EDX
+        const FPU = 1 << 0;
+        const TSC = 1 << 1;
+        const MSR = 1 << 2;
+        const PAE = 1 << 3;
+        const APIC = 1 << 4;
+        const PGE = 1 << 5;
+        const PAT = 1 << 6;
+        const CLFLUSH = 1 << 7;
+        const MMX = 1 << 8;
+        const FXSR = 1 << 9;
+        const SSE = 1 << 10;
+        const SSE2 = 1 << 11;
+        const HTT = 1 << 12;
+        // Leaf 1, ECX
+        const SSE3 = 1 << 13;
+        const PCLMULQDQ = 1 << 14;
+        const MONITOR = 1 << 15;
+        const SSSE3 = 1 << 16;
+        const FMA = 1 << 17;
+        const CX16 = 1 << 18;
+        const PCID = 1 << 19;
+        const SSE4_1 = 1 << 20;
+        const SSE4_2 = 1 << 21;
+        const X2APIC = 1 << 22;
+        const POPCNT = 1 << 23;
+        const TSC_DEADLINE = 1 << 24;
+        const AES = 1 << 25;
+        const XSAVE = 1 << 26;
+        const OSXSAVE = 1 << 27;
+        const AVX = 1 << 28;
+        const F16C = 1 << 29;
+        const RDRAND = 1 << 30;
+        const HYPERVISOR = 1 << 31;
+        // Leaf 7, EBX/ECX
+        const FSGSBASE = 1 << 32;
+        const BMI1 = 1 << 33;
+        const AVX2 = 1 << 34;
+        const SMEP = 1 << 35;
+        const BMI2 = 1 << 36;
+        const ERMS = 1 << 37;
+        const INVPCID = 1 << 38;
+        const AVX512F = 1 << 39;
+        const RDSEED = 1 << 40;
+        const ADX = 1 << 41;
+        const SMAP = 1 << 42;
+        const CLFLUSHOPT = 1 << 43;
+        const UMIP = 1 << 44;
+        // Leaf 0xD, EAX
+        const XSAVEOPT = 1 << 45;
+        const XSAVEC = 1 << 46;
+        const XSAVES = 1 << 47;
+        // Leaf 0x80000001, EDX
+        const SYSCALL = 1 << 48;
+        const NX = 1 << 49;
+        const PAGE_1GB = 1 << 50;
+        const RDTSCP = 1 << 51;
+        const LONG_MODE = 1 << 52;
+        // Leaf 0x80000007, EDX
+        const INVARIANT_TSC = 1 << 53;
+    }
+}
+
+/// (leaf register bit, feature) pairs for the registers decoded below.
+const LEAF1_EDX: &[(u32, CpuFeatures)] = &[
+    (0, CpuFeatures::FPU),
+    (4, CpuFeatures::TSC),
+    (5, CpuFeatures::MSR),
+    (6, CpuFeatures::PAE),
+    (9, CpuFeatures::APIC),
+    (13, CpuFeatures::PGE),
+    (16, CpuFeatures::PAT),
+    (19, CpuFeatures::CLFLUSH),
+    (23, CpuFeatures::MMX),
+    (24, CpuFeatures::FXSR),
+    (25, CpuFeatures::SSE),
+    (26, CpuFeatures::SSE2),
+    (28, CpuFeatures::HTT),
+];
+
+const LEAF1_ECX: &[(u32, CpuFeatures)] = &[
+    (0, CpuFeatures::SSE3),
+    (1, CpuFeatures::PCLMULQDQ),
+    (3, CpuFeatures::MONITOR),
+    (9, CpuFeatures::SSSE3),
+    (12, CpuFeatures::FMA),
+    (13, CpuFeatures::CX16),
+    (17, CpuFeatures::PCID),
+    (19, CpuFeatures::SSE4_1),
+    (20, CpuFeatures::SSE4_2),
+    (21, CpuFeatures::X2APIC),
+    (23, CpuFeatures::POPCNT),
+    (24, CpuFeatures::TSC_DEADLINE),
+    (25, CpuFeatures::AES),
+    (26, CpuFeatures::XSAVE),
+    (27, CpuFeatures::OSXSAVE),
+    (28, CpuFeatures::AVX),
+    (29, CpuFeatures::F16C),
+    (30, CpuFeatures::RDRAND),
+    (31, CpuFeatures::HYPERVISOR),
+];
+
+const LEAF7_EBX: &[(u32, CpuFeatures)] = &[
+    (0, CpuFeatures::FSGSBASE),
+    (3, CpuFeatures::BMI1),
+    (5, CpuFeatures::AVX2),
+    (7, CpuFeatures::SMEP),
+    (8, CpuFeatures::BMI2),
+    (9, CpuFeatures::ERMS),
+    (10, CpuFeatures::INVPCID),
+    (16, CpuFeatures::AVX512F),
+    (18, CpuFeatures::RDSEED),
+    (19, CpuFeatures::ADX),
+    (20, CpuFeatures::SMAP),
+    (23, CpuFeatures::CLFLUSHOPT),
+];
+
+const LEAF7_ECX: &[(u32, CpuFeatures)] = &[(2, CpuFeatures::UMIP)];
+
+const LEAFD_EAX: &[(u32, CpuFeatures)] = &[
+    (0, CpuFeatures::XSAVEOPT),
+    (1, CpuFeatures::XSAVEC),
+    (3, CpuFeatures::XSAVES),
+];
+
+const EXTENDED_LEAF1_EDX: &[(u32, CpuFeatures)] = &[
+    (11, CpuFeatures::SYSCALL),
+    (20, CpuFeatures::NX),
+    (26, CpuFeatures::PAGE_1GB),
+    (27, CpuFeatures::RDTSCP),
+    (29, CpuFeatures::LONG_MODE),
+];
+
+const EXTENDED_LEAF7_EDX: &[(u32, CpuFeatures)] = &[(8, CpuFeatures::INVARIANT_TSC)];
+
+fn decode(register: u32, bits: &[(u32, CpuFeatures)]) -> CpuFeatures {
+    bits.iter()
+        .filter(|(bit, _)| register & (1 << bit) != 0)
+        .fold(CpuFeatures::empty(), |features, (_, feature)| {
+            features | *feature
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo {
+    pub level: u8,
+    pub kind: CacheKind,
+    pub line_size: usize,
+    pub ways: usize,
+    pub sets: usize,
+    /// Number of logical processors sharing this cache.
+    pub shared_by: usize,
+}
+
+impl CacheInfo {
+    pub fn size(&self) -> usize {
+        self.line_size * self.ways * self.sets
+    }
+
+    /// Decodes the EAX..EDX layout shared by leaf 4 (Intel) and
+    /// leaf 0x8000001D (AMD).
+    fn from_cpuid(result: CpuidResult) -> Option<Self> {
+        let kind = match result.eax & 0x1F {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            _ => return None,
+        };
+        Some(CacheInfo {
+            level: ((result.eax >> 5) & 0x7) as u8,
+            kind,
+            line_size: (result.ebx & 0xFFF) as usize + 1,
+            ways: (result.ebx >> 22) as usize + 1,
+            sets: result.ecx as usize + 1,
+            shared_by: ((result.eax >> 14) & 0xFFF) as usize + 1,
+        })
+    }
+}
+
+const MAX_CACHES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: CpuFeatures,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    pub physical_address_bits: u8,
+    pub linear_address_bits: u8,
+    caches: [Option<CacheInfo>; MAX_CACHES],
+}
+
+impl CpuInfo {
+    fn detect() -> Self {
+        let leaf0 = cpuid(0, 0);
+        let max_leaf = leaf0.eax;
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        let leaf1 = cpuid(1, 0);
+        let mut features = decode(leaf1.edx, LEAF1_EDX) | decode(leaf1.ecx, LEAF1_ECX);
+
+        let base_family = (leaf1.eax >> 8) & 0xF;
+        let base_model = (leaf1.eax >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((leaf1.eax >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model | ((leaf1.eax >> 12) & 0xF0)
+        } else {
+            base_model
+        };
+
+        if max_leaf >= 7 {
+            let leaf7 = cpuid(7, 0);
+            features |= decode(leaf7.ebx, LEAF7_EBX) | decode(leaf7.ecx, LEAF7_ECX);
+        }
+        if max_leaf >= 0xD && features.contains(CpuFeatures::XSAVE) {
+            features |= decode(cpuid(0xD, 1).eax, LEAFD_EAX);
+        }
+
+        let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+        if max_extended_leaf >= 0x8000_0001 {
+            features |= decode(cpuid(0x8000_0001, 0).edx, EXTENDED_LEAF1_EDX);
+        }
+        if max_extended_leaf >= 0x8000_0007 {
+            features |= decode(cpuid(0x8000_0007, 0).edx, EXTENDED_LEAF7_EDX);
+        }
+
+        let mut brand = [0u8; 48];
+        if max_extended_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let result = cpuid(leaf, 0);
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .iter()
+                    .enumerate()
+                {
+                    let offset = i * 16 + j * 4;
+                    brand[offset..offset + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let (physical_address_bits, linear_address_bits) = if max_extended_leaf >= 0x8000_0008 {
+            let result = cpuid(0x8000_0008, 0);
+            (result.eax as u8, (result.eax >> 8) as u8)
+        } else {
+            (36, 48)
+        };
+
+        let mut info = CpuInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: leaf1.eax & 0xF,
+            features,
+            max_leaf,
+            max_extended_leaf,
+            physical_address_bits,
+            linear_address_bits,
+            caches: [None; MAX_CACHES],
+        };
+        info.detect_caches();
+        info
+    }
+
+    fn detect_caches(&mut self) {
+        let leaf = if self.vendor() == "GenuineIntel" && self.max_leaf >= 4 {
+            4
+        } else if self.vendor() == "AuthenticAMD" && self.max_extended_leaf >= 0x8000_001D {
+            0x8000_001D
+        } else {
+            return;
+        };
+        for (subleaf, slot) in self.caches.iter_mut().enumerate() {
+            match CacheInfo::from_cpuid(cpuid(leaf, subleaf as u32)) {
+                Some(cache) => *slot = Some(cache),
+                None => break,
+            }
+        }
+    }
+
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("Unknown")
+    }
+
+    pub fn brand(&self) -> &str {
+        let end = self
+            .brand
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..end])
+            .map(str::trim)
+            .unwrap_or("Unknown")
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &CacheInfo> {
+        self.caches.iter().flatten()
+    }
+
+    pub fn has(&self, features: CpuFeatures) -> bool {
+        self.features.contains(features)
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({})", self.brand(), self.vendor())?;
+        writeln!(
+            f,
+            "Family {:#X} Model {:#X} Stepping {}, {} bit physical, {} bit linear",
+            self.family,
+            self.model,
+            self.stepping,
+            self.physical_address_bits,
+            self.linear_address_bits
+        )?;
+        for cache in self.caches() {
+            writeln!(
+                f,
+                "L{} {:?}: {} KiB, {}-way, {} byte lines, shared by {}",
+                cache.level,
+                cache.kind,
+                cache.size() / 1024,
+                cache.ways,
+                cache.line_size,
+                cache.shared_by
+            )?;
+        }
+        write!(f, "Features:")?;
+        for (name, _) in self.features.iter_names() {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+static CPU_INFO: OnceCell<CpuInfo> = OnceCell::uninit();
+
+pub fn init_cpu() -> InitResult {
+    CPU_INFO.init_once(CpuInfo::detect);
+    Ok(())
+}
+
+initcall! {
+    name: "cpu",
+    stage: Cpu,
+    depends_on: ["idt"],
+    optional: false,
+    run: |_| init_cpu(),
+}
+
+pub fn cpu_info() -> &'static CpuInfo {
+    CPU_INFO.get_or_init(CpuInfo::detect)
+}
+
+pub fn has_feature(features: CpuFeatures) -> bool {
+    cpu_info().has(features)
+}
+
+pub fn print_cpu_info() {
+    println!("{}", cpu_info());
+}
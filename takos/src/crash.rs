@@ -0,0 +1,258 @@
+use alloc::string::{String, ToString};
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+use takobl_api::tags::Tag;
+use takobl_api::{BootData, KERNEL_LINK_BASE};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+use crate::console::try_print;
+use crate::efi::{
+    get_variable, set_variable, try_get_time, try_set_variable, ucs2_const, EfiError, Guid,
+    VariableAttributes,
+};
+use crate::initcall::initcall;
+use crate::log::copy_recent_log;
+use crate::paging::is_mapped;
+use crate::{println, KERNEL_BASE};
+
+// There's no driver that can write to the boot medium, so crash reports go
+// into a non-volatile UEFI variable. At the next boot takobl copies it to a
+// file on the ESP and shows it, and takos picks it up, see
+// `init_crash_reports`. Saving happens when the kernel is in an unknown
+// state, so it doesn't allocate or wait for locks.
+
+/// The vendor of takobl's variables.
+const TAKOBL_VENDOR: Guid = Guid::new(
+    0x5F0B_8AD0,
+    0x6C3E,
+    0x4D53,
+    [0x9B, 0x0A, 0x7A, 0x4A, 0x2C, 0x6E, 0x1D, 0x91],
+);
+const CRASH_REPORT_NAME: &str = "TakosCrashReport";
+const CRASH_REPORT_UCS2: [u16; CRASH_REPORT_NAME.len() + 1] = ucs2_const(CRASH_REPORT_NAME);
+/// Firmware limits variables to a few KiB; 8 KiB is common.
+const CRASH_REPORT_SIZE: usize = 7 * 1024;
+const MAX_BACKTRACE: usize = 32;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+static PREVIOUS_CRASH: OnceCell<String> = OnceCell::uninit();
+static KERNEL_SYMBOLS: OnceCell<(&'static [u8], &'static [u8])> = OnceCell::uninit();
+static SAVING: AtomicBool = AtomicBool::new(false);
+static REPORT: Mutex<ReportBuffer> = Mutex::new(ReportBuffer::new());
+
+fn variable_attributes() -> VariableAttributes {
+    VariableAttributes::NON_VOLATILE
+        | VariableAttributes::BOOTSERVICE_ACCESS
+        | VariableAttributes::RUNTIME_ACCESS
+}
+
+/// Takes over the report a crash left, if any, so it's only reported once.
+pub fn init_crash_reports(boot_data: &'static BootData) -> Result<(), EfiError> {
+    if let Some(symbols) = boot_data.tags().find_map(|tag| match tag {
+        Tag::Symbols { symtab, strtab } => Some((symtab, strtab)),
+        _ => None,
+    }) {
+        KERNEL_SYMBOLS.init_once(|| symbols);
+    }
+    let report = match get_variable(CRASH_REPORT_NAME, &TAKOBL_VENDOR) {
+        Ok((report, _)) => String::from_utf8_lossy(&report).into_owned(),
+        Err(EfiError::NOT_FOUND) => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    set_variable(
+        CRASH_REPORT_NAME,
+        &TAKOBL_VENDOR,
+        variable_attributes(),
+        &[],
+    )?;
+    // A panic message can take several lines.
+    let reason = report
+        .split_once("Reason: ")
+        .and_then(|(_, rest)| rest.split_once("\nRegisters:"))
+        .map_or("unknown", |(reason, _)| reason);
+    warn!("The previous boot crashed: {}", reason.replace('\n', " "));
+    info!("`lastcrash` shows the report");
+    PREVIOUS_CRASH.init_once(|| report);
+    Ok(())
+}
+
+initcall! {
+    name: "crash-reports",
+    stage: Firmware,
+    depends_on: ["efi"],
+    optional: true,
+    run: |boot_data| init_crash_reports(boot_data).map_err(|error| error.to_string().into()),
+}
+
+/// The function containing `address`, as a mangled name and offset.
+fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    let (symtab, strtab) = KERNEL_SYMBOLS.get()?;
+    let slide = KERNEL_BASE.get()? - KERNEL_LINK_BASE;
+    let link_address = address.wrapping_sub(slide);
+    symtab.chunks_exact(SYMBOL_SIZE).find_map(|symbol| {
+        let name = u32::from_le_bytes(symbol[0..4].try_into().unwrap()) as usize;
+        let value = u64::from_le_bytes(symbol[8..16].try_into().unwrap());
+        let size = u64::from_le_bytes(symbol[16..24].try_into().unwrap());
+        if symbol[4] & 0xF != STT_FUNC || !(value..value + size).contains(&link_address) {
+            return None;
+        }
+        let name = strtab.get(name..)?;
+        let end = name.iter().position(|&byte| byte == 0)?;
+        let name = core::str::from_utf8(&name[..end]).ok()?;
+        Some((name, link_address - value))
+    })
+}
+
+/// Return addresses found by following the frame pointer chain, which needs
+/// `-C force-frame-pointers=yes`.
+struct Backtrace {
+    rbp: u64,
+    frames: usize,
+}
+
+impl Iterator for Backtrace {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+        if self.frames == MAX_BACKTRACE || rbp == 0 || rbp % 8 != 0 || !is_mapped(rbp, 16) {
+            return None;
+        }
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            return None;
+        }
+        self.frames += 1;
+        // Frames only ever go up the stack.
+        self.rbp = if next > rbp { next } else { 0 };
+        Some(return_address)
+    }
+}
+
+/// The report being built. What doesn't fit is dropped.
+struct ReportBuffer {
+    bytes: [u8; CRASH_REPORT_SIZE],
+    length: usize,
+}
+
+impl ReportBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; CRASH_REPORT_SIZE],
+            length: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+}
+
+impl Write for ReportBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut length = s.len().min(CRASH_REPORT_SIZE - self.length);
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+        self.bytes[self.length..self.length + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+fn write_registers(report: &mut ReportBuffer) -> fmt::Result {
+    let (rsp, rbp): (u64, u64);
+    unsafe { asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp) };
+    writeln!(report, "Registers:")?;
+    writeln!(report, "  RSP {:016X} RBP {:016X}", rsp, rbp)?;
+    writeln!(
+        report,
+        "  CR0 {:016X} CR2 {:016X}",
+        Cr0::read_raw(),
+        Cr2::read_raw()
+    )?;
+    writeln!(
+        report,
+        "  CR3 {:016X} CR4 {:016X}",
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    )?;
+    writeln!(report, "Backtrace:")?;
+    for (i, address) in (Backtrace { rbp, frames: 0 }).enumerate() {
+        match symbolize(address) {
+            Some((name, offset)) => {
+                writeln!(report, "  {:2}: {:016X} {}+{:#X}", i, address, name, offset)?
+            }
+            None => writeln!(report, "  {:2}: {:016X}", i, address)?,
+        }
+    }
+    Ok(())
+}
+
+fn build_report(report: &mut ReportBuffer, reason: &dyn fmt::Display) -> fmt::Result {
+    report.length = 0;
+    writeln!(report, "takos crash report")?;
+    match try_get_time() {
+        Ok(time) => writeln!(report, "Time: {}", time)?,
+        Err(_) => writeln!(report, "Time: unknown")?,
+    }
+    if let Some(base) = KERNEL_BASE.get() {
+        writeln!(report, "Kernel base: {:016X}", base)?;
+    }
+    writeln!(report, "Reason: {}", reason)?;
+    write_registers(report)?;
+    writeln!(report, "Recent log:")?;
+    // Whatever room is left goes to the log, newest lines first.
+    report.length += copy_recent_log(&mut report.bytes[report.length..]);
+    Ok(())
+}
+
+/// Stores a report for the next boot, replacing any earlier one.
+pub fn save_crash_report(reason: &dyn fmt::Display) -> Result<(), EfiError> {
+    // A panic while saving would otherwise recurse.
+    if SAVING.swap(true, Ordering::Relaxed) {
+        return Err(EfiError::DEVICE_ERROR);
+    }
+    let result = match REPORT.try_lock() {
+        Some(mut report) => match build_report(&mut report, reason) {
+            Ok(()) => try_set_variable(
+                &CRASH_REPORT_UCS2,
+                &TAKOBL_VENDOR,
+                variable_attributes(),
+                report.as_bytes(),
+            ),
+            Err(_) => Err(EfiError::OUT_OF_RESOURCES),
+        },
+        None => Err(EfiError::NOT_READY),
+    };
+    SAVING.store(false, Ordering::Relaxed);
+    result
+}
+
+/// For the panic handler.
+pub fn save_panic_report(info: &PanicInfo) {
+    match save_crash_report(info) {
+        Ok(()) => try_print(format_args!("Crash report saved for the next boot\n")),
+        Err(error) => try_print(format_args!("Couldn't save the crash report: {}\n", error)),
+    }
+}
+
+pub fn dump_crash_report() {
+    match save_crash_report(&"requested from the diagnostics console") {
+        Ok(()) => println!("Saved a crash report"),
+        Err(error) => println!("Couldn't save the crash report: {}", error),
+    }
+}
+
+pub fn print_previous_crash() {
+    match PREVIOUS_CRASH.get() {
+        Some(report) => println!("{}", report),
+        None => println!("The previous boot didn't leave a crash report"),
+    }
+}
@@ -0,0 +1,128 @@
+use crate::cpu::print_cpu_info;
+use crate::crash::{dump_crash_report, print_previous_crash};
+use crate::efi::{print_time, reboot};
+use crate::gdb::enter_gdb;
+use crate::hardening::print_wx_audit;
+use crate::interrupts::stats::print_interrupt_stats;
+use crate::keyboard::keycodes::KeyCode;
+use crate::kmod::print_loaded_modules;
+use crate::log::{cycle_log_level, print_dmesg};
+use crate::modules::print_modules;
+use crate::println;
+
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub hotkey: Option<KeyCode>,
+    pub run: fn(),
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        description: "List diagnostic commands",
+        hotkey: Some(KeyCode::F1),
+        run: print_help,
+    },
+    Command {
+        name: "irqstat",
+        description: "Interrupt counts and handler times per vector and CPU",
+        hotkey: Some(KeyCode::F2),
+        run: print_interrupt_stats,
+    },
+    Command {
+        name: "cpuinfo",
+        description: "CPU vendor, brand, caches and features",
+        hotkey: Some(KeyCode::F3),
+        run: print_cpu_info,
+    },
+    Command {
+        name: "wxaudit",
+        description: "List mappings that are both writable and executable",
+        hotkey: Some(KeyCode::F4),
+        run: print_wx_audit,
+    },
+    Command {
+        name: "modules",
+        description: "Modules loaded by takobl",
+        hotkey: None,
+        run: print_modules,
+    },
+    Command {
+        name: "kmods",
+        description: "Loaded kernel modules",
+        hotkey: None,
+        run: print_loaded_modules,
+    },
+    Command {
+        name: "dmesg",
+        description: "Every log message so far, whatever the console level",
+        hotkey: None,
+        run: print_dmesg,
+    },
+    Command {
+        name: "loglevel",
+        description: "Make the console log level more verbose, wrapping around",
+        hotkey: Some(KeyCode::F5),
+        run: cycle_log_level,
+    },
+    Command {
+        name: "crashdump",
+        description: "Save a crash report for the next boot without crashing",
+        hotkey: None,
+        run: dump_crash_report,
+    },
+    Command {
+        name: "lastcrash",
+        description: "The crash report the previous boot left, if any",
+        hotkey: None,
+        run: print_previous_crash,
+    },
+    Command {
+        name: "time",
+        description: "Date and time from the firmware clock",
+        hotkey: None,
+        run: print_time,
+    },
+    Command {
+        name: "gdb",
+        description: "Stop in the GDB stub on the serial port",
+        hotkey: Some(KeyCode::F12),
+        run: enter_gdb,
+    },
+    Command {
+        name: "reboot",
+        description: "Reset the machine through UEFI",
+        hotkey: None,
+        run: reboot,
+    },
+];
+
+fn print_help() {
+    for command in COMMANDS {
+        match command.hotkey {
+            Some(key) => println!("{:10} {:?}\t{}", command.name, key, command.description),
+            None => println!("{:10}\t{}", command.name, command.description),
+        }
+    }
+}
+
+pub fn run_command(name: &str) -> bool {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            (command.run)();
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn run_hotkey(key: KeyCode) -> bool {
+    match COMMANDS.iter().find(|command| command.hotkey == Some(key)) {
+        Some(command) => {
+            (command.run)();
+            true
+        }
+        None => false,
+    }
+}
@@ -11,7 +11,7 @@ use x86_64::{
 
 use crate::{
     allocator::frame_allocator::FRAME_ALLOCATOR,
-    paging::{map_writable_page, PAGE_TABLE},
+    paging::{map_writable_page, reserve_mmio, PAGE_TABLE},
 };
 
 #[derive(Debug)]
@@ -43,16 +43,16 @@ impl FrameBuffer {
     pub fn new(data: &FrameBufferData) -> FrameBuffer {
         use x86_64::structures::paging::{Mapper, Page, PageTableFlags};
         let physical_address = data.buffer_addr;
-        const VIRTUAL_ADDRESS: u64 = 0xFFFF_F000_0000_0000;
-        const VIRTUAL_ADDRESS_DOUBLE: u64 = 0xFFFF_F000_1000_0000;
         let size = 4 * data.height * data.stride;
         let pages = ((size + 4095) / 4096) as u64;
+        let virtual_address = reserve_mmio(pages);
+        let virtual_address_double = reserve_mmio(pages);
         let flags = PageTableFlags::PRESENT
             .union(PageTableFlags::WRITABLE)
             .union(PageTableFlags::WRITE_THROUGH)
             .union(PageTableFlags::NO_EXECUTE);
         for i in 0..pages {
-            let virt = VIRTUAL_ADDRESS + i * 4096;
+            let virt = virtual_address + i * 4096;
             let phys = physical_address as u64 + i * 4096;
             unsafe {
                 let page = Page::<Size4KiB>::from_start_address(VirtAddr::new(virt)).unwrap();
@@ -69,14 +69,14 @@ impl FrameBuffer {
             }
         }
         for i in 0..pages {
-            let virt = VIRTUAL_ADDRESS_DOUBLE + i * 4096;
+            let virt = virtual_address_double + i * 4096;
             let frame = FRAME_ALLOCATOR.lock().allocate_frame().unwrap();
             map_writable_page(virt, frame);
         }
 
         FrameBuffer {
-            base_addr: VIRTUAL_ADDRESS as *mut u8,
-            double_buffer: VIRTUAL_ADDRESS_DOUBLE as *mut u8,
+            base_addr: virtual_address as *mut u8,
+            double_buffer: virtual_address_double as *mut u8,
             width: data.width,
             height: data.height,
             stride: data.stride,
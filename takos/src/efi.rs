@@ -0,0 +1,371 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::ptr;
+use log::{info, warn};
+use spin::Mutex;
+use takobl_api::BootData;
+
+use crate::fpu::with_fpu;
+use crate::initcall::{initcall, InitResult};
+use crate::{hlt_loop, println};
+
+pub mod device_path;
+
+const RUNTIME_SERVICES_SIGNATURE: u64 = u64::from_le_bytes(*b"RUNTSERV");
+const ERROR_BIT: usize = 1 << 63;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+
+impl Guid {
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        Self {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let d = self.data4;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            self.data1, self.data2, self.data3, d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]
+        )
+    }
+}
+
+/// Vendor of the variables the UEFI specification defines.
+pub const GLOBAL_VARIABLE: Guid = Guid::new(
+    0x8BE4_DF61,
+    0x93CA,
+    0x11D2,
+    [0xAA, 0x0D, 0x00, 0xE0, 0x98, 0x03, 0x2B, 0x8C],
+);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pad1: u8,
+    pub nanosecond: u32,
+    /// Minutes from UTC, 2047 if unspecified.
+    pub time_zone: i16,
+    pub daylight: u8,
+    pad2: u8,
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VariableAttributes: u32 {
+        const NON_VOLATILE = 0x01;
+        const BOOTSERVICE_ACCESS = 0x02;
+        const RUNTIME_ACCESS = 0x04;
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetType {
+    Cold = 0,
+    Warm = 1,
+    Shutdown = 2,
+}
+
+/// A UEFI status with the error bit set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EfiError(pub usize);
+
+impl EfiError {
+    pub const INVALID_PARAMETER: Self = Self(ERROR_BIT | 2);
+    pub const UNSUPPORTED: Self = Self(ERROR_BIT | 3);
+    pub const BUFFER_TOO_SMALL: Self = Self(ERROR_BIT | 5);
+    pub const NOT_READY: Self = Self(ERROR_BIT | 6);
+    pub const DEVICE_ERROR: Self = Self(ERROR_BIT | 7);
+    pub const WRITE_PROTECTED: Self = Self(ERROR_BIT | 8);
+    pub const OUT_OF_RESOURCES: Self = Self(ERROR_BIT | 9);
+    pub const NOT_FOUND: Self = Self(ERROR_BIT | 14);
+    pub const SECURITY_VIOLATION: Self = Self(ERROR_BIT | 26);
+}
+
+impl fmt::Display for EfiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::INVALID_PARAMETER => "invalid parameter",
+            Self::UNSUPPORTED => "unsupported",
+            Self::BUFFER_TOO_SMALL => "buffer too small",
+            Self::NOT_READY => "not ready",
+            Self::DEVICE_ERROR => "device error",
+            Self::WRITE_PROTECTED => "write protected",
+            Self::OUT_OF_RESOURCES => "out of resources",
+            Self::NOT_FOUND => "not found",
+            Self::SECURITY_VIOLATION => "security violation",
+            _ => return write!(f, "EFI error {}", self.0 & !ERROR_BIT),
+        };
+        f.write_str(name)
+    }
+}
+
+fn check(status: usize) -> Result<(), EfiError> {
+    if status & ERROR_BIT == 0 {
+        Ok(())
+    } else {
+        Err(EfiError(status))
+    }
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+// Only the services takos calls have real signatures.
+#[repr(C)]
+struct RuntimeServices {
+    header: TableHeader,
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut u8) -> usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: *mut u32,
+        size: *mut usize,
+        data: *mut u8,
+    ) -> usize,
+    get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        size: usize,
+        data: *const u8,
+    ) -> usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: unsafe extern "efiapi" fn(
+        kind: ResetType,
+        status: usize,
+        size: usize,
+        data: *const u8,
+    ) -> !,
+}
+
+// The firmware isn't reentrant, so every call holds this lock.
+static RUNTIME_SERVICES: OnceCell<Mutex<&'static RuntimeServices>> = OnceCell::uninit();
+
+pub fn init_runtime_services(boot_data: &BootData) -> InitResult {
+    if boot_data.runtime_services == 0 {
+        return Err("no UEFI runtime services".into());
+    }
+    let runtime_services = unsafe { &*(boot_data.runtime_services as *const RuntimeServices) };
+    if runtime_services.header.signature != RUNTIME_SERVICES_SIGNATURE {
+        return Err(format!(
+            "UEFI runtime services at {:016X} have a bad signature",
+            boot_data.runtime_services
+        )
+        .into());
+    }
+    let revision = runtime_services.header.revision;
+    info!(
+        "UEFI runtime services {}.{} at {:016X}",
+        revision >> 16,
+        (revision & 0xFFFF) / 10,
+        boot_data.runtime_services
+    );
+    RUNTIME_SERVICES.init_once(|| Mutex::new(runtime_services));
+    Ok(())
+}
+
+initcall! {
+    name: "efi",
+    stage: Firmware,
+    depends_on: [],
+    optional: true,
+    run: init_runtime_services,
+}
+
+/// Firmware follows the MS x64 ABI and may clobber XMM0-5 and MXCSR, so
+/// calls run inside `with_fpu` to keep the current task's state.
+fn with_runtime_services<R>(f: impl FnOnce(&RuntimeServices) -> R) -> Result<R, EfiError> {
+    let runtime_services = RUNTIME_SERVICES.get().ok_or(EfiError::UNSUPPORTED)?;
+    Ok(with_fpu(|| f(&runtime_services.lock())))
+}
+
+/// Like `with_runtime_services`, but fails instead of waiting for a lock
+/// that a panic inside a firmware call would never release.
+fn try_with_runtime_services<R>(f: impl FnOnce(&RuntimeServices) -> R) -> Result<R, EfiError> {
+    let runtime_services = RUNTIME_SERVICES.get().ok_or(EfiError::UNSUPPORTED)?;
+    with_fpu(|| {
+        let runtime_services = runtime_services.try_lock().ok_or(EfiError::NOT_READY)?;
+        Ok(f(&runtime_services))
+    })
+}
+
+fn ucs2_name(name: &str) -> Vec<u16> {
+    name.encode_utf16().chain(Some(0)).collect()
+}
+
+/// `ucs2_name` for ASCII names known at compile time, so using them doesn't
+/// allocate. `N` counts the terminating 0.
+pub const fn ucs2_const<const N: usize>(name: &str) -> [u16; N] {
+    let bytes = name.as_bytes();
+    assert!(bytes.len() + 1 == N, "N must be the name's length plus one");
+    let mut result = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii(), "Only ASCII names are supported");
+        result[i] = bytes[i] as u16;
+        i += 1;
+    }
+    result
+}
+
+pub fn get_time() -> Result<Time, EfiError> {
+    let mut time = Time::default();
+    let status = with_runtime_services(|rs| unsafe { (rs.get_time)(&mut time, ptr::null_mut()) })?;
+    check(status).map(|_| time)
+}
+
+/// `get_time` for the panic path.
+pub fn try_get_time() -> Result<Time, EfiError> {
+    let mut time = Time::default();
+    let status =
+        try_with_runtime_services(|rs| unsafe { (rs.get_time)(&mut time, ptr::null_mut()) })?;
+    check(status).map(|_| time)
+}
+
+initcall! {
+    name: "firmware-time",
+    stage: Firmware,
+    depends_on: ["efi"],
+    optional: true,
+    run: |_| {
+        let time = get_time().map_err(|error| error.to_string())?;
+        info!("Firmware time: {}", time);
+        Ok(())
+    },
+}
+
+pub fn get_variable(name: &str, vendor: &Guid) -> Result<(Vec<u8>, VariableAttributes), EfiError> {
+    let name = ucs2_name(name);
+    let mut data = vec![0u8; 64];
+    loop {
+        let mut attributes = 0;
+        let mut size = data.len();
+        let status = with_runtime_services(|rs| unsafe {
+            (rs.get_variable)(
+                name.as_ptr(),
+                vendor,
+                &mut attributes,
+                &mut size,
+                data.as_mut_ptr(),
+            )
+        })?;
+        match check(status) {
+            Ok(()) => {
+                data.truncate(size);
+                return Ok((data, VariableAttributes::from_bits_truncate(attributes)));
+            }
+            // `size` now holds what the variable needs.
+            Err(EfiError::BUFFER_TOO_SMALL) => data.resize(size, 0),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Writing empty `data` deletes the variable.
+pub fn set_variable(
+    name: &str,
+    vendor: &Guid,
+    attributes: VariableAttributes,
+    data: &[u8],
+) -> Result<(), EfiError> {
+    let name = ucs2_name(name);
+    let status = with_runtime_services(|rs| unsafe {
+        (rs.set_variable)(
+            name.as_ptr(),
+            vendor,
+            attributes.bits(),
+            data.len(),
+            data.as_ptr(),
+        )
+    })?;
+    check(status)
+}
+
+/// `set_variable` for the panic path, with a name from `ucs2_const`.
+pub fn try_set_variable(
+    name: &[u16],
+    vendor: &Guid,
+    attributes: VariableAttributes,
+    data: &[u8],
+) -> Result<(), EfiError> {
+    if name.last() != Some(&0) {
+        return Err(EfiError::INVALID_PARAMETER);
+    }
+    let status = try_with_runtime_services(|rs| unsafe {
+        (rs.set_variable)(
+            name.as_ptr(),
+            vendor,
+            attributes.bits(),
+            data.len(),
+            data.as_ptr(),
+        )
+    })?;
+    check(status)
+}
+
+/// Halts instead if there are no runtime services.
+pub fn reset_system(kind: ResetType) -> ! {
+    let _ = with_runtime_services(|rs| unsafe { (rs.reset_system)(kind, 0, 0, ptr::null()) });
+    warn!("Couldn't reset through UEFI, halting");
+    hlt_loop();
+}
+
+pub fn print_time() {
+    match get_time() {
+        Ok(time) => println!("{}", time),
+        Err(error) => println!("Couldn't read the time: {}", error),
+    }
+}
+
+pub fn reboot() {
+    reset_system(ResetType::Cold);
+}
@@ -0,0 +1,415 @@
+use alloc::{string::String, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use log::{info, warn};
+use takobl_api::tags::Tag;
+use takobl_api::BootData;
+
+use super::Guid;
+use crate::initcall::{initcall, InitResult};
+
+const HARDWARE: u8 = 0x01;
+const ACPI: u8 = 0x02;
+const MESSAGING: u8 = 0x03;
+const MEDIA: u8 = 0x04;
+const END: u8 = 0x7F;
+
+const HARDWARE_PCI: u8 = 0x01;
+const ACPI_ACPI: u8 = 0x01;
+const MESSAGING_SCSI: u8 = 0x02;
+const MESSAGING_USB: u8 = 0x05;
+const MESSAGING_SATA: u8 = 0x12;
+const MESSAGING_NVME: u8 = 0x17;
+const MEDIA_HARD_DRIVE: u8 = 0x01;
+const MEDIA_CD_ROM: u8 = 0x02;
+const MEDIA_FILE_PATH: u8 = 0x04;
+
+/// EISA IDs of PCI and PCI Express root bridges, PNP0A03 and PNP0A08.
+const PNP0A03: u32 = 0x0A03_41D0;
+const PNP0A08: u32 = 0x0A08_41D0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionSignature {
+    None,
+    Mbr(u32),
+    Gpt(Guid),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevicePathNode {
+    PciRoot {
+        uid: u32,
+    },
+    Acpi {
+        hid: u32,
+        uid: u32,
+    },
+    Pci {
+        device: u8,
+        function: u8,
+    },
+    Usb {
+        parent_port: u8,
+        interface: u8,
+    },
+    Scsi {
+        target: u16,
+        lun: u16,
+    },
+    Sata {
+        hba_port: u16,
+        port_multiplier_port: u16,
+        lun: u16,
+    },
+    Nvme {
+        namespace: u32,
+        eui64: u64,
+    },
+    HardDrive {
+        /// 1-based; 0 means the whole disk.
+        number: u32,
+        /// In logical blocks.
+        start: u64,
+        size: u64,
+        signature: PartitionSignature,
+    },
+    CdRom {
+        boot_entry: u32,
+        start: u64,
+        size: u64,
+    },
+    FilePath(String),
+    Unknown {
+        kind: u8,
+        subtype: u8,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevicePathError {
+    Truncated,
+    BadNodeLength(usize),
+    MissingEnd,
+}
+
+/// A parsed UEFI device path, without its end node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DevicePath {
+    pub nodes: Vec<DevicePathNode>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn guid_at(data: &[u8], offset: usize) -> Option<Guid> {
+    Some(Guid::new(
+        u32_at(data, offset)?,
+        u16_at(data, offset + 4)?,
+        u16_at(data, offset + 6)?,
+        data.get(offset + 8..offset + 16)?.try_into().ok()?,
+    ))
+}
+
+fn parse_node(kind: u8, subtype: u8, data: &[u8]) -> Option<DevicePathNode> {
+    let node = match (kind, subtype) {
+        (ACPI, ACPI_ACPI) => {
+            let hid = u32_at(data, 0)?;
+            let uid = u32_at(data, 4)?;
+            if hid == PNP0A03 || hid == PNP0A08 {
+                DevicePathNode::PciRoot { uid }
+            } else {
+                DevicePathNode::Acpi { hid, uid }
+            }
+        }
+        (HARDWARE, HARDWARE_PCI) => DevicePathNode::Pci {
+            function: *data.first()?,
+            device: *data.get(1)?,
+        },
+        (MESSAGING, MESSAGING_USB) => DevicePathNode::Usb {
+            parent_port: *data.first()?,
+            interface: *data.get(1)?,
+        },
+        (MESSAGING, MESSAGING_SCSI) => DevicePathNode::Scsi {
+            target: u16_at(data, 0)?,
+            lun: u16_at(data, 2)?,
+        },
+        (MESSAGING, MESSAGING_SATA) => DevicePathNode::Sata {
+            hba_port: u16_at(data, 0)?,
+            port_multiplier_port: u16_at(data, 2)?,
+            lun: u16_at(data, 4)?,
+        },
+        (MESSAGING, MESSAGING_NVME) => DevicePathNode::Nvme {
+            namespace: u32_at(data, 0)?,
+            eui64: u64_at(data, 4)?,
+        },
+        (MEDIA, MEDIA_HARD_DRIVE) => DevicePathNode::HardDrive {
+            number: u32_at(data, 0)?,
+            start: u64_at(data, 4)?,
+            size: u64_at(data, 12)?,
+            signature: match *data.get(37)? {
+                1 => PartitionSignature::Mbr(u32_at(data, 20)?),
+                2 => PartitionSignature::Gpt(guid_at(data, 20)?),
+                _ => PartitionSignature::None,
+            },
+        },
+        (MEDIA, MEDIA_CD_ROM) => DevicePathNode::CdRom {
+            boot_entry: u32_at(data, 0)?,
+            start: u64_at(data, 4)?,
+            size: u64_at(data, 12)?,
+        },
+        (MEDIA, MEDIA_FILE_PATH) => {
+            let units = (0..data.len() / 2)
+                .map(|i| u16_at(data, i * 2).unwrap())
+                .take_while(|&unit| unit != 0);
+            DevicePathNode::FilePath(
+                char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            )
+        }
+        _ => return None,
+    };
+    Some(node)
+}
+
+impl DevicePath {
+    /// Parses nodes up to the first end-of-path node.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DevicePathError> {
+        let mut nodes = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = bytes
+                .get(offset..offset + 4)
+                .ok_or(DevicePathError::MissingEnd)?;
+            let (kind, subtype) = (header[0], header[1]);
+            let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+            if length < 4 {
+                return Err(DevicePathError::BadNodeLength(length));
+            }
+            if kind == END {
+                // End-of-instance nodes separate alternatives; the first
+                // instance is the one we booted from.
+                return Ok(Self { nodes });
+            }
+            let data = bytes
+                .get(offset + 4..offset + length)
+                .ok_or(DevicePathError::Truncated)?;
+            nodes.push(parse_node(kind, subtype, data).unwrap_or_else(|| {
+                DevicePathNode::Unknown {
+                    kind,
+                    subtype,
+                    data: data.to_vec(),
+                }
+            }));
+            offset += length;
+        }
+    }
+
+    /// The partition, or whole disk, part of the path.
+    pub fn partition(&self) -> Option<&DevicePathNode> {
+        self.nodes.iter().find(|node| {
+            matches!(
+                node,
+                DevicePathNode::HardDrive { .. } | DevicePathNode::CdRom { .. }
+            )
+        })
+    }
+
+    /// The PCI device the disk hangs off, as (device, function) pairs from
+    /// the root bridge down.
+    pub fn pci_path(&self) -> Vec<(u8, u8)> {
+        self.nodes
+            .iter()
+            .filter_map(|node| match node {
+                DevicePathNode::Pci { device, function } => Some((*device, *function)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// File path nodes joined into one UEFI path, such as
+    /// `\EFI\BOOT\BOOTX64.EFI`.
+    pub fn file_path(&self) -> Option<String> {
+        let mut result: Option<String> = None;
+        for node in &self.nodes {
+            if let DevicePathNode::FilePath(part) = node {
+                let path = result.get_or_insert_with(String::new);
+                if !path.is_empty() && !path.ends_with('\\') && !part.starts_with('\\') {
+                    path.push('\\');
+                }
+                path.push_str(part);
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Display for DevicePathNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DevicePathNode::PciRoot { uid } => write!(f, "PciRoot(0x{:X})", uid),
+            DevicePathNode::Acpi { hid, uid } => write!(f, "Acpi(0x{:08X},0x{:X})", hid, uid),
+            DevicePathNode::Pci { device, function } => {
+                write!(f, "Pci(0x{:X},0x{:X})", device, function)
+            }
+            DevicePathNode::Usb {
+                parent_port,
+                interface,
+            } => write!(f, "USB(0x{:X},0x{:X})", parent_port, interface),
+            DevicePathNode::Scsi { target, lun } => write!(f, "Scsi(0x{:X},0x{:X})", target, lun),
+            DevicePathNode::Sata {
+                hba_port,
+                port_multiplier_port,
+                lun,
+            } => write!(
+                f,
+                "Sata(0x{:X},0x{:X},0x{:X})",
+                hba_port, port_multiplier_port, lun
+            ),
+            DevicePathNode::Nvme { namespace, eui64 } => {
+                write!(f, "NVMe(0x{:X},{:016X})", namespace, eui64)
+            }
+            DevicePathNode::HardDrive {
+                number,
+                start,
+                size,
+                signature,
+            } => {
+                write!(f, "HD({},", number)?;
+                match signature {
+                    PartitionSignature::None => write!(f, "None,0,")?,
+                    PartitionSignature::Mbr(id) => write!(f, "MBR,0x{:08X},", id)?,
+                    PartitionSignature::Gpt(guid) => write!(f, "GPT,{},", guid)?,
+                }
+                write!(f, "0x{:X},0x{:X})", start, size)
+            }
+            DevicePathNode::CdRom {
+                boot_entry,
+                start,
+                size,
+            } => write!(f, "CDROM(0x{:X},0x{:X},0x{:X})", boot_entry, start, size),
+            DevicePathNode::FilePath(path) => f.write_str(path),
+            DevicePathNode::Unknown {
+                kind,
+                subtype,
+                data,
+            } => write!(f, "Path({},{},{} bytes)", kind, subtype, data.len()),
+        }
+    }
+}
+
+impl fmt::Display for DevicePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            write!(f, "{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+static BOOT_DEVICE: OnceCell<DevicePath> = OnceCell::uninit();
+
+pub fn init_boot_device(boot_data: &BootData) -> InitResult {
+    let Some(bytes) = boot_data.tags().find_map(|tag| match tag {
+        Tag::DevicePath(bytes) => Some(bytes),
+        _ => None,
+    }) else {
+        warn!("takobl passed no boot device path");
+        return Ok(());
+    };
+    match DevicePath::parse(bytes) {
+        Ok(path) => {
+            info!("Boot device: {}", path);
+            BOOT_DEVICE.init_once(|| path);
+        }
+        Err(error) => warn!("Bad boot device path: {:?}", error),
+    }
+    Ok(())
+}
+
+initcall! {
+    name: "boot-device",
+    stage: Firmware,
+    depends_on: [],
+    optional: false,
+    run: init_boot_device,
+}
+
+/// Where takobl was loaded from: the disk, partition and loader file.
+pub fn boot_device() -> Option<&'static DevicePath> {
+    BOOT_DEVICE.get()
+}
+
+#[test_case]
+fn test_parse_device_path() {
+    use crate::{print, println};
+    print!("test_parse_device_path... ");
+
+    #[rustfmt::skip]
+    let bytes: &[u8] = &[
+        // PciRoot(0x0)
+        0x02, 0x01, 0x0C, 0x00, 0xD0, 0x41, 0x03, 0x0A, 0x00, 0x00, 0x00, 0x00,
+        // Pci(0x1D,0x0)
+        0x01, 0x01, 0x06, 0x00, 0x00, 0x1D,
+        // USB(0x2,0x0)
+        0x03, 0x05, 0x06, 0x00, 0x02, 0x00,
+        // HD(1,GPT,...,0x800,0x100000)
+        0x04, 0x01, 0x2A, 0x00,
+        0x01, 0x00, 0x00, 0x00,
+        0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66,
+        0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+        0x02, 0x02,
+        // \EFI\BOOT.EFI
+        0x04, 0x04, 0x20, 0x00,
+        b'\\', 0, b'E', 0, b'F', 0, b'I', 0, b'\\', 0, b'B', 0, b'O', 0, b'O', 0, b'T', 0,
+        b'.', 0, b'E', 0, b'F', 0, b'I', 0, 0, 0,
+        // End
+        0x7F, 0xFF, 0x04, 0x00,
+    ];
+    let path = DevicePath::parse(bytes).unwrap();
+    assert_eq!(path.nodes.len(), 5);
+    assert_eq!(path.nodes[0], DevicePathNode::PciRoot { uid: 0 });
+    assert_eq!(path.pci_path(), [(0x1D, 0)]);
+    assert_eq!(
+        path.partition(),
+        Some(&DevicePathNode::HardDrive {
+            number: 1,
+            start: 0x800,
+            size: 0x100000,
+            signature: PartitionSignature::Gpt(Guid::new(
+                0x0011_2233,
+                0x4455,
+                0x6677,
+                [0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]
+            )),
+        })
+    );
+    assert_eq!(path.file_path().as_deref(), Some("\\EFI\\BOOT.EFI"));
+    assert_eq!(
+        DevicePath::parse(&bytes[..bytes.len() - 4]),
+        Err(DevicePathError::MissingEnd)
+    );
+
+    println!("[ok]");
+}
@@ -1,3 +1,33 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use self::{archive::ArchiveFilesystem, fat::Fat32Filesystem, ramdisk::RamDisk};
+
+pub mod archive;
 pub mod blockdevice;
 pub mod fat;
 pub mod ramdisk;
+
+#[derive(Debug, Clone)]
+pub enum FilesystemError {
+    NonAbsolutePath,
+    PathDoesntExist(String),
+    NotADirectory(String),
+    NotAFile(String),
+}
+
+pub trait Filesystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FilesystemError>;
+    fn dir_iter(
+        &self,
+        path: &str,
+    ) -> Result<Box<dyn Iterator<Item = String> + '_>, FilesystemError>;
+}
+
+/// Picks the filesystem for an initrd: a cpio or tar archive, else FAT32.
+pub fn open_ramdisk(data: &'static mut [u8]) -> Box<dyn Filesystem + Send + Sync> {
+    if archive::is_archive(data) {
+        Box::new(ArchiveFilesystem::new(data))
+    } else {
+        Box::new(Fat32Filesystem::new(RamDisk::new(data)))
+    }
+}
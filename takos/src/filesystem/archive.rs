@@ -0,0 +1,398 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{info, warn};
+
+use super::{Filesystem, FilesystemError};
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const USTAR_MAGIC: &[u8] = b"ustar";
+const USTAR_MAGIC_OFFSET: usize = 257;
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    Directory,
+    File(&'static [u8]),
+    Symlink(&'static str),
+}
+
+/// Files of a newc cpio or ustar archive, indexed in place.
+pub struct ArchiveFilesystem {
+    /// Keyed by absolute path without a trailing slash; the root is "".
+    nodes: BTreeMap<String, Node>,
+}
+
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(CPIO_MAGIC)
+        || data.starts_with(CPIO_CRC_MAGIC)
+        || data.get(USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + USTAR_MAGIC.len()) == Some(USTAR_MAGIC)
+}
+
+fn normalize(path: &str) -> String {
+    let mut result = String::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                let parent = result.rfind('/').unwrap_or(0);
+                result.truncate(parent);
+            }
+            part => {
+                result.push('/');
+                result.push_str(part);
+            }
+        }
+    }
+    result
+}
+
+fn parent(path: &str) -> &str {
+    &path[..path.rfind('/').unwrap_or(0)]
+}
+
+fn parse_hex(field: &[u8]) -> Option<u32> {
+    u32::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let field = core::str::from_utf8(field).ok()?;
+    let field = field.trim_matches(|c: char| c == '\0' || c == ' ');
+    if field.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(field, 8).ok()
+}
+
+fn c_string(field: &'static [u8]) -> &'static str {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).unwrap_or("")
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+impl ArchiveFilesystem {
+    pub fn new(data: &'static [u8]) -> Self {
+        let mut filesystem = Self {
+            nodes: BTreeMap::new(),
+        };
+        filesystem.nodes.insert(String::new(), Node::Directory);
+        if data.starts_with(CPIO_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+            filesystem.parse_cpio(data);
+        } else {
+            filesystem.parse_tar(data);
+        }
+        info!("Archive filesystem: {} entries", filesystem.nodes.len() - 1);
+        filesystem
+    }
+
+    fn insert(&mut self, path: &str, node: Node) {
+        let path = normalize(path);
+        if path.is_empty() {
+            return;
+        }
+        // Archives don't have to list the directories leading to a file.
+        let mut directory = parent(&path);
+        while !directory.is_empty() && !self.nodes.contains_key(directory) {
+            self.nodes.insert(directory.to_string(), Node::Directory);
+            directory = parent(directory);
+        }
+        self.nodes.insert(path, node);
+    }
+
+    fn parse_cpio(&mut self, data: &'static [u8]) {
+        // newc stores a hard-linked file's data only with its last link.
+        let mut pending_links: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        let mut offset = 0;
+        while let Some(header) = data.get(offset..offset + CPIO_HEADER_SIZE) {
+            if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+                warn!("cpio: bad header magic at {:X}", offset);
+                return;
+            }
+            let field = |index: usize| parse_hex(&header[6 + index * 8..14 + index * 8]);
+            let (Some(inode), Some(mode), Some(links), Some(size), Some(name_size)) =
+                (field(0), field(1), field(4), field(6), field(11))
+            else {
+                warn!("cpio: bad header at {:X}", offset);
+                return;
+            };
+            let name_start = offset + CPIO_HEADER_SIZE;
+            let data_start = align(name_start + name_size as usize, 4);
+            let data_end = data_start + size as usize;
+            let (Some(name), Some(contents)) = (
+                data.get(name_start..name_start + name_size as usize),
+                data.get(data_start..data_end),
+            ) else {
+                warn!("cpio: truncated entry at {:X}", offset);
+                return;
+            };
+            let name = c_string(name);
+            if name == CPIO_TRAILER {
+                return;
+            }
+            offset = align(data_end, 4);
+
+            match mode & MODE_TYPE_MASK {
+                MODE_DIRECTORY => self.insert(name, Node::Directory),
+                MODE_REGULAR if links > 1 && size == 0 => {
+                    pending_links
+                        .entry(inode)
+                        .or_default()
+                        .push(name.to_string());
+                    self.insert(name, Node::File(&[]));
+                }
+                MODE_REGULAR => {
+                    for link in pending_links.remove(&inode).unwrap_or_default() {
+                        self.insert(&link, Node::File(contents));
+                    }
+                    self.insert(name, Node::File(contents));
+                }
+                MODE_SYMLINK => match core::str::from_utf8(contents) {
+                    Ok(target) => self.insert(name, Node::Symlink(target)),
+                    Err(_) => warn!("cpio: bad symlink {}", name),
+                },
+                // Device nodes, FIFOs and sockets mean nothing here.
+                _ => {}
+            }
+        }
+    }
+
+    fn parse_tar(&mut self, data: &'static [u8]) {
+        let mut long_name: Option<&'static str> = None;
+        let mut offset = 0;
+        while let Some(header) = data.get(offset..offset + TAR_BLOCK_SIZE) {
+            // The archive ends with two zero blocks.
+            if header.iter().all(|&b| b == 0) {
+                return;
+            }
+            let Some(size) = parse_octal(&header[124..136]) else {
+                warn!("tar: bad size at {:X}", offset);
+                return;
+            };
+            let data_start = offset + TAR_BLOCK_SIZE;
+            let Some(contents) = data.get(data_start..data_start + size as usize) else {
+                warn!("tar: truncated entry at {:X}", offset);
+                return;
+            };
+            offset = data_start + align(size as usize, TAR_BLOCK_SIZE);
+
+            let kind = header[156];
+            let name = match long_name.take() {
+                Some(name) => String::from(name),
+                None => {
+                    let prefix = c_string(&header[345..500]);
+                    let name = c_string(&header[0..100]);
+                    if prefix.is_empty() {
+                        name.to_string()
+                    } else {
+                        alloc::format!("{}/{}", prefix, name)
+                    }
+                }
+            };
+            let link = c_string(&header[157..257]);
+
+            match kind {
+                b'0' | b'\0' | b'7' => self.insert(&name, Node::File(contents)),
+                b'1' => match self.lookup(link) {
+                    Ok(node) => self.insert(&name, node),
+                    Err(_) => warn!("tar: hard link {} to missing {}", name, link),
+                },
+                b'2' => self.insert(&name, Node::Symlink(link)),
+                b'5' => self.insert(&name, Node::Directory),
+                // GNU long name for the next entry.
+                b'L' => long_name = Some(c_string(contents)),
+                // pax extended header; only the path matters.
+                b'x' => long_name = pax_path(contents),
+                _ => {}
+            }
+        }
+    }
+
+    fn lookup(&self, path: &str) -> Result<Node, FilesystemError> {
+        let mut path = normalize(path);
+        for _ in 0..MAX_SYMLINK_DEPTH {
+            match self.nodes.get(&path) {
+                Some(Node::Symlink(target)) => {
+                    path = if target.starts_with('/') {
+                        normalize(target)
+                    } else {
+                        normalize(&alloc::format!("{}/{}", parent(&path), target))
+                    };
+                }
+                Some(node) => return Ok(*node),
+                None => return Err(FilesystemError::PathDoesntExist(path)),
+            }
+        }
+        Err(FilesystemError::PathDoesntExist(path))
+    }
+}
+
+fn pax_path(records: &'static [u8]) -> Option<&'static str> {
+    // Records are "<length> <key>=<value>\n".
+    let mut records = core::str::from_utf8(records).ok()?;
+    while let Some((length, rest)) = records.split_once(' ') {
+        let length: usize = length.parse().ok()?;
+        let record = records.get(..length)?;
+        let (key, value) = rest
+            .get(..length.checked_sub(length_digits(length) + 1)?)?
+            .split_once('=')?;
+        if key == "path" {
+            return Some(value.trim_end_matches('\n'));
+        }
+        records = &records[record.len()..];
+    }
+    None
+}
+
+fn length_digits(mut value: usize) -> usize {
+    let mut digits = 1;
+    while value >= 10 {
+        value /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+impl Filesystem for ArchiveFilesystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FilesystemError> {
+        if !path.starts_with('/') {
+            return Err(FilesystemError::NonAbsolutePath);
+        }
+        match self.lookup(path)? {
+            Node::File(data) => Ok(data.to_vec()),
+            _ => Err(FilesystemError::NotAFile(path.to_string())),
+        }
+    }
+
+    fn dir_iter(
+        &self,
+        path: &str,
+    ) -> Result<Box<dyn Iterator<Item = String> + '_>, FilesystemError> {
+        if !path.starts_with('/') {
+            return Err(FilesystemError::NonAbsolutePath);
+        }
+        let directory = normalize(path);
+        match self.lookup(&directory)? {
+            Node::Directory => {}
+            _ => return Err(FilesystemError::NotADirectory(path.to_string())),
+        }
+        let prefix = alloc::format!("{}/", directory);
+        let skip = prefix.len();
+        let children = self
+            .nodes
+            .range(prefix.clone()..)
+            .take_while(move |(name, _)| name.starts_with(&prefix))
+            .map(move |(name, _)| &name[skip..])
+            .filter(|name| !name.contains('/'))
+            .map(ToString::to_string);
+        Ok(Box::new(children))
+    }
+}
+
+#[cfg(test)]
+fn cpio_entry(archive: &mut Vec<u8>, inode: u32, mode: u32, links: u32, name: &str, data: &[u8]) {
+    let fields = [inode, mode, 0, 0, links, 0, data.len() as u32, 0, 0, 0, 0];
+    archive.extend_from_slice(CPIO_MAGIC);
+    for field in fields.into_iter().chain([name.len() as u32 + 1, 0]) {
+        archive.extend_from_slice(alloc::format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(align(archive.len(), 4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(align(archive.len(), 4), 0);
+}
+
+#[cfg(test)]
+fn tar_entry(archive: &mut Vec<u8>, kind: u8, name: &str, link: &str, data: &[u8]) {
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..135].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 6].copy_from_slice(b"ustar\0");
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(align(archive.len(), TAR_BLOCK_SIZE), 0);
+}
+
+#[test_case]
+fn test_parse_cpio() {
+    use crate::{print, println};
+    print!("test_parse_cpio... ");
+
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, 1, MODE_DIRECTORY | 0o755, 2, "etc", b"");
+    cpio_entry(
+        &mut archive,
+        2,
+        MODE_REGULAR | 0o644,
+        1,
+        "etc/hostname",
+        b"tako\n",
+    );
+    cpio_entry(
+        &mut archive,
+        3,
+        MODE_SYMLINK | 0o777,
+        1,
+        "hostname",
+        b"etc/hostname",
+    );
+    // A hard link pair; only the last one carries the data.
+    cpio_entry(&mut archive, 4, MODE_REGULAR | 0o644, 2, "a", b"");
+    cpio_entry(&mut archive, 4, MODE_REGULAR | 0o644, 2, "b", b"abc");
+    cpio_entry(&mut archive, 0, 0, 1, CPIO_TRAILER, b"");
+    let filesystem = ArchiveFilesystem::new(Box::leak(archive.into_boxed_slice()));
+
+    assert_eq!(filesystem.read_file("/hostname").unwrap(), b"tako\n");
+    assert_eq!(filesystem.read_file("/a").unwrap(), b"abc");
+    let root: Vec<String> = filesystem.dir_iter("/").unwrap().collect();
+    assert_eq!(root, ["a", "b", "etc", "hostname"]);
+    assert!(filesystem.read_file("/etc").is_err());
+
+    println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_tar() {
+    use crate::{print, println};
+    print!("test_parse_tar... ");
+
+    let mut archive = Vec::new();
+    tar_entry(&mut archive, b'0', "bin/sh", "", b"#!");
+    tar_entry(&mut archive, b'1', "bin/bash", "bin/sh", b"");
+    // A pax record whose length doesn't even cover its own digits.
+    tar_entry(&mut archive, b'x', "pax", "", b"1 x");
+    tar_entry(&mut archive, b'0', "short", "", b"1");
+    let record = "path=some/long/name.txt\n";
+    let record = alloc::format!("{} {}", record.len() + 3, record);
+    tar_entry(&mut archive, b'x', "pax", "", record.as_bytes());
+    tar_entry(&mut archive, b'0', "truncated", "", b"2");
+    archive.resize(archive.len() + 2 * TAR_BLOCK_SIZE, 0);
+    assert!(is_archive(&archive));
+    let filesystem = ArchiveFilesystem::new(Box::leak(archive.into_boxed_slice()));
+
+    assert_eq!(filesystem.read_file("/bin/bash").unwrap(), b"#!");
+    assert_eq!(filesystem.read_file("/short").unwrap(), b"1");
+    assert_eq!(filesystem.read_file("/some/long/name.txt").unwrap(), b"2");
+    assert!(filesystem.read_file("/truncated").is_err());
+
+    println!("[ok]");
+}
@@ -4,11 +4,11 @@ use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
-use log::info;
+use log::trace;
 
 use bitflags::bitflags;
 
-use super::blockdevice::RandomAccessDevice;
+use super::{blockdevice::RandomAccessDevice, Filesystem, FilesystemError};
 
 const SECTOR_SIZE: usize = 512;
 pub struct Fat32Filesystem {
@@ -64,13 +64,6 @@ pub struct DirectoryEntryData {
     cluster: u32,
 }
 
-#[derive(Debug, Clone)]
-pub enum FatError {
-    NonAbsolutePath,
-    PathDoesntExist(String),
-    NotADirectory(String),
-}
-
 impl Fat32Filesystem {
     pub fn new(device: impl RandomAccessDevice + Send + Sync + 'static) -> Self {
         let bytes_per_sector = device.read(0x0B, 2);
@@ -105,7 +98,7 @@ impl Fat32Filesystem {
         let fat_entry_addr = self.fat_offset * SECTOR_SIZE + cluster as usize * 4;
         let fat_entry: [u8; 4] = self.device.read(fat_entry_addr, 4).try_into().unwrap();
         let fat_entry = u32::from_le_bytes(fat_entry);
-        info!("Fat entry: 0x{:X}", fat_entry);
+        trace!("Fat entry: 0x{:X}", fat_entry);
         if fat_entry == 0 || fat_entry >= 0x0FFF_FFFF {
             None
         } else {
@@ -148,17 +141,21 @@ impl Fat32Filesystem {
         }
     }
 
-    fn find_file(&self, current_dir: u32, filename: &str) -> Result<DirectoryEntryData, FatError> {
+    fn find_file(
+        &self,
+        current_dir: u32,
+        filename: &str,
+    ) -> Result<DirectoryEntryData, FilesystemError> {
         let filename_uppercase = filename.to_uppercase();
         for entry in self.directory_entry_iter(current_dir) {
             if entry.filename.to_uppercase() == filename_uppercase {
                 return Ok(entry);
             }
         }
-        Err(FatError::PathDoesntExist(filename.to_string()))
+        Err(FilesystemError::PathDoesntExist(filename.to_string()))
     }
 
-    fn find_file_full(&self, path: &str) -> Result<DirectoryEntryData, FatError> {
+    fn find_file_full(&self, path: &str) -> Result<DirectoryEntryData, FilesystemError> {
         let mut current_entry = DirectoryEntryData {
             filename: String::from("/"),
             attributes: FatFileAttributes::DIRECTORY,
@@ -166,7 +163,7 @@ impl Fat32Filesystem {
             cluster: self.root_dir_first_cluster,
         };
         if !path.starts_with('/') {
-            return Err(FatError::NonAbsolutePath);
+            return Err(FilesystemError::NonAbsolutePath);
         }
         for (i, part) in path[1..].split('/').enumerate() {
             if part.is_empty() {
@@ -176,7 +173,7 @@ impl Fat32Filesystem {
                 .attributes
                 .contains(FatFileAttributes::DIRECTORY)
             {
-                return Err(FatError::NotADirectory(
+                return Err(FilesystemError::NotADirectory(
                     part.split('/')
                         .take(i + 1)
                         .fold(String::new(), |x, y| x + "/" + y),
@@ -187,20 +184,27 @@ impl Fat32Filesystem {
         }
         Ok(current_entry)
     }
+}
 
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FatError> {
+impl Filesystem for Fat32Filesystem {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, FilesystemError> {
         let entry = self.find_file_full(path)?;
         let mut data = self.read_chain_full(entry.cluster);
         data.truncate(entry.file_size as usize);
         Ok(data)
     }
 
-    pub fn dir_iter(&self, path: &str) -> Result<DirectoryIterator, FatError> {
+    fn dir_iter(
+        &self,
+        path: &str,
+    ) -> Result<Box<dyn Iterator<Item = String> + '_>, FilesystemError> {
         let entry = self.find_file_full(path)?;
         if !entry.attributes.contains(FatFileAttributes::DIRECTORY) {
-            Err(FatError::NotADirectory(path.to_string()))
+            Err(FilesystemError::NotADirectory(path.to_string()))
         } else {
-            Ok(DirectoryIterator(self.directory_entry_iter(entry.cluster)))
+            Ok(Box::new(DirectoryIterator(
+                self.directory_entry_iter(entry.cluster),
+            )))
         }
     }
 }
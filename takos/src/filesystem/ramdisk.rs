@@ -1,4 +1,4 @@
-use log::info;
+use log::trace;
 
 use super::blockdevice::RandomAccessDevice;
 
@@ -12,7 +12,7 @@ impl RamDisk {
 
 impl RandomAccessDevice for RamDisk {
     fn read(&self, addr: usize, size: usize) -> &[u8] {
-        info!("RamDisk read: 0x{:X}, 0x{:X}", addr, size);
+        trace!("RamDisk read: 0x{:X}, 0x{:X}", addr, size);
         assert!(addr + size <= self.0.len());
         &self.0[addr..(addr + size)]
     }
@@ -0,0 +1,170 @@
+use core::arch::asm;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use crate::cpu::{cpuid, has_feature, CpuFeatures};
+use crate::initcall::{initcall, InitResult};
+
+const FXSAVE_AREA_SIZE: usize = 512;
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+#[derive(Debug, Clone, Copy)]
+enum SaveMode {
+    FxSave,
+    XSave { mask: u64, size: usize },
+}
+
+impl SaveMode {
+    fn size(&self) -> usize {
+        match *self {
+            SaveMode::FxSave => FXSAVE_AREA_SIZE,
+            SaveMode::XSave { size, .. } => size,
+        }
+    }
+}
+
+static SAVE_MODE: OnceCell<SaveMode> = OnceCell::uninit();
+
+// The kernel itself is built soft-float, so interrupt handlers never touch
+// the FPU and only task switches and `with_fpu` have to preserve its state.
+pub fn init_fpu() -> InitResult {
+    let mut cr0 = Cr0::read();
+    cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+    cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+    let mut cr4 = Cr4::read();
+    cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+    let use_xsave = has_feature(CpuFeatures::XSAVE);
+    if use_xsave {
+        cr4.insert(Cr4Flags::OSXSAVE);
+    }
+    unsafe {
+        Cr0::write(cr0);
+        Cr4::write(cr4);
+    }
+
+    let mode = if use_xsave {
+        let supported = XCr0Flags::from_bits_truncate(cpuid(0xD, 0).eax as u64);
+        let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        let mut flags = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_feature(CpuFeatures::AVX) && supported.contains(XCr0Flags::AVX) {
+            flags |= XCr0Flags::AVX;
+            if has_feature(CpuFeatures::AVX512F) && supported.contains(avx512) {
+                flags |= avx512;
+            }
+        }
+        unsafe { XCr0::write(flags) };
+        // EBX reports the area size for the features enabled in XCR0.
+        SaveMode::XSave {
+            mask: flags.bits(),
+            size: cpuid(0xD, 0).ebx as usize,
+        }
+    } else {
+        SaveMode::FxSave
+    };
+    SAVE_MODE.init_once(|| mode);
+
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+    Ok(())
+}
+
+initcall! {
+    name: "fpu",
+    stage: Cpu,
+    depends_on: ["cpu", "idt"],
+    optional: false,
+    run: |_| init_fpu(),
+}
+
+fn save_mode() -> SaveMode {
+    *SAVE_MODE.get().expect("FPU not initialized")
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct SaveAreaChunk([u8; 64]);
+
+/// Buffer for FXSAVE/XSAVE, which need 16 and 64 byte alignment respectively.
+pub struct FpuState {
+    area: Vec<SaveAreaChunk>,
+}
+
+impl FpuState {
+    /// Returns the state `fninit` leaves behind, with all SIMD exceptions masked.
+    pub fn new() -> Self {
+        let size = save_mode().size();
+        let mut area = vec![SaveAreaChunk([0; 64]); size.div_ceil(64)];
+        area[0].0[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        area[0].0[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        Self { area }
+    }
+
+    pub fn save(&mut self) {
+        let area = self.area.as_mut_ptr();
+        match save_mode() {
+            SaveMode::FxSave => unsafe {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            },
+            SaveMode::XSave { mask, .. } => unsafe {
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack)
+                );
+            },
+        }
+    }
+
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        match save_mode() {
+            SaveMode::FxSave => unsafe {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+            },
+            SaveMode::XSave { mask, .. } => unsafe {
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack, readonly)
+                );
+            },
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+lazy_static! {
+    static ref KERNEL_FPU_STATE: Mutex<FpuState> = Mutex::new(FpuState::new());
+}
+
+/// Runs `f` with the current task's FPU state saved, so that `f` may use
+/// SSE/AVX through inline assembly or `#[target_feature]` functions.
+/// Interrupts stay disabled for the duration of `f`.
+pub fn with_fpu<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| match KERNEL_FPU_STATE.try_lock() {
+        Some(mut state) => {
+            state.save();
+            let result = f();
+            state.restore();
+            result
+        }
+        // Nested call, the outer one already saved the task's state.
+        None => f(),
+    })
+}
@@ -0,0 +1,474 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+use crate::cmdline::cmdline;
+use crate::hardening::with_user_access;
+use crate::initcall::initcall;
+use crate::interrupts::InterruptFrame;
+use crate::paging::is_mapped;
+use crate::serial::{console_port, ComPort, SerialPort};
+
+// A GDB Remote Serial Protocol stub. With `gdb=com1` or `gdb=com2`, the
+// kernel stops in the stub on `int3`, on panics, on F12 and when GDB sends
+// Ctrl-C; connect with `target remote /dev/ttyS0` (or QEMU's `-serial`).
+// Use a port other than the console's if there is one: kernel output on
+// the same port confuses GDB.
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xCC;
+
+/// In GDB's amd64 numbering: RAX..R15, RIP, EFLAGS, then CS, SS, DS, ES, FS
+/// and GS as 32 bit registers.
+const REGISTER_COUNT: usize = 24;
+const FIRST_SEGMENT_REGISTER: usize = 18;
+
+struct Stub {
+    serial: SerialPort,
+    /// Address and the byte `int3` replaced.
+    breakpoints: Vec<(u64, u8)>,
+    stepping: bool,
+}
+
+static STUB: Mutex<Option<Stub>> = Mutex::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn gdb_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn gdb_port() -> Option<ComPort> {
+    cmdline().gdb.or_else(console_port)
+}
+
+fn enable(port: ComPort) -> Result<(), &'static str> {
+    let serial = SerialPort::open(port).ok_or("no UART for GDB")?;
+    *STUB.lock() = Some(Stub {
+        serial,
+        breakpoints: Vec::new(),
+        stepping: false,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Only does something with `gdb=` on the command line.
+pub fn init_gdb() -> Result<(), &'static str> {
+    match cmdline().gdb {
+        Some(port) => enable(port),
+        None => Ok(()),
+    }
+}
+
+initcall! {
+    name: "gdb",
+    stage: Interrupts,
+    depends_on: ["serial-irq"],
+    optional: true,
+    run: |_| init_gdb().map_err(Into::into),
+}
+
+/// Whether the serial IRQ handler should hand `frame` to the stub because
+/// GDB sent Ctrl-C.
+pub fn is_gdb_interrupt(port: ComPort, byte: u8) -> bool {
+    byte == 0x03
+        && gdb_enabled()
+        && STUB
+            .try_lock()
+            .is_some_and(|stub| stub.as_ref().is_some_and(|s| s.serial.com_port() == port))
+}
+
+/// Stops in the stub, right after the `int3`.
+pub fn breakpoint() {
+    unsafe { asm!("int3") };
+}
+
+/// For the diagnostics hotkey; enables the stub on the console port if the
+/// command line didn't.
+pub fn enter_gdb() {
+    if !gdb_enabled() {
+        let Some(port) = gdb_port() else {
+            crate::println!("No serial port for GDB");
+            return;
+        };
+        if let Err(error) = enable(port) {
+            crate::println!("{}", error);
+            return;
+        }
+    }
+    crate::println!("Waiting for GDB");
+    breakpoint();
+}
+
+/// Called from the `int3` handler. Returns false if the stub isn't enabled.
+pub fn handle_breakpoint(frame: &mut InterruptFrame) -> bool {
+    if !gdb_enabled() {
+        return false;
+    }
+    let mut stub = STUB.lock();
+    let stub = stub.as_mut().unwrap();
+    // `rip` is past the `int3`; for our own breakpoints GDB wants its address.
+    let address = frame.rip - 1;
+    if stub.breakpoints.iter().any(|&(a, _)| a == address) {
+        frame.rip = address;
+    }
+    stub.run(frame, SIGTRAP);
+    true
+}
+
+/// Called from the debug exception handler after a single step.
+pub fn handle_debug(frame: &mut InterruptFrame) -> bool {
+    if !gdb_enabled() {
+        return false;
+    }
+    let mut stub = STUB.lock();
+    let stub = stub.as_mut().unwrap();
+    if !stub.stepping {
+        return false;
+    }
+    stub.stepping = false;
+    frame.rflags &= !TRAP_FLAG;
+    stub.run(frame, SIGTRAP);
+    true
+}
+
+pub fn handle_interrupt(frame: &mut InterruptFrame) {
+    if let Some(stub) = STUB.lock().as_mut() {
+        stub.run(frame, SIGINT);
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let pairs = text.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn encode_hex(reply: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(reply, "{:02x}", byte);
+    }
+}
+
+/// `addr,length`
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let (address, length) = text.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn register(frame: &mut InterruptFrame, index: usize) -> Option<&mut u64> {
+    let r = &mut frame.registers;
+    Some(match index {
+        0 => &mut r.rax,
+        1 => &mut r.rbx,
+        2 => &mut r.rcx,
+        3 => &mut r.rdx,
+        4 => &mut r.rsi,
+        5 => &mut r.rdi,
+        6 => &mut r.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut r.r8,
+        9 => &mut r.r9,
+        10 => &mut r.r10,
+        11 => &mut r.r11,
+        12 => &mut r.r12,
+        13 => &mut r.r13,
+        14 => &mut r.r14,
+        15 => &mut r.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    })
+}
+
+fn register_size(index: usize) -> usize {
+    if index < 17 {
+        8
+    } else {
+        4
+    }
+}
+
+fn read_register(frame: &mut InterruptFrame, index: usize, reply: &mut String) {
+    let value = register(frame, index).map_or(0, |value| *value);
+    encode_hex(reply, &value.to_le_bytes()[..register_size(index)]);
+}
+
+/// Only general purpose registers, RIP and EFLAGS can be written.
+fn write_register(frame: &mut InterruptFrame, index: usize, bytes: &[u8]) -> bool {
+    if index >= FIRST_SEGMENT_REGISTER || bytes.len() != register_size(index) {
+        return index < REGISTER_COUNT;
+    }
+    let mut value = [0u8; 8];
+    value[..bytes.len()].copy_from_slice(bytes);
+    match register(frame, index) {
+        Some(register) => {
+            *register = u64::from_le_bytes(value);
+            true
+        }
+        None => false,
+    }
+}
+
+fn read_memory(address: u64, length: u64) -> Option<Vec<u8>> {
+    if !is_mapped(address, length) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(length as usize);
+    with_user_access(|| {
+        for i in 0..length {
+            bytes.push(unsafe { core::ptr::read_volatile((address + i) as *const u8) });
+        }
+    });
+    Some(bytes)
+}
+
+/// Also writes to read-only pages, for breakpoints in kernel text.
+fn write_memory(address: u64, bytes: &[u8]) -> bool {
+    if !is_mapped(address, bytes.len() as u64) {
+        return false;
+    }
+    let write_protect = Cr0::read().contains(Cr0Flags::WRITE_PROTECT);
+    unsafe {
+        Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT));
+        with_user_access(|| {
+            for (i, &byte) in bytes.iter().enumerate() {
+                core::ptr::write_volatile((address + i as u64) as *mut u8, byte);
+            }
+        });
+        if write_protect {
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        }
+    }
+    true
+}
+
+enum Resume {
+    Stay,
+    Continue,
+    Step,
+    Detach,
+}
+
+impl Stub {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.serial.read_byte() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// `$data#checksum`, acknowledged with `+`.
+    fn read_packet(&mut self) -> String {
+        loop {
+            while self.read_byte() != b'$' {}
+            let mut packet = String::new();
+            let mut checksum = 0u8;
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => {
+                        checksum = checksum.wrapping_add(byte);
+                        packet.push(byte as char);
+                    }
+                }
+            }
+            let high = hex_digit(self.read_byte());
+            let low = hex_digit(self.read_byte());
+            if let (Some(high), Some(low)) = (high, low) {
+                if high << 4 | low == checksum {
+                    self.serial.write_byte(b'+');
+                    return packet;
+                }
+            }
+            self.serial.write_byte(b'-');
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        loop {
+            self.serial.write_byte(b'$');
+            for byte in data.bytes() {
+                self.serial.write_byte(byte);
+            }
+            self.serial.write_byte(b'#');
+            for digit in [checksum >> 4, checksum & 0xF] {
+                self.serial.write_byte(b"0123456789abcdef"[digit as usize]);
+            }
+            if self.read_byte() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self.breakpoints.iter().any(|&(a, _)| a == address) {
+            return true;
+        }
+        let Some(original) = read_memory(address, 1) else {
+            return false;
+        };
+        if !write_memory(address, &[INT3]) {
+            return false;
+        }
+        self.breakpoints.push((address, original[0]));
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> bool {
+        match self.breakpoints.iter().position(|&(a, _)| a == address) {
+            Some(index) => {
+                let (address, original) = self.breakpoints.remove(index);
+                write_memory(address, &[original])
+            }
+            None => false,
+        }
+    }
+
+    fn handle_packet(&mut self, packet: &str, frame: &mut InterruptFrame, signal: u8) -> Resume {
+        let mut reply = String::new();
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let ok = |success: bool| if success { "OK" } else { "E01" };
+        let resume_at = |frame: &mut InterruptFrame| {
+            if let Some(address) = parse_hex(arguments) {
+                frame.rip = address;
+            }
+        };
+        match command {
+            "?" => {
+                let _ = write!(reply, "S{:02x}", signal);
+            }
+            "g" => {
+                for index in 0..REGISTER_COUNT {
+                    read_register(frame, index, &mut reply);
+                }
+            }
+            "G" => {
+                let success = decode_hex(arguments).is_some_and(|bytes| {
+                    let mut offset = 0;
+                    for index in 0..REGISTER_COUNT {
+                        let size = register_size(index);
+                        let Some(value) = bytes.get(offset..offset + size) else {
+                            break;
+                        };
+                        write_register(frame, index, value);
+                        offset += size;
+                    }
+                    true
+                });
+                reply.push_str(ok(success));
+            }
+            "p" => match parse_hex(arguments) {
+                Some(index) if (index as usize) < REGISTER_COUNT => {
+                    read_register(frame, index as usize, &mut reply)
+                }
+                _ => reply.push_str("E00"),
+            },
+            "P" => {
+                let success = arguments.split_once('=').is_some_and(|(index, value)| {
+                    match (parse_hex(index), decode_hex(value)) {
+                        (Some(index), Some(value)) => write_register(frame, index as usize, &value),
+                        _ => false,
+                    }
+                });
+                reply.push_str(ok(success));
+            }
+            "m" => match parse_range(arguments).and_then(|(a, l)| read_memory(a, l)) {
+                Some(bytes) => encode_hex(&mut reply, &bytes),
+                None => reply.push_str("E14"),
+            },
+            "M" => {
+                let success = arguments.split_once(':').is_some_and(|(range, data)| {
+                    match (parse_range(range), decode_hex(data)) {
+                        (Some((address, length)), Some(data)) if data.len() as u64 == length => {
+                            write_memory(address, &data)
+                        }
+                        _ => false,
+                    }
+                });
+                reply.push_str(ok(success));
+            }
+            "Z" | "z" => {
+                // Only software breakpoints: `Z0,addr,kind`.
+                let mut fields = arguments.split(',');
+                match (fields.next(), fields.next().and_then(parse_hex)) {
+                    (Some("0"), Some(address)) if command == "Z" => {
+                        reply.push_str(ok(self.insert_breakpoint(address)))
+                    }
+                    (Some("0"), Some(address)) => {
+                        reply.push_str(ok(self.remove_breakpoint(address)))
+                    }
+                    _ => {}
+                }
+            }
+            "c" => {
+                resume_at(frame);
+                return Resume::Continue;
+            }
+            "s" => {
+                resume_at(frame);
+                return Resume::Step;
+            }
+            "D" | "k" => {
+                self.send_packet("OK");
+                return Resume::Detach;
+            }
+            "H" => reply.push_str("OK"),
+            "q" if arguments.starts_with("Supported") => reply.push_str("PacketSize=1000"),
+            "q" if arguments == "Attached" => reply.push('1'),
+            // Anything else is unsupported, which an empty reply says.
+            _ => {}
+        }
+        self.send_packet(&reply);
+        Resume::Stay
+    }
+
+    /// Talks to GDB until it resumes the kernel.
+    fn run(&mut self, frame: &mut InterruptFrame, signal: u8) {
+        let mut reply = String::new();
+        let _ = write!(reply, "S{:02x}", signal);
+        self.send_packet(&reply);
+        loop {
+            let packet = self.read_packet();
+            match self.handle_packet(&packet, frame, signal) {
+                Resume::Stay => {}
+                Resume::Continue => return,
+                Resume::Step => {
+                    self.stepping = true;
+                    frame.rflags |= TRAP_FLAG;
+                    return;
+                }
+                Resume::Detach => {
+                    while let Some(&(address, _)) = self.breakpoints.first() {
+                        self.remove_breakpoint(address);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
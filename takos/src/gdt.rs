@@ -7,19 +7,29 @@ use x86_64::structures::{
 };
 use x86_64::VirtAddr;
 
+use crate::initcall::{initcall, InitResult};
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+macro_rules! ist_stack {
+    ($size:expr) => {{
+        const STACK_SIZE: usize = $size;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_end = stack_start + STACK_SIZE;
+        stack_end
+    }};
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!(4096 * 5);
         tss
     };
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
@@ -44,11 +54,20 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-pub fn init_gdt() {
+pub fn init_gdt() -> InitResult {
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         SS::set_reg(GDT.1.stack_selector);
         load_tss(GDT.1.tss_selector);
     }
+    Ok(())
+}
+
+initcall! {
+    name: "gdt",
+    stage: Cpu,
+    depends_on: [],
+    optional: false,
+    run: |_| init_gdt(),
 }
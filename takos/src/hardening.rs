@@ -0,0 +1,168 @@
+use core::arch::asm;
+
+use alloc::vec::Vec;
+use log::{info, warn};
+use takobl_api::{PHYSICAL_MEMORY_OFFSET, RUNTIME_AREA_END, RUNTIME_AREA_START};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+use crate::cpu::{has_feature, CpuFeatures};
+use crate::initcall::{initcall, InitResult};
+use crate::paging::PAGE_TABLE;
+use crate::println;
+
+pub fn init_hardening() -> InitResult {
+    let mut cr4 = Cr4Flags::empty();
+    if has_feature(CpuFeatures::SMEP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if has_feature(CpuFeatures::SMAP) {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if has_feature(CpuFeatures::UMIP) {
+        cr4 |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+    unsafe {
+        if has_feature(CpuFeatures::NX) {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|flags| flags.insert(cr4));
+    }
+    Ok(())
+}
+
+initcall! {
+    name: "hardening",
+    stage: Cpu,
+    depends_on: ["cpu", "idt"],
+    optional: false,
+    run: |_| init_hardening(),
+}
+
+/// Runs `f` with SMAP lifted, for code that has to touch user memory.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WxViolation {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl WxViolation {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+fn canonical(address: u64) -> u64 {
+    ((address << 16) as i64 >> 16) as u64
+}
+
+fn entry_size(level: u8) -> u64 {
+    0x1000 << (9 * (level as u64 - 1))
+}
+
+// Permissions are the intersection of all levels: a page is writable only if
+// every entry on the way is, and executable only if none has NO_EXECUTE.
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    writable: bool,
+    executable: bool,
+    violations: &mut Vec<WxViolation>,
+) {
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = canonical(base + index as u64 * entry_size(level));
+        let writable = writable && flags.contains(PageTableFlags::WRITABLE);
+        let executable = executable && !flags.contains(PageTableFlags::NO_EXECUTE);
+        if !writable || !executable {
+            continue;
+        }
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let size = entry_size(level);
+            match violations.last_mut() {
+                Some(last) if last.end() == start => last.size += size,
+                _ => violations.push(WxViolation { start, size }),
+            }
+        } else {
+            let next = (entry.addr().as_u64() + PHYSICAL_MEMORY_OFFSET) as *const PageTable;
+            walk_table(
+                unsafe { &*next },
+                level - 1,
+                start,
+                writable,
+                executable,
+                violations,
+            );
+        }
+    }
+}
+
+/// Returns every virtual range that is mapped both writable and executable.
+pub fn audit_wx() -> Vec<WxViolation> {
+    let mut violations = Vec::new();
+    let mut page_table = PAGE_TABLE.lock();
+    walk_table(
+        page_table.level_4_table(),
+        4,
+        0,
+        true,
+        true,
+        &mut violations,
+    );
+    // Without a memory attributes table takobl can't tell the firmware's
+    // runtime code from its data and has to map both W+X; that's for the
+    // firmware to fix, not us.
+    violations
+        .retain(|violation| !(RUNTIME_AREA_START..RUNTIME_AREA_END).contains(&violation.start));
+    violations
+}
+
+pub fn report_wx() -> InitResult {
+    let violations = audit_wx();
+    if violations.is_empty() {
+        info!("W^X audit: no writable and executable mappings");
+    }
+    for violation in violations {
+        warn!(
+            "W^X audit: {:016X}-{:016X} is writable and executable",
+            violation.start,
+            violation.end()
+        );
+    }
+    Ok(())
+}
+
+initcall! {
+    name: "wx-audit",
+    stage: Firmware,
+    depends_on: ["unmap-loader"],
+    optional: false,
+    run: |_| report_wx(),
+}
+
+pub fn print_wx_audit() {
+    let violations = audit_wx();
+    println!("{} W+X mapping(s)", violations.len());
+    for violation in violations {
+        println!("{:016X}-{:016X}", violation.start, violation.end());
+    }
+}
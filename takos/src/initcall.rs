@@ -0,0 +1,412 @@
+use alloc::borrow::Cow;
+use core::{fmt, slice};
+use log::{info, warn, LevelFilter};
+use takobl_api::BootData;
+
+use crate::tsc::{cycles_to_micros, rdtsc};
+
+const MAX_INITCALLS: usize = 64;
+
+/// Stages run in this order; dependencies can only point to the same or an
+/// earlier stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// GDT and IDT come first. Steps touching control registers or MSRs
+    /// depend on `idt`, so a fault there is reported instead of triple
+    /// faulting.
+    Cpu,
+    /// Steps that may fail depend on `heap` even if they don't allocate.
+    Memory,
+    Console,
+    Firmware,
+    Interrupts,
+    Devices,
+}
+
+const STAGES: [Stage; 6] = [
+    Stage::Cpu,
+    Stage::Memory,
+    Stage::Console,
+    Stage::Firmware,
+    Stage::Interrupts,
+    Stage::Devices,
+];
+
+/// Steps run before the heap exists, so failing mustn't need to allocate.
+pub type InitResult = Result<(), Cow<'static, str>>;
+
+/// A step of booting, registered with `initcall!` next to the code it sets
+/// up.
+pub struct Initcall {
+    pub name: &'static str,
+    pub stage: Stage,
+    pub depends_on: &'static [&'static str],
+    /// Boot carries on without it if it fails. Whatever depends on it is
+    /// skipped.
+    pub optional: bool,
+    pub run: fn(&'static BootData) -> InitResult,
+}
+
+/// Registers an init step for `init`.
+macro_rules! initcall {
+    (
+        name: $name:literal,
+        stage: $stage:ident,
+        depends_on: [$($dependency:literal),* $(,)?],
+        optional: $optional:literal,
+        run: $run:expr $(,)?
+    ) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".initcalls"]
+            static INITCALL: $crate::initcall::Initcall = $crate::initcall::Initcall {
+                name: $name,
+                stage: $crate::initcall::Stage::$stage,
+                depends_on: &[$($dependency),*],
+                optional: $optional,
+                run: $run,
+            };
+        };
+    };
+}
+pub(crate) use initcall;
+
+// Defined in link.x around the `.initcalls` section.
+extern "C" {
+    static __start_initcalls: u8;
+    static __stop_initcalls: u8;
+}
+
+/// Everything registered with `initcall!`, in link order.
+pub fn initcalls() -> &'static [Initcall] {
+    unsafe {
+        let start = &__start_initcalls as *const u8 as *const Initcall;
+        let end = &__stop_initcalls as *const u8 as *const Initcall;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+enum Status {
+    Pending,
+    Done,
+    Failed(Cow<'static, str>),
+    Skipped(&'static str),
+}
+
+/// Why boot can't go on. Like `InitResult`, it doesn't allocate.
+#[derive(Debug)]
+pub enum InitError {
+    TooMany,
+    UnknownDependency {
+        name: &'static str,
+        dependency: &'static str,
+    },
+    /// Depends on a later stage or on itself.
+    Stuck(&'static str),
+    Failed {
+        name: &'static str,
+        stage: Stage,
+        message: Cow<'static, str>,
+    },
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::TooMany => write!(f, "Too many init steps"),
+            InitError::UnknownDependency { name, dependency } => write!(
+                f,
+                "Init step `{}` depends on unknown step `{}`",
+                name, dependency
+            ),
+            InitError::Stuck(name) => write!(
+                f,
+                "Init step `{}` depends on a later stage or on itself",
+                name
+            ),
+            InitError::Failed {
+                name,
+                stage,
+                message,
+            } => write!(f, "Init step `{}` ({:?}) failed: {}", name, stage, message),
+        }
+    }
+}
+
+struct Duration(u64);
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match cycles_to_micros(self.0) {
+            Some(micros) => write!(f, "{}.{:03} ms", micros / 1000, micros % 1000),
+            None => write!(f, "{} cycles", self.0),
+        }
+    }
+}
+
+struct Runner<'a> {
+    initcalls: &'a [Initcall],
+    status: [Status; MAX_INITCALLS],
+    cycles: [u64; MAX_INITCALLS],
+    /// Indices into `initcalls` in the order they were handled.
+    order: [usize; MAX_INITCALLS],
+    handled: usize,
+    logged: usize,
+}
+
+impl<'a> Runner<'a> {
+    fn new(initcalls: &'a [Initcall]) -> Self {
+        Self {
+            initcalls,
+            status: core::array::from_fn(|_| Status::Pending),
+            cycles: [0; MAX_INITCALLS],
+            order: [0; MAX_INITCALLS],
+            handled: 0,
+            logged: 0,
+        }
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.initcalls
+            .iter()
+            .position(|initcall| initcall.name == name)
+    }
+
+    /// Checked by `run_all` before anything runs.
+    fn check_dependencies(&self) -> Result<(), InitError> {
+        for initcall in self.initcalls {
+            if let Some(dependency) = initcall
+                .depends_on
+                .iter()
+                .find(|name| self.index(name).is_none())
+            {
+                return Err(InitError::UnknownDependency {
+                    name: initcall.name,
+                    dependency,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn status_of(&self, name: &str) -> Option<&Status> {
+        self.index(name).map(|i| &self.status[i])
+    }
+
+    /// The next step of `stage` whose dependencies have all been handled.
+    fn next_ready(&self, stage: Stage) -> Option<usize> {
+        (0..self.initcalls.len()).find(|&i| {
+            let initcall = &self.initcalls[i];
+            initcall.stage == stage
+                && matches!(self.status[i], Status::Pending)
+                && initcall
+                    .depends_on
+                    .iter()
+                    .all(|name| !matches!(self.status_of(name), None | Some(Status::Pending)))
+        })
+    }
+
+    /// Fails if a step boot can't do without fails.
+    fn run(
+        &mut self,
+        i: usize,
+        call: &mut impl FnMut(&Initcall) -> InitResult,
+    ) -> Result<(), InitError> {
+        let initcall = &self.initcalls[i];
+        let failed_dependency = initcall.depends_on.iter().find(|name| {
+            matches!(
+                self.status_of(name),
+                Some(Status::Failed(_) | Status::Skipped(_))
+            )
+        });
+        self.status[i] = match failed_dependency {
+            Some(name) => Status::Skipped(name),
+            None => {
+                let start = rdtsc();
+                let result = call(initcall);
+                self.cycles[i] = rdtsc() - start;
+                match result {
+                    Ok(()) => Status::Done,
+                    Err(message) if initcall.optional => Status::Failed(message),
+                    Err(message) => {
+                        return Err(InitError::Failed {
+                            name: initcall.name,
+                            stage: initcall.stage,
+                            message,
+                        })
+                    }
+                }
+            }
+        };
+        self.order[self.handled] = i;
+        self.handled += 1;
+        Ok(())
+    }
+
+    /// Hands every step to `call`, stage by stage.
+    fn run_all(&mut self, mut call: impl FnMut(&Initcall) -> InitResult) -> Result<(), InitError> {
+        if self.initcalls.len() > MAX_INITCALLS {
+            return Err(InitError::TooMany);
+        }
+        self.check_dependencies()?;
+        for stage in STAGES {
+            while let Some(i) = self.next_ready(stage) {
+                self.run(i, &mut call)?;
+                self.log_handled();
+            }
+            if let Some(stuck) = (0..self.initcalls.len()).find(|&i| {
+                self.initcalls[i].stage == stage && matches!(self.status[i], Status::Pending)
+            }) {
+                return Err(InitError::Stuck(self.initcalls[stuck].name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Steps that ran before the logger are logged once it's up.
+    fn log_handled(&mut self) {
+        if log::max_level() == LevelFilter::Off {
+            return;
+        }
+        while self.logged < self.handled {
+            let i = self.order[self.logged];
+            let name = self.initcalls[i].name;
+            match &self.status[i] {
+                Status::Done => info!("init: {} in {}", name, Duration(self.cycles[i])),
+                Status::Failed(message) => warn!("init: {} failed: {}", name, message),
+                Status::Skipped(dependency) => {
+                    warn!("init: {} skipped, needs {}", name, dependency)
+                }
+                Status::Pending => unreachable!(),
+            }
+            self.logged += 1;
+        }
+    }
+
+    fn print_summary(&self, total_cycles: u64) {
+        let count = |f: fn(&Status) -> bool| self.status.iter().filter(|s| f(s)).count();
+        info!(
+            "Boot finished in {}: {} steps done, {} failed, {} skipped",
+            Duration(total_cycles),
+            count(|s| matches!(s, Status::Done)),
+            count(|s| matches!(s, Status::Failed(_))),
+            count(|s| matches!(s, Status::Skipped(_)))
+        );
+        if let Some(slowest) = (0..self.initcalls.len()).max_by_key(|&i| self.cycles[i]) {
+            info!(
+                "Slowest step: {} ({})",
+                self.initcalls[slowest].name,
+                Duration(self.cycles[slowest])
+            );
+        }
+        for i in 0..self.initcalls.len() {
+            match &self.status[i] {
+                Status::Failed(message) => {
+                    warn!("  {} failed: {}", self.initcalls[i].name, message)
+                }
+                Status::Skipped(dependency) => {
+                    warn!("  {} skipped, needs {}", self.initcalls[i].name, dependency)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Runs `initcalls` stage by stage. Within a stage they run in the given
+/// order, except that a step waits for its dependencies. The order of
+/// `initcalls()` is up to the linker, so a step has to name everything it
+/// needs from its own stage.
+pub fn run_initcalls(initcalls: &[Initcall], boot_data: &'static BootData) {
+    let start = rdtsc();
+    let mut runner = Runner::new(initcalls);
+    if let Err(error) = runner.run_all(|initcall| (initcall.run)(boot_data)) {
+        panic!("{}", error);
+    }
+    runner.print_summary(rdtsc() - start);
+}
+
+#[test_case]
+fn test_run_initcalls() {
+    use crate::{print, println};
+    use alloc::vec::Vec;
+    print!("test_run_initcalls... ");
+
+    let step = |name, stage, depends_on, optional| Initcall {
+        name,
+        stage,
+        depends_on,
+        optional,
+        run: |_| Ok(()),
+    };
+    let call = |order: &mut Vec<&'static str>, initcall: &Initcall| {
+        order.push(initcall.name);
+        match initcall.name {
+            "broken" | "fatal" => Err("failed on purpose".into()),
+            _ => Ok(()),
+        }
+    };
+
+    let initcalls = [
+        step("late", Stage::Cpu, &["early"], false),
+        step("early", Stage::Cpu, &[], false),
+        step("broken", Stage::Cpu, &[], true),
+        step("needs-broken", Stage::Memory, &["broken"], false),
+        step("needs-skipped", Stage::Devices, &["needs-broken"], false),
+    ];
+    let mut order = Vec::new();
+    let mut runner = Runner::new(&initcalls);
+    assert!(runner
+        .run_all(|initcall| call(&mut order, initcall))
+        .is_ok());
+    assert_eq!(order, ["early", "late", "broken"]);
+    assert!(matches!(runner.status[3], Status::Skipped("broken")));
+    assert!(matches!(runner.status[4], Status::Skipped("needs-broken")));
+
+    let initcalls = [
+        step("fatal", Stage::Cpu, &[], false),
+        step("after", Stage::Memory, &[], false),
+    ];
+    let mut order = Vec::new();
+    let mut runner = Runner::new(&initcalls);
+    assert!(matches!(
+        runner.run_all(|initcall| call(&mut order, initcall)),
+        Err(InitError::Failed { name: "fatal", .. })
+    ));
+    assert_eq!(order, ["fatal"]);
+
+    let initcalls = [
+        step("first", Stage::Cpu, &[], false),
+        step("typo", Stage::Cpu, &["frist"], false),
+    ];
+    let mut order = Vec::new();
+    let mut runner = Runner::new(&initcalls);
+    assert!(matches!(
+        runner.run_all(|initcall| call(&mut order, initcall)),
+        Err(InitError::UnknownDependency {
+            name: "typo",
+            dependency: "frist"
+        })
+    ));
+    assert!(order.is_empty());
+
+    for initcalls in [
+        [
+            step("stuck", Stage::Cpu, &["later"], false),
+            step("later", Stage::Devices, &[], false),
+        ],
+        [
+            step("stuck", Stage::Cpu, &["stuck"], false),
+            step("other", Stage::Cpu, &[], false),
+        ],
+    ] {
+        let mut order = Vec::new();
+        let mut runner = Runner::new(&initcalls);
+        assert!(matches!(
+            runner.run_all(|initcall| call(&mut order, initcall)),
+            Err(InitError::Stuck("stuck"))
+        ));
+    }
+
+    println!("[ok]");
+}
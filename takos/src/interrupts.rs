@@ -1,101 +1,212 @@
+use alloc::format;
+use core::arch::global_asm;
+
 use lazy_static::lazy_static;
 
-use x86_64::instructions::port::Port;
-use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
+use x86_64::VirtAddr;
+
+use crate::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use crate::initcall::{initcall, InitResult};
+use crate::pic::MASTER_PIC_OFFSET;
+use crate::random::add_interrupt_entropy;
+
+mod exceptions;
+pub mod irq;
+pub mod stats;
 
-use crate::console::WRITER;
-use crate::gdt::DOUBLE_FAULT_IST_INDEX;
-use crate::{
-    pic::{MASTER_PIC_OFFSET, PICS},
-    println,
+pub use exceptions::ExceptionVector;
+pub use irq::{
+    allocate_irq, allocate_irq_block, free_irq, irq_count, register_irq, unregister_irq, Irq,
+    IrqHandler, IrqReturn,
 };
 
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Everything pushed on the stack by the CPU and by the entry stubs below.
 #[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = MASTER_PIC_OFFSET,
-    Keyboard,
+#[repr(C)]
+pub struct InterruptFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
+// Every stub is padded to 16 bytes, so the stub for vector N lives at
+// `interrupt_stubs + 16 * N`. Vectors without a CPU-provided error code push
+// a zero instead, so that all of them share the same `InterruptFrame` layout.
+global_asm!(
+    r#"
+.section .text
+interrupt_entry_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call {dispatch}
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
+.p2align 4
+interrupt_stubs:
+.set vector, 0
+.rept 256
+    .p2align 4
+    .if (vector == 8) || ((vector >= 10) && (vector <= 14)) || (vector == 17) || (vector == 21) || (vector == 29) || (vector == 30)
+    .else
+    push 0
+    .endif
+    push offset vector
+    jmp interrupt_entry_common
+    .set vector, vector + 1
+.endr
+"#,
+    dispatch = sym interrupt_dispatch,
+);
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+extern "C" {
+    fn interrupt_stubs();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    if WRITER.lock().frame_buffer().is_init() {
-        panic!(
-            "EXCEPTION: DOUBLE FAULT ({:?})\n{:#?}",
-            error_code, stack_frame
-        );
-    } else {
-        loop {}
-    }
-}
+const STUB_SIZE: u64 = 16;
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    let addr = Cr2::read();
-    panic!(
-        "EXCEPTION: PAGE FAULT at {:?}, {:?}\n{:#?}\n",
-        addr, error_code, stack_frame
-    );
+fn stub_address(vector: usize) -> VirtAddr {
+    VirtAddr::new(interrupt_stubs as usize as u64 + vector as u64 * STUB_SIZE)
 }
 
-extern "x86-interrupt" fn gpf_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    panic!(
-        "EXCEPTION: GENERAL PROTECTION FAULT ({:?})\n{:#?}",
-        error_code, stack_frame
-    );
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let start = stats::timestamp();
+    let vector = frame.vector as u8;
+    if vector < MASTER_PIC_OFFSET {
+        exceptions::handle_exception(frame);
+    } else if irq::handle_irq(frame) {
+        add_interrupt_entropy(vector, start);
+    } else {
+        stats::record_spurious(vector);
+        return;
+    }
+    stats::record(vector, start);
 }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+fn timer_handler(_frame: &mut InterruptFrame) -> IrqReturn {
     tako_async::timer::tick();
     //print!(".");
-    PICS.lock()
-        .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-}
-
-extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::<u8>::new(0x60);
-    let scancode = unsafe { port.read() };
-    crate::keyboard::add_scancode(scancode);
-    PICS.lock()
-        .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    IrqReturn::Handled
 }
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        {
+            // The typed fields don't cover the reserved vectors, but the table
+            // is just 256 identical 16-byte gates, so it can be filled uniformly.
+            let entries = unsafe {
+                &mut *(&mut idt as *mut InterruptDescriptorTable as *mut [Entry<HandlerFunc>; 256])
+            };
+            for (vector, entry) in entries.iter_mut().enumerate() {
+                let options = unsafe { entry.set_handler_addr(stub_address(vector)) };
+                let stack_index = match ExceptionVector::from_u8(vector as u8) {
+                    Some(ExceptionVector::DoubleFault) => Some(DOUBLE_FAULT_IST_INDEX),
+                    Some(ExceptionVector::NonMaskableInterrupt) => Some(NMI_IST_INDEX),
+                    Some(ExceptionVector::MachineCheck) => Some(MACHINE_CHECK_IST_INDEX),
+                    _ => None,
+                };
+                if let Some(stack_index) = stack_index {
+                    unsafe {
+                        options.set_stack_index(stack_index);
+                    }
+                }
+            }
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault.set_handler_fn(gpf_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
         idt
     };
 }
 
-pub fn init_idt() {
+pub fn init_idt() -> InitResult {
     IDT.load();
+    Ok(())
+}
+
+initcall! {
+    name: "idt",
+    stage: Cpu,
+    depends_on: ["gdt"],
+    optional: false,
+    run: |_| init_idt(),
+}
+
+pub fn init_timer() -> InitResult {
+    register_irq(irq::TIMER_IRQ, timer_handler)
+        .map_err(|error| format!("couldn't register the timer IRQ: {:?}", error))?;
+    Ok(())
+}
+
+initcall! {
+    name: "timer",
+    stage: Interrupts,
+    depends_on: ["pic", "apic"],
+    optional: false,
+    run: |_| init_timer(),
+}
+
+initcall! {
+    name: "interrupts",
+    stage: Interrupts,
+    depends_on: ["timer", "keyboard"],
+    optional: false,
+    run: |_| {
+        x86_64::instructions::interrupts::enable();
+        Ok(())
+    },
 }
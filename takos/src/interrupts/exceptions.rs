@@ -0,0 +1,238 @@
+use core::fmt;
+
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+use crate::console::{try_print, WRITER};
+use crate::gdb::{handle_breakpoint, handle_debug};
+use crate::paging::PAGE_TABLE;
+use crate::serial::serial_ready;
+use crate::{hlt_loop, println};
+
+use super::InterruptFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExceptionVector {
+    DivideError = 0,
+    Debug = 1,
+    NonMaskableInterrupt = 2,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DeviceNotAvailable = 7,
+    DoubleFault = 8,
+    CoprocessorSegmentOverrun = 9,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    Virtualization = 20,
+    ControlProtection = 21,
+    HypervisorInjection = 28,
+    VmmCommunication = 29,
+    Security = 30,
+}
+
+impl ExceptionVector {
+    pub fn from_u8(vector: u8) -> Option<Self> {
+        use ExceptionVector::*;
+        Some(match vector {
+            0 => DivideError,
+            1 => Debug,
+            2 => NonMaskableInterrupt,
+            3 => Breakpoint,
+            4 => Overflow,
+            5 => BoundRangeExceeded,
+            6 => InvalidOpcode,
+            7 => DeviceNotAvailable,
+            8 => DoubleFault,
+            9 => CoprocessorSegmentOverrun,
+            10 => InvalidTss,
+            11 => SegmentNotPresent,
+            12 => StackSegmentFault,
+            13 => GeneralProtectionFault,
+            14 => PageFault,
+            16 => X87FloatingPoint,
+            17 => AlignmentCheck,
+            18 => MachineCheck,
+            19 => SimdFloatingPoint,
+            20 => Virtualization,
+            21 => ControlProtection,
+            28 => HypervisorInjection,
+            29 => VmmCommunication,
+            30 => Security,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        use ExceptionVector::*;
+        match self {
+            DivideError => "DIVIDE ERROR",
+            Debug => "DEBUG",
+            NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Breakpoint => "BREAKPOINT",
+            Overflow => "OVERFLOW",
+            BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            InvalidOpcode => "INVALID OPCODE",
+            DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            DoubleFault => "DOUBLE FAULT",
+            CoprocessorSegmentOverrun => "COPROCESSOR SEGMENT OVERRUN",
+            InvalidTss => "INVALID TSS",
+            SegmentNotPresent => "SEGMENT NOT PRESENT",
+            StackSegmentFault => "STACK SEGMENT FAULT",
+            GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            PageFault => "PAGE FAULT",
+            X87FloatingPoint => "X87 FLOATING POINT",
+            AlignmentCheck => "ALIGNMENT CHECK",
+            MachineCheck => "MACHINE CHECK",
+            SimdFloatingPoint => "SIMD FLOATING POINT",
+            Virtualization => "VIRTUALIZATION",
+            ControlProtection => "CONTROL PROTECTION",
+            HypervisorInjection => "HYPERVISOR INJECTION",
+            VmmCommunication => "VMM COMMUNICATION",
+            Security => "SECURITY",
+        }
+    }
+}
+
+const INSTRUCTION_BYTES: usize = 16;
+
+fn instruction_bytes(rip: u64) -> Option<[u8; INSTRUCTION_BYTES]> {
+    let first = VirtAddr::try_new(rip).ok()?;
+    let last = VirtAddr::try_new(rip + INSTRUCTION_BYTES as u64 - 1).ok()?;
+    {
+        // The fault might have happened with the page table locked.
+        let page_table = PAGE_TABLE.try_lock()?;
+        page_table.translate_addr(first)?;
+        page_table.translate_addr(last)?;
+    }
+    let mut bytes = [0u8; INSTRUCTION_BYTES];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((rip as *const u8).add(i)) };
+    }
+    Some(bytes)
+}
+
+impl fmt::Display for InterruptFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.registers;
+        writeln!(
+            f,
+            "RAX={:016X} RBX={:016X} RCX={:016X} RDX={:016X}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016X} RDI={:016X} RBP={:016X} RSP={:016X}",
+            r.rsi, r.rdi, r.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016X} R9 ={:016X} R10={:016X} R11={:016X}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016X} R13={:016X} R14={:016X} R15={:016X}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016X} RFLAGS={:016X} CS={:04X} SS={:04X} ERR={:X}",
+            self.rip, self.rflags, self.cs, self.ss, self.error_code
+        )?;
+        writeln!(
+            f,
+            "CR0={:016X} CR2={:016X} CR3={:016X} CR4={:016X}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64(),
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )?;
+        write!(f, "Code:")?;
+        match instruction_bytes(self.rip) {
+            Some(bytes) => {
+                for byte in bytes {
+                    write!(f, " {:02X}", byte)?;
+                }
+                Ok(())
+            }
+            None => write!(f, " <unavailable>"),
+        }
+    }
+}
+
+fn breakpoint_handler(frame: &mut InterruptFrame) {
+    if handle_breakpoint(frame) {
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{}", frame);
+}
+
+fn debug_handler(frame: &mut InterruptFrame) {
+    if handle_debug(frame) {
+        return;
+    }
+    println!("EXCEPTION: DEBUG\n{}", frame);
+}
+
+fn nmi_handler(frame: &mut InterruptFrame) {
+    try_print(format_args!(
+        "EXCEPTION: NON-MASKABLE INTERRUPT\n{}\n",
+        frame
+    ));
+}
+
+fn double_fault_handler(frame: &mut InterruptFrame) -> ! {
+    // A stack overflow inside `println!` double faults with the console held.
+    let frame_buffer_ready = WRITER
+        .try_lock()
+        .is_some_and(|mut writer| writer.frame_buffer().is_init());
+    if serial_ready() || frame_buffer_ready {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT ({:?})\n{}",
+            frame.error_code, frame
+        );
+    }
+    hlt_loop();
+}
+
+fn page_fault_handler(frame: &mut InterruptFrame) -> ! {
+    let addr = Cr2::read();
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:?}, {:?}\n{}\n",
+        addr, error_code, frame
+    );
+}
+
+fn fatal_exception_handler(frame: &mut InterruptFrame) -> ! {
+    let name = ExceptionVector::from_u8(frame.vector as u8)
+        .map(ExceptionVector::name)
+        .unwrap_or("RESERVED");
+    panic!(
+        "EXCEPTION: {} (vector {}, error code {:#X})\n{}",
+        name, frame.vector, frame.error_code, frame
+    );
+}
+
+pub fn handle_exception(frame: &mut InterruptFrame) {
+    match ExceptionVector::from_u8(frame.vector as u8) {
+        Some(ExceptionVector::Breakpoint) => breakpoint_handler(frame),
+        Some(ExceptionVector::Debug) => debug_handler(frame),
+        Some(ExceptionVector::NonMaskableInterrupt) => nmi_handler(frame),
+        Some(ExceptionVector::DoubleFault) => double_fault_handler(frame),
+        Some(ExceptionVector::PageFault) => page_fault_handler(frame),
+        _ => fatal_exception_handler(frame),
+    }
+}
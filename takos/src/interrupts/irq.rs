@@ -0,0 +1,212 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::apic::{self, SPURIOUS_VECTOR};
+use crate::pic::{MASTER_PIC_OFFSET, PICS, PIC_IRQ_COUNT};
+
+use super::{stats, InterruptFrame};
+
+/// IRQ number, i.e. the interrupt vector minus `MASTER_PIC_OFFSET`.
+/// IRQs below `PIC_IRQ_COUNT` are the legacy PIC lines, the rest are free
+/// vectors that can be handed out with `allocate_irq`.
+pub type Irq = u8;
+
+pub const IRQ_COUNT: usize = 256 - MASTER_PIC_OFFSET as usize;
+
+/// Handlers one IRQ can be shared between.
+pub const MAX_SHARED_HANDLERS: usize = 8;
+
+pub const TIMER_IRQ: Irq = 0;
+pub const KEYBOARD_IRQ: Irq = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+pub type IrqHandler = fn(&mut InterruptFrame) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IrqHandlerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq(Irq),
+    TooManyHandlers(Irq),
+    HandlerNotFound(Irq, IrqHandlerId),
+}
+
+struct IrqAction {
+    id: IrqHandlerId,
+    handler: IrqHandler,
+}
+
+static IRQ_ACTIONS: [Mutex<Vec<IrqAction>>; IRQ_COUNT] =
+    [const { Mutex::new(Vec::new()) }; IRQ_COUNT];
+static IRQ_ALLOCATED: [AtomicBool; IRQ_COUNT] = [const { AtomicBool::new(false) }; IRQ_COUNT];
+
+#[inline]
+pub fn irq_to_vector(irq: Irq) -> u8 {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} has no vector", irq);
+    irq + MASTER_PIC_OFFSET
+}
+
+fn end_of_interrupt(irq: Irq) {
+    if irq < PIC_IRQ_COUNT {
+        PICS.lock().notify_end_of_interrupt(irq_to_vector(irq));
+    } else {
+        apic::end_of_interrupt();
+    }
+}
+
+/// Adds `handler` to the chain of `irq`. Handlers of a shared IRQ are all
+/// called in registration order; EOI is sent after the last one.
+pub fn register_irq(irq: Irq, handler: IrqHandler) -> Result<IrqHandlerId, IrqError> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    let id = IrqHandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    without_interrupts(|| {
+        let mut actions = IRQ_ACTIONS[irq as usize].lock();
+        if actions.len() == MAX_SHARED_HANDLERS {
+            return Err(IrqError::TooManyHandlers(irq));
+        }
+        actions.push(IrqAction { id, handler });
+        if actions.len() == 1 && irq < PIC_IRQ_COUNT {
+            PICS.lock().set_masked(irq, false);
+        }
+        Ok(id)
+    })
+}
+
+pub fn unregister_irq(irq: Irq, id: IrqHandlerId) -> Result<(), IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| {
+        let mut actions = IRQ_ACTIONS[irq as usize].lock();
+        let index = actions
+            .iter()
+            .position(|action| action.id == id)
+            .ok_or(IrqError::HandlerNotFound(irq, id))?;
+        actions.remove(index);
+        if actions.is_empty() && irq < PIC_IRQ_COUNT {
+            PICS.lock().set_masked(irq, true);
+        }
+        Ok(())
+    })
+}
+
+fn is_allocatable(irq: usize) -> bool {
+    irq >= PIC_IRQ_COUNT as usize && irq_to_vector(irq as Irq) != SPURIOUS_VECTOR
+}
+
+/// Reserves an IRQ that isn't wired to the PIC, e.g. for MSI.
+pub fn allocate_irq() -> Option<Irq> {
+    allocate_irq_block(1)
+}
+
+/// Reserves `count` consecutive IRQs whose first vector is aligned to `count`,
+/// as required by multi-message MSI. `count` must be a power of two.
+pub fn allocate_irq_block(count: usize) -> Option<Irq> {
+    assert!(count.is_power_of_two());
+    without_interrupts(|| {
+        let first_vector = irq_to_vector(PIC_IRQ_COUNT) as usize;
+        let mut vector = (first_vector + count - 1) & !(count - 1);
+        while vector + count <= 256 {
+            let irq = vector - MASTER_PIC_OFFSET as usize;
+            let block = irq..irq + count;
+            if block
+                .clone()
+                .all(|irq| is_allocatable(irq) && !IRQ_ALLOCATED[irq].load(Ordering::Relaxed))
+            {
+                for irq in block {
+                    IRQ_ALLOCATED[irq].store(true, Ordering::Relaxed);
+                }
+                return Some(irq as Irq);
+            }
+            vector += count;
+        }
+        None
+    })
+}
+
+pub fn free_irq(irq: Irq) {
+    assert!(is_allocatable(irq as usize));
+    IRQ_ALLOCATED[irq as usize].store(false, Ordering::Relaxed);
+}
+
+pub fn irq_count(irq: Irq) -> Result<u64, IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    Ok(stats::vector_stats(irq_to_vector(irq)).count)
+}
+
+pub fn irq_unhandled_count(irq: Irq) -> Result<u64, IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    Ok(stats::vector_stats(irq_to_vector(irq)).unhandled)
+}
+
+pub fn irq_handler_count(irq: Irq) -> Result<usize, IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    Ok(without_interrupts(|| {
+        IRQ_ACTIONS[irq as usize].lock().len()
+    }))
+}
+
+/// Spurious interrupts must not be acknowledged like real ones: the LAPIC
+/// doesn't expect an EOI at all and the PIC only on the cascade line.
+fn check_spurious(irq: Irq) -> bool {
+    if irq_to_vector(irq) == SPURIOUS_VECTOR {
+        return true;
+    }
+    if irq < PIC_IRQ_COUNT {
+        let mut pics = PICS.lock();
+        if pics.is_spurious(irq) {
+            pics.notify_spurious(irq);
+            return true;
+        }
+    }
+    false
+}
+
+/// Returns false if the interrupt was spurious and no handler was called.
+pub(super) fn handle_irq(frame: &mut InterruptFrame) -> bool {
+    let irq = (frame.vector - MASTER_PIC_OFFSET as u64) as Irq;
+    if check_spurious(irq) {
+        return false;
+    }
+
+    // Handlers run without the lock so they can (un)register on their own
+    // IRQ, and copying them to the stack doesn't allocate.
+    let mut handlers: [Option<IrqHandler>; MAX_SHARED_HANDLERS] = [None; MAX_SHARED_HANDLERS];
+    for (slot, action) in handlers
+        .iter_mut()
+        .zip(IRQ_ACTIONS[irq as usize].lock().iter())
+    {
+        *slot = Some(action.handler);
+    }
+    let mut result = IrqReturn::NotHandled;
+    for handler in handlers.into_iter().flatten() {
+        if handler(frame) == IrqReturn::Handled {
+            result = IrqReturn::Handled;
+        }
+    }
+    if result == IrqReturn::NotHandled {
+        stats::record_unhandled(irq_to_vector(irq));
+    }
+
+    end_of_interrupt(irq);
+    true
+}
@@ -0,0 +1,164 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+
+use crate::apic::local_apic_id;
+use crate::pic::MASTER_PIC_OFFSET;
+use crate::println;
+
+use super::exceptions::ExceptionVector;
+use super::irq::{irq_handler_count, Irq};
+
+pub const MAX_CPUS: usize = 16;
+const VECTOR_COUNT: usize = 256;
+
+struct VectorCounters {
+    count: AtomicU64,
+    spurious: AtomicU64,
+    unhandled: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl VectorCounters {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+static COUNTERS: [[VectorCounters; VECTOR_COUNT]; MAX_CPUS] =
+    [const { [const { VectorCounters::new() }; VECTOR_COUNT] }; MAX_CPUS];
+/// The local APIC ID of each CPU number, `NO_CPU` for unused slots.
+static CPU_APIC_IDS: [AtomicU16; MAX_CPUS] = [const { AtomicU16::new(NO_CPU) }; MAX_CPUS];
+const NO_CPU: u16 = u16::MAX;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterruptStats {
+    pub count: u64,
+    pub spurious: u64,
+    pub unhandled: u64,
+    /// Handler time in TSC cycles.
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl InterruptStats {
+    pub fn average_cycles(&self) -> u64 {
+        let handled = self.count - self.spurious;
+        self.total_cycles.checked_div(handled).unwrap_or(0)
+    }
+
+    fn add(&mut self, other: &InterruptStats) {
+        self.count += other.count;
+        self.spurious += other.spurious;
+        self.unhandled += other.unhandled;
+        self.total_cycles += other.total_cycles;
+        self.max_cycles = self.max_cycles.max(other.max_cycles);
+    }
+}
+
+/// A dense number for the running CPU, handed out the first time it takes
+/// an interrupt. APIC IDs can be sparse, so they aren't used directly.
+/// `None` once `MAX_CPUS` CPUs have been numbered.
+pub fn current_cpu() -> Option<usize> {
+    let apic_id = local_apic_id() as u16;
+    for (cpu, slot) in CPU_APIC_IDS.iter().enumerate() {
+        match slot.compare_exchange(NO_CPU, apic_id, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return Some(cpu),
+            Err(id) if id == apic_id => return Some(cpu),
+            Err(_) => {}
+        }
+    }
+    None
+}
+
+#[inline]
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Interrupts on CPUs past `MAX_CPUS` aren't counted.
+fn counters(vector: u8) -> Option<&'static VectorCounters> {
+    Some(&COUNTERS[current_cpu()?][vector as usize])
+}
+
+pub(super) fn record(vector: u8, start: u64) {
+    let cycles = timestamp().wrapping_sub(start);
+    if let Some(counters) = counters(vector) {
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.total_cycles.fetch_add(cycles, Ordering::Relaxed);
+        counters.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+pub(super) fn record_spurious(vector: u8) {
+    if let Some(counters) = counters(vector) {
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.spurious.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(super) fn record_unhandled(vector: u8) {
+    if let Some(counters) = counters(vector) {
+        counters.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn cpu_stats(cpu: usize, vector: u8) -> InterruptStats {
+    let counters = &COUNTERS[cpu][vector as usize];
+    InterruptStats {
+        count: counters.count.load(Ordering::Relaxed),
+        spurious: counters.spurious.load(Ordering::Relaxed),
+        unhandled: counters.unhandled.load(Ordering::Relaxed),
+        total_cycles: counters.total_cycles.load(Ordering::Relaxed),
+        max_cycles: counters.max_cycles.load(Ordering::Relaxed),
+    }
+}
+
+pub fn vector_stats(vector: u8) -> InterruptStats {
+    let mut result = InterruptStats::default();
+    for cpu in 0..MAX_CPUS {
+        result.add(&cpu_stats(cpu, vector));
+    }
+    result
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    if vector < MASTER_PIC_OFFSET {
+        ExceptionVector::from_u8(vector)
+            .map(ExceptionVector::name)
+            .unwrap_or("RESERVED")
+    } else if irq_handler_count((vector - MASTER_PIC_OFFSET) as Irq).is_ok_and(|count| count > 0) {
+        "IRQ"
+    } else {
+        "-"
+    }
+}
+
+pub fn print_interrupt_stats() {
+    println!("VEC CPU      COUNT  SPURIOUS UNHANDLED   AVG CYC   MAX CYC NAME");
+    for vector in 0..=255u8 {
+        for cpu in 0..MAX_CPUS {
+            let stats = cpu_stats(cpu, vector);
+            if stats.count == 0 {
+                continue;
+            }
+            println!(
+                "{:3} {:3} {:10} {:9} {:9} {:9} {:9} {}",
+                vector,
+                cpu,
+                stats.count,
+                stats.spurious,
+                stats.unhandled,
+                stats.average_cycles(),
+                stats.max_cycles,
+                vector_name(vector)
+            );
+        }
+    }
+}
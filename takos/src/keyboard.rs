@@ -7,5 +7,6 @@ mod typer;
 
 pub use decoder::get_keyboard_event_receiver;
 pub use driver::add_scancode;
+pub use driver::init_keyboard;
 pub use driver::keyboard_driver;
 pub use typer::KeyboardEvent;
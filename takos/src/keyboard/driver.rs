@@ -1,3 +1,4 @@
+use alloc::format;
 use core::{
     pin::Pin,
     sync::atomic::{AtomicBool, Ordering},
@@ -8,6 +9,8 @@ use futures::{task::AtomicWaker, Stream, StreamExt};
 use thingbuf::StaticThingBuf;
 use x86_64::instructions::port::Port;
 
+use crate::initcall::{initcall, InitResult};
+use crate::interrupts::{irq::KEYBOARD_IRQ, register_irq, InterruptFrame, IrqReturn};
 use crate::{keyboard::decoder::keycode_decoder, println};
 
 use super::commands;
@@ -54,6 +57,27 @@ pub fn add_scancode(scancode: u8) {
     }
 }
 
+fn keyboard_handler(_frame: &mut InterruptFrame) -> IrqReturn {
+    let mut port = Port::<u8>::new(0x60);
+    let scancode = unsafe { port.read() };
+    add_scancode(scancode);
+    IrqReturn::Handled
+}
+
+pub fn init_keyboard() -> InitResult {
+    register_irq(KEYBOARD_IRQ, keyboard_handler)
+        .map_err(|error| format!("couldn't register the keyboard IRQ: {:?}", error))?;
+    Ok(())
+}
+
+initcall! {
+    name: "keyboard",
+    stage: Interrupts,
+    depends_on: ["pic", "apic"],
+    optional: false,
+    run: |_| init_keyboard(),
+}
+
 pub async fn init_ps2_controller(scancodes: &mut ScancodeStream) {
     let mut control_port = Port::<u8>::new(0x64);
     let mut data_port = Port::<u8>::new(0x60);
@@ -0,0 +1,592 @@
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use elf::abi::{
+    EM_X86_64, ET_REL, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_GOTPCREL,
+    R_X86_64_GOTPCRELX, R_X86_64_NONE, R_X86_64_PC32, R_X86_64_PC64, R_X86_64_PLT32,
+    R_X86_64_REX_GOTPCRELX, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHN_ABS, SHN_COMMON, SHN_UNDEF,
+    SHT_NOBITS, SHT_RELA, STB_WEAK,
+};
+use elf::endian::AnyEndian;
+use elf::section::SectionHeader;
+use elf::string_table::StringTable;
+use elf::symbol::SymbolTable;
+use elf::{ElfBytes, ParseError};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::allocator::frame_allocator::FRAME_ALLOCATOR;
+use crate::cmdline::cmdline;
+use crate::filesystem::FilesystemError;
+use crate::initcall::{initcall, InitResult};
+use crate::paging::PAGE_TABLE;
+use crate::{println, RAMDISK_FILESYSTEM};
+
+use self::exports::find_kernel_symbol;
+
+pub mod exports;
+
+// Kernel modules are ELF relocatable objects, built with
+// `-C relocation-model=pic`. They export `extern "C" fn module_init() -> i32`,
+// which returns 0 on success, and optionally `extern "C" fn module_exit()`.
+// Undefined symbols are looked up among those exported with `export_symbol!`.
+
+/// Just below the kernel image, so 32 bit PC-relative references into the
+/// kernel reach.
+const KMOD_AREA_START: u64 = 0xFFFF_FFFF_7000_0000;
+const KMOD_AREA_SIZE: u64 = 0x1000_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+static KMOD_NEXT: AtomicU64 = AtomicU64::new(KMOD_AREA_START);
+
+#[derive(Debug)]
+pub enum ModuleError {
+    Parse(ParseError),
+    Malformed(&'static str),
+    NotRelocatable,
+    WrongMachine(u16),
+    UndefinedSymbol(String),
+    CommonSymbol(String),
+    UnsupportedRelocation(u32),
+    RelocationOverflow { kind: u32, offset: u64 },
+    NoInit,
+    InitFailed(i32),
+    AlreadyLoaded(String),
+    NotLoaded(String),
+    OutOfAddressSpace,
+    OutOfMemory,
+    NoRamdisk,
+    File(FilesystemError),
+}
+
+impl From<ParseError> for ModuleError {
+    fn from(error: ParseError) -> Self {
+        ModuleError::Parse(error)
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::Parse(error) => write!(f, "bad ELF file: {}", error),
+            ModuleError::Malformed(reason) => write!(f, "malformed module: {}", reason),
+            ModuleError::NotRelocatable => write!(f, "not a relocatable object"),
+            ModuleError::WrongMachine(machine) => write!(f, "not built for x86_64 ({})", machine),
+            ModuleError::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            ModuleError::CommonSymbol(name) => {
+                write!(f, "common symbol `{}`, build with -fno-common", name)
+            }
+            ModuleError::UnsupportedRelocation(kind) => {
+                write!(f, "unsupported relocation type {}", kind)
+            }
+            ModuleError::RelocationOverflow { kind, offset } => write!(
+                f,
+                "relocation type {} at offset {:X} doesn't fit",
+                kind, offset
+            ),
+            ModuleError::NoInit => write!(f, "no module_init"),
+            ModuleError::InitFailed(result) => write!(f, "module_init returned {}", result),
+            ModuleError::AlreadyLoaded(name) => write!(f, "{} is already loaded", name),
+            ModuleError::NotLoaded(name) => write!(f, "{} isn't loaded", name),
+            ModuleError::OutOfAddressSpace => write!(f, "module area exhausted"),
+            ModuleError::OutOfMemory => write!(f, "out of memory"),
+            ModuleError::NoRamdisk => write!(f, "no ramdisk"),
+            ModuleError::File(error) => write!(f, "{:?}", error),
+        }
+    }
+}
+
+/// Sections are grouped by the permissions they end up with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    Text,
+    ReadOnly,
+    Data,
+}
+
+const SEGMENTS: [Segment; 3] = [Segment::Text, Segment::ReadOnly, Segment::Data];
+
+impl Segment {
+    fn of(section: &SectionHeader) -> Self {
+        if section.sh_flags & SHF_EXECINSTR as u64 != 0 {
+            Segment::Text
+        } else if section.sh_flags & SHF_WRITE as u64 != 0 {
+            Segment::Data
+        } else {
+            Segment::ReadOnly
+        }
+    }
+
+    fn page_flags(self) -> PageTableFlags {
+        match self {
+            Segment::Text => PageTableFlags::PRESENT,
+            Segment::ReadOnly => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            Segment::Data => {
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            }
+        }
+    }
+}
+
+pub struct LoadedModule {
+    pub name: String,
+    base: u64,
+    pages: u64,
+    exit: Option<extern "C" fn()>,
+}
+
+static LOADED_MODULES: Mutex<Vec<LoadedModule>> = Mutex::new(Vec::new());
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+fn is_got_relocation(kind: u32) -> bool {
+    matches!(
+        kind,
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX
+    )
+}
+
+fn page(address: u64) -> Page<Size4KiB> {
+    Page::from_start_address(VirtAddr::new(address)).unwrap()
+}
+
+fn unmap_pages(base: u64, pages: u64) {
+    let mut page_table = PAGE_TABLE.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..pages {
+        let (frame, flush) = page_table
+            .unmap(page(base + i * PAGE_SIZE))
+            .expect("Kernel module page isn't mapped");
+        flush.flush();
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+/// Maps fresh, zeroed pages; they stay writable until `protect`.
+fn map_pages(base: u64, pages: u64) -> Result<(), ModuleError> {
+    let mut page_table = PAGE_TABLE.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let flags = Segment::Data.page_flags();
+    for i in 0..pages {
+        let mapped = frame_allocator.allocate_frame().and_then(|frame| {
+            unsafe {
+                page_table.map_to(
+                    page(base + i * PAGE_SIZE),
+                    frame,
+                    flags,
+                    &mut *frame_allocator,
+                )
+            }
+            .ok()
+        });
+        match mapped {
+            Some(flush) => flush.flush(),
+            None => {
+                drop(page_table);
+                drop(frame_allocator);
+                unmap_pages(base, i);
+                return Err(ModuleError::OutOfMemory);
+            }
+        }
+    }
+    unsafe { core::ptr::write_bytes(base as *mut u8, 0, (pages * PAGE_SIZE) as usize) };
+    Ok(())
+}
+
+fn protect(base: u64, pages: u64, flags: PageTableFlags) {
+    let mut page_table = PAGE_TABLE.lock();
+    for i in 0..pages {
+        unsafe { page_table.update_flags(page(base + i * PAGE_SIZE), flags) }
+            .expect("Kernel module page isn't mapped")
+            .flush();
+    }
+}
+
+/// Where every section goes, relative to the module's base.
+struct Layout {
+    sections: Vec<Option<u64>>,
+    /// Start and size of each of `SEGMENTS`, page aligned.
+    segments: [(u64, u64); 3],
+    got: u64,
+    got_symbols: Vec<u32>,
+    pages: u64,
+}
+
+impl Layout {
+    fn new(sections: &[SectionHeader], got_symbols: Vec<u32>) -> Self {
+        let mut layout = Layout {
+            sections: vec![None; sections.len()],
+            segments: [(0, 0); 3],
+            got: 0,
+            got_symbols,
+            pages: 0,
+        };
+        let mut size = 0;
+        for (index, segment) in SEGMENTS.iter().enumerate() {
+            let start = size;
+            for (section_index, section) in sections.iter().enumerate() {
+                if section.sh_flags & SHF_ALLOC as u64 == 0 || Segment::of(section) != *segment {
+                    continue;
+                }
+                size = align_up(size, section.sh_addralign.max(1));
+                layout.sections[section_index] = Some(size);
+                size += section.sh_size;
+            }
+            // The GOT is only written while loading.
+            if *segment == Segment::ReadOnly {
+                size = align_up(size, 8);
+                layout.got = size;
+                size += 8 * layout.got_symbols.len() as u64;
+            }
+            size = align_up(size, PAGE_SIZE);
+            layout.segments[index] = (start, size - start);
+        }
+        layout.pages = size / PAGE_SIZE;
+        layout
+    }
+
+    fn got_entry(&self, symbol: u32) -> u64 {
+        let slot = self.got_symbols.iter().position(|&s| s == symbol).unwrap();
+        self.got + 8 * slot as u64
+    }
+}
+
+struct Symbols<'a> {
+    table: SymbolTable<'a, AnyEndian>,
+    names: StringTable<'a>,
+}
+
+impl Symbols<'_> {
+    fn address(&self, index: u32, base: u64, layout: &Layout) -> Result<u64, ModuleError> {
+        if index == 0 {
+            return Ok(0);
+        }
+        let symbol = self.table.get(index as usize)?;
+        match symbol.st_shndx {
+            SHN_UNDEF => {
+                let name = self.names.get(symbol.st_name as usize)?;
+                match find_kernel_symbol(name) {
+                    Some(address) => Ok(address),
+                    None if symbol.st_bind() == STB_WEAK => Ok(0),
+                    None => Err(ModuleError::UndefinedSymbol(name.to_string())),
+                }
+            }
+            SHN_ABS => Ok(symbol.st_value),
+            SHN_COMMON => {
+                let name = self.names.get(symbol.st_name as usize)?;
+                Err(ModuleError::CommonSymbol(name.to_string()))
+            }
+            section => layout
+                .sections
+                .get(section as usize)
+                .copied()
+                .flatten()
+                .map(|offset| base + offset + symbol.st_value)
+                .ok_or(ModuleError::Malformed(
+                    "symbol in a section that isn't loaded",
+                )),
+        }
+    }
+
+    /// A function the module defines, such as `module_init`.
+    fn function(&self, name: &str, base: u64, layout: &Layout) -> Option<u64> {
+        self.table.iter().enumerate().find_map(|(index, symbol)| {
+            (symbol.st_shndx != SHN_UNDEF
+                && self.names.get(symbol.st_name as usize).ok() == Some(name))
+            .then(|| self.address(index as u32, base, layout).ok())
+            .flatten()
+        })
+    }
+}
+
+fn write_value<T>(place: u64, value: T) {
+    unsafe { core::ptr::write_unaligned(place as *mut T, value) }
+}
+
+fn relocate(
+    elf: &ElfBytes<AnyEndian>,
+    sections: &[SectionHeader],
+    symbols: &Symbols,
+    base: u64,
+    layout: &Layout,
+) -> Result<(), ModuleError> {
+    for (slot, &symbol) in layout.got_symbols.iter().enumerate() {
+        let address = symbols.address(symbol, base, layout)?;
+        write_value(base + layout.got + 8 * slot as u64, address);
+    }
+
+    for section in sections
+        .iter()
+        .filter(|section| section.sh_type == SHT_RELA)
+    {
+        // Relocations for debug info and the like don't matter.
+        let Some(Some(target)) = layout.sections.get(section.sh_info as usize).copied() else {
+            continue;
+        };
+        let target_size = sections[section.sh_info as usize].sh_size;
+        for relocation in elf.section_data_as_relas(section)? {
+            let kind = relocation.r_type;
+            let width = match kind {
+                R_X86_64_NONE => 0,
+                R_X86_64_64 | R_X86_64_PC64 => 8,
+                _ => 4,
+            };
+            if relocation
+                .r_offset
+                .checked_add(width)
+                .filter(|&end| end <= target_size)
+                .is_none()
+            {
+                return Err(ModuleError::Malformed("relocation outside its section"));
+            }
+            let place = base + target + relocation.r_offset;
+            let symbol = symbols.address(relocation.r_sym, base, layout)?;
+            let value = symbol.wrapping_add(relocation.r_addend as u64);
+            let pc_relative = |target: u64| {
+                i32::try_from(target.wrapping_sub(place) as i64).map_err(|_| {
+                    ModuleError::RelocationOverflow {
+                        kind,
+                        offset: relocation.r_offset,
+                    }
+                })
+            };
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_64 => write_value(place, value),
+                R_X86_64_PC64 => write_value(place, value.wrapping_sub(place)),
+                R_X86_64_PC32 | R_X86_64_PLT32 => write_value(place, pc_relative(value)?),
+                R_X86_64_32 => write_value(
+                    place,
+                    u32::try_from(value).map_err(|_| ModuleError::RelocationOverflow {
+                        kind,
+                        offset: relocation.r_offset,
+                    })?,
+                ),
+                R_X86_64_32S => write_value(
+                    place,
+                    i32::try_from(value as i64).map_err(|_| ModuleError::RelocationOverflow {
+                        kind,
+                        offset: relocation.r_offset,
+                    })?,
+                ),
+                kind if is_got_relocation(kind) => {
+                    let entry = base + layout.got_entry(relocation.r_sym);
+                    write_value(
+                        place,
+                        pc_relative(entry.wrapping_add(relocation.r_addend as u64))?,
+                    )
+                }
+                kind => return Err(ModuleError::UnsupportedRelocation(kind)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies, relocates and protects the module at `base`, whose pages are
+/// already mapped, and returns its `module_init` and `module_exit`.
+fn link(
+    elf: &ElfBytes<AnyEndian>,
+    sections: &[SectionHeader],
+    symbols: &Symbols,
+    base: u64,
+    layout: &Layout,
+) -> Result<(u64, Option<u64>), ModuleError> {
+    for (section, offset) in sections.iter().zip(layout.sections.iter()) {
+        let Some(offset) = offset else {
+            continue;
+        };
+        if section.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let (data, compression) = elf.section_data(section)?;
+        if compression.is_some() {
+            return Err(ModuleError::Malformed("compressed section"));
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), (base + offset) as *mut u8, data.len())
+        };
+    }
+    relocate(elf, sections, symbols, base, layout)?;
+
+    for (segment, (start, size)) in SEGMENTS.iter().zip(layout.segments) {
+        protect(base + start, size / PAGE_SIZE, segment.page_flags());
+    }
+
+    let init = symbols
+        .function("module_init", base, layout)
+        .ok_or(ModuleError::NoInit)?;
+    let exit = symbols.function("module_exit", base, layout);
+    Ok((init, exit))
+}
+
+/// Loads the ELF relocatable object `data` as kernel module `name` and runs
+/// its `module_init`.
+pub fn load_module(name: &str, data: &[u8]) -> Result<(), ModuleError> {
+    if LOADED_MODULES
+        .lock()
+        .iter()
+        .any(|module| module.name == name)
+    {
+        return Err(ModuleError::AlreadyLoaded(name.to_string()));
+    }
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(data)?;
+    if elf.ehdr.e_type != ET_REL {
+        return Err(ModuleError::NotRelocatable);
+    }
+    if elf.ehdr.e_machine != EM_X86_64 {
+        return Err(ModuleError::WrongMachine(elf.ehdr.e_machine));
+    }
+    let sections: Vec<SectionHeader> = elf
+        .section_headers()
+        .ok_or(ModuleError::Malformed("no section headers"))?
+        .iter()
+        .collect();
+    let (table, names) = elf
+        .symbol_table()?
+        .ok_or(ModuleError::Malformed("no symbol table"))?;
+    let symbols = Symbols { table, names };
+
+    let mut got_symbols = Vec::new();
+    for section in sections
+        .iter()
+        .filter(|section| section.sh_type == SHT_RELA)
+    {
+        for relocation in elf.section_data_as_relas(section)? {
+            if is_got_relocation(relocation.r_type) && !got_symbols.contains(&relocation.r_sym) {
+                got_symbols.push(relocation.r_sym);
+            }
+        }
+    }
+    let layout = Layout::new(&sections, got_symbols);
+    if layout.pages == 0 {
+        return Err(ModuleError::Malformed("nothing to load"));
+    }
+
+    // One more page is left unmapped as a guard before the next module.
+    let base = KMOD_NEXT.fetch_add((layout.pages + 1) * PAGE_SIZE, Ordering::Relaxed);
+    if base + layout.pages * PAGE_SIZE > KMOD_AREA_START + KMOD_AREA_SIZE {
+        return Err(ModuleError::OutOfAddressSpace);
+    }
+    map_pages(base, layout.pages)?;
+    let (init, exit) = match link(&elf, &sections, &symbols, base, &layout) {
+        Ok(entry_points) => entry_points,
+        Err(error) => {
+            unmap_pages(base, layout.pages);
+            return Err(error);
+        }
+    };
+
+    info!(
+        "Kernel module {} at {:016X}, {} pages",
+        name, base, layout.pages
+    );
+    let init: extern "C" fn() -> i32 = unsafe { core::mem::transmute(init) };
+    let result = init();
+    if result != 0 {
+        unmap_pages(base, layout.pages);
+        return Err(ModuleError::InitFailed(result));
+    }
+    LOADED_MODULES.lock().push(LoadedModule {
+        name: name.to_string(),
+        base,
+        pages: layout.pages,
+        exit: exit.map(|exit| unsafe { core::mem::transmute(exit) }),
+    });
+    Ok(())
+}
+
+/// Loads a kernel module from the ramdisk, named after its file.
+pub fn load_module_file(path: &str) -> Result<(), ModuleError> {
+    let filesystem = RAMDISK_FILESYSTEM.get().ok_or(ModuleError::NoRamdisk)?;
+    let data = filesystem.read_file(path).map_err(ModuleError::File)?;
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let name = file_name.split('.').next().unwrap_or(file_name);
+    load_module(name, &data)
+}
+
+/// Runs the module's `module_exit` and frees its memory.
+pub fn unload_module(name: &str) -> Result<(), ModuleError> {
+    let module = {
+        let mut modules = LOADED_MODULES.lock();
+        let index = modules
+            .iter()
+            .position(|module| module.name == name)
+            .ok_or_else(|| ModuleError::NotLoaded(name.to_string()))?;
+        modules.remove(index)
+    };
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    unmap_pages(module.base, module.pages);
+    info!("Kernel module {} unloaded", name);
+    Ok(())
+}
+
+/// Loads the modules given with `kmod=` on the command line.
+pub fn load_command_line_modules() -> InitResult {
+    for path in cmdline().kmods.iter() {
+        if let Err(error) = load_module_file(path) {
+            warn!("Couldn't load kernel module {}: {}", path, error);
+        }
+    }
+    Ok(())
+}
+
+initcall! {
+    name: "kmods",
+    stage: Devices,
+    depends_on: ["ramdisk", "pci"],
+    optional: true,
+    run: |_| load_command_line_modules(),
+}
+
+pub fn print_loaded_modules() {
+    for module in LOADED_MODULES.lock().iter() {
+        println!(
+            "{:16} {:016X} {:>6} pages",
+            module.name, module.base, module.pages
+        );
+    }
+}
+
+#[test_case]
+fn test_load_module() {
+    use crate::{print, println};
+    print!("test_load_module... ");
+
+    // Built from resources/test_module.s; its `module_init` fails unless the
+    // GOT, PC-relative and absolute relocations all came out right.
+    let module = include_bytes!("../resources/test_module.o");
+    load_module("test_module", module).unwrap();
+    assert!(matches!(
+        load_module("test_module", module),
+        Err(ModuleError::AlreadyLoaded(_))
+    ));
+    unload_module("test_module").unwrap();
+
+    let mut module = module.to_vec();
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(&module).unwrap();
+    let rela = elf
+        .section_headers()
+        .unwrap()
+        .iter()
+        .find(|section| section.sh_type == SHT_RELA)
+        .unwrap();
+    let offset = rela.sh_offset as usize;
+    module[offset..offset + 8].copy_from_slice(&0x1000u64.to_le_bytes());
+    assert!(matches!(
+        load_module("test_module", &module),
+        Err(ModuleError::Malformed(_))
+    ));
+
+    println!("[ok]");
+}
@@ -0,0 +1,90 @@
+use alloc::alloc::{alloc, dealloc, Layout};
+use core::slice;
+use log::Level;
+
+use crate::paging::map_mmio;
+
+/// An entry of the table kernel modules are linked against.
+#[repr(C)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub address: *const (),
+}
+
+unsafe impl Sync for KernelSymbol {}
+
+/// Lets kernel modules call an `extern "C"` function by its name.
+#[macro_export]
+macro_rules! export_symbol {
+    ($function:ident) => {
+        const _: () = {
+            #[used]
+            #[link_section = ".kernel_symbols"]
+            static SYMBOL: $crate::kmod::exports::KernelSymbol =
+                $crate::kmod::exports::KernelSymbol {
+                    name: stringify!($function),
+                    address: $function as *const (),
+                };
+        };
+    };
+}
+
+// Defined in link.x around the `.kernel_symbols` section.
+extern "C" {
+    static __start_kernel_symbols: u8;
+    static __stop_kernel_symbols: u8;
+}
+
+pub fn kernel_symbols() -> &'static [KernelSymbol] {
+    unsafe {
+        let start = &__start_kernel_symbols as *const u8 as *const KernelSymbol;
+        let end = &__stop_kernel_symbols as *const u8 as *const KernelSymbol;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+pub fn find_kernel_symbol(name: &str) -> Option<u64> {
+    kernel_symbols()
+        .iter()
+        .find(|symbol| symbol.name == name)
+        .map(|symbol| symbol.address as u64)
+}
+
+/// `level` is 1 for errors up to 5 for trace messages, as in `log::Level`.
+extern "C" fn takos_log(level: u32, message: *const u8, length: usize) {
+    let level = match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    };
+    let message = unsafe { slice::from_raw_parts(message, length) };
+    log::log!(
+        target: "kmod",
+        level,
+        "{}",
+        core::str::from_utf8(message).unwrap_or("<invalid UTF-8>")
+    );
+}
+export_symbol!(takos_log);
+
+extern "C" fn takos_alloc(size: usize, align: usize) -> *mut u8 {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) if size > 0 => unsafe { alloc(layout) },
+        _ => core::ptr::null_mut(),
+    }
+}
+export_symbol!(takos_alloc);
+
+extern "C" fn takos_free(pointer: *mut u8, size: usize, align: usize) {
+    if let Ok(layout) = Layout::from_size_align(size, align) {
+        unsafe { dealloc(pointer, layout) }
+    }
+}
+export_symbol!(takos_free);
+
+extern "C" fn takos_map_mmio(physical_address: u64, size: u64) -> u64 {
+    map_mmio(physical_address, size)
+}
+export_symbol!(takos_map_mmio);
@@ -1,6 +1,5 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
 #![feature(custom_test_frameworks)]
 #![feature(naked_functions)]
 #![test_runner(crate::test_runner)]
@@ -11,59 +10,113 @@ extern crate alloc;
 #[cfg(test)]
 use core::panic::PanicInfo;
 
-use ::log::info;
-use alloc::string::ToString;
-use allocator::frame_allocator::init_frame_allocator;
+use ::log::{info, warn};
+use alloc::boxed::Box;
+use boot::{command_line, log_boot_tags};
+use cmdline::{cmdline, init_cmdline, warn_unknown_options, Console};
 use conquer_once::spin::OnceCell;
-use console::init_writer;
-use display::{ColorRGB, FrameBuffer};
-use filesystem::fat::Fat32Filesystem;
-use gdt::init_gdt;
-use interrupts::init_idt;
-use paging::{init_pat, unmap_loader_code};
-use pic::init_pics;
+use console::set_frame_buffer_output;
+use cpu::cpu_info;
+use filesystem::{open_ramdisk, Filesystem};
+use initcall::{initcall, initcalls, run_initcalls, InitResult};
+use modules::find_module;
+use serial::{console_port, serial_ready};
 use takobl_api::BootData;
-
-use crate::{filesystem::ramdisk::RamDisk, pci::init_pci};
+use tsc::tsc_frequency;
 
 pub mod allocator;
+pub mod apic;
+pub mod boot;
+pub mod cmdline;
 pub mod console;
+pub mod cpu;
+pub mod crash;
+pub mod diagnostics;
 pub mod display;
-mod filesystem;
+pub mod efi;
+pub mod filesystem;
+pub mod fpu;
+pub mod gdb;
 mod gdt;
+pub mod hardening;
+pub mod initcall;
 pub mod interrupts;
 pub mod keyboard;
-mod log;
+pub mod kmod;
+pub mod log;
+pub mod modules;
 pub mod multitask;
 pub mod paging;
-mod pci;
+pub mod pci;
 mod pic;
+pub mod random;
+pub mod serial;
 pub mod text;
+pub mod tsc;
+
+pub static RAMDISK_FILESYSTEM: OnceCell<Box<dyn Filesystem + Send + Sync>> = OnceCell::uninit();
+pub static KERNEL_BASE: OnceCell<u64> = OnceCell::uninit();
+
+fn log_boot_info(boot_data: &'static BootData) -> InitResult {
+    info!("Command line: {}", command_line(boot_data));
+    warn_unknown_options();
+    match console_port() {
+        Some(port) => info!("Serial console on {:?}", port),
+        None if cmdline().console == Console::Serial => {
+            warn!("No UART, console stays on the framebuffer")
+        }
+        None => {}
+    }
+    info!("CPU: {}", cpu_info().brand());
+    info!("TSC: {} MHz", tsc_frequency() / 1_000_000);
+    info!("Kernel base: {:016X}", boot_data.kernel_base);
+    log_boot_tags(boot_data);
+    Ok(())
+}
 
-pub static RAMDISK_FILESYSTEM: OnceCell<Fat32Filesystem> = OnceCell::uninit();
-
-pub fn init(boot_data: &'static mut BootData) {
-    init_gdt();
-    init_idt();
-    init_pat();
-    init_frame_allocator(boot_data.free_memory_map.clone());
+initcall! {
+    name: "boot-info",
+    stage: Console,
+    depends_on: ["logger"],
+    optional: false,
+    run: log_boot_info,
+}
 
-    let frame_buffer = FrameBuffer::new(&boot_data.frame_buffer);
-    frame_buffer.fill(ColorRGB::from_hex(0x000000));
-    init_writer(frame_buffer);
-    crate::log::init().expect("Couldn't initialize logger");
+fn init_ramdisk(_: &'static BootData) -> InitResult {
+    let initrd = find_module("initrd").ok_or("no initrd module")?;
+    // takobl already decompressed it.
+    let data = unsafe { initrd.data_mut() };
+    RAMDISK_FILESYSTEM.init_once(|| open_ramdisk(data));
+    Ok(())
+}
 
-    let image_device_path = boot_data.image_device_path.to_string();
+initcall! {
+    name: "ramdisk",
+    stage: Firmware,
+    depends_on: ["boot-modules"],
+    optional: true,
+    run: init_ramdisk,
+}
 
-    unmap_loader_code(boot_data.loader_code);
-    let device = RamDisk::new(boot_data.ramdisk);
-    RAMDISK_FILESYSTEM.init_once(|| Fat32Filesystem::new(device));
+fn init_command_line(boot_data: &'static BootData) -> InitResult {
+    KERNEL_BASE.init_once(|| boot_data.kernel_base);
+    init_cmdline(command_line(boot_data));
+    if cmdline().console == Console::Serial && serial_ready() {
+        set_frame_buffer_output(false);
+    }
+    Ok(())
+}
 
-    init_pics();
-    x86_64::instructions::interrupts::enable();
+initcall! {
+    name: "cmdline",
+    stage: Memory,
+    depends_on: ["heap"],
+    optional: false,
+    run: init_command_line,
+}
 
-    info!("Image device path: {}", image_device_path);
-    init_pci();
+pub fn init(boot_data: &'static BootData) {
+    run_initcalls(initcalls(), boot_data);
 }
 
 pub fn hlt_loop() -> ! {
@@ -111,6 +164,7 @@ fn panic(info: &PanicInfo) -> ! {
 #[cfg(test)]
 #[export_name = "_start"]
 pub extern "C" fn _start(boot_data: &'static mut BootData) -> ! {
+    boot::check_boot_data(boot_data);
     init(boot_data);
     println!("Testing!");
     #[cfg(test)]
@@ -1,25 +1,353 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 
+use crate::cmdline::cmdline;
+use crate::initcall::initcall;
 use crate::println;
+use crate::tsc::{cycles_to_micros, rdtsc};
 
-struct SimpleLogger;
+const DMESG_SIZE: usize = 64 * 1024;
 
-impl log::Log for SimpleLogger {
+/// The last `N` bytes of log output, whole lines only, including
+/// what the console filters hide down to `DMESG_LEVEL`.
+struct Dmesg<const N: usize> {
+    buffer: [u8; N],
+    start: usize,
+    length: usize,
+}
+
+impl<const N: usize> Dmesg<N> {
+    const fn new() -> Self {
+        Dmesg {
+            buffer: [0; N],
+            start: 0,
+            length: 0,
+        }
+    }
+
+    fn byte(&self, i: usize) -> u8 {
+        self.buffer[(self.start + i) % N]
+    }
+
+    /// Makes room by dropping the oldest line.
+    fn drop_line(&mut self) {
+        while self.length > 0 {
+            let byte = self.byte(0);
+            self.start = (self.start + 1) % N;
+            self.length -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.length == N {
+            self.drop_line();
+        }
+        self.buffer[(self.start + self.length) % N] = byte;
+        self.length += 1;
+    }
+
+    /// Copies the newest whole lines that fit into `buffer`, oldest first,
+    /// and returns how many bytes that took.
+    fn copy_newest(&self, buffer: &mut [u8]) -> usize {
+        let mut start = self.length.saturating_sub(buffer.len());
+        // Skip what's left of a line that didn't fit.
+        while start > 0 && start < self.length && self.byte(start - 1) != b'\n' {
+            start += 1;
+        }
+        let length = self.length - start;
+        for (i, byte) in buffer[..length].iter_mut().enumerate() {
+            *byte = self.byte(start + i);
+        }
+        length
+    }
+
+    fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        let mut bytes = (0..self.length).map(move |i| self.byte(i)).peekable();
+        core::iter::from_fn(move || {
+            bytes.peek()?;
+            let line: Vec<u8> = bytes.by_ref().take_while(|&byte| byte != b'\n').collect();
+            Some(String::from_utf8_lossy(&line).into_owned())
+        })
+    }
+}
+
+impl<const N: usize> Write for Dmesg<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+static DMESG: Mutex<Dmesg<DMESG_SIZE>> = Mutex::new(Dmesg::new());
+
+/// `LevelFilter` as a number, so the hot path doesn't take a lock.
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// What dmesg keeps besides what the console shows.
+static DMESG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
+
+/// Per module overrides of a level; the longest matching prefix wins.
+struct ModuleLevels {
+    levels: Mutex<Vec<(String, LevelFilter)>>,
+    /// Lets `get` skip the lock while there are no overrides.
+    any: AtomicBool,
+}
+
+impl ModuleLevels {
+    const fn new() -> Self {
+        Self {
+            levels: Mutex::new(Vec::new()),
+            any: AtomicBool::new(false),
+        }
+    }
+
+    fn set(&self, module: &str, level: LevelFilter) {
+        without_interrupts(|| {
+            let mut levels = self.levels.lock();
+            match levels.iter_mut().find(|(name, _)| name == module) {
+                Some((_, old_level)) => *old_level = level,
+                None => levels.push((module.to_string(), level)),
+            }
+            self.any.store(true, Ordering::Relaxed);
+        });
+    }
+
+    fn get(&self, target: &str) -> Option<LevelFilter> {
+        if !self.any.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.levels
+            .lock()
+            .iter()
+            .filter(|(module, _)| matches_module(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|&(_, level)| level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        without_interrupts(|| {
+            self.levels
+                .lock()
+                .iter()
+                .map(|&(_, level)| level)
+                .max()
+                .unwrap_or(LevelFilter::Off)
+        })
+    }
+}
+
+static MODULE_LEVELS: ModuleLevels = ModuleLevels::new();
+static DMESG_MODULE_LEVELS: ModuleLevels = ModuleLevels::new();
+
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+pub fn log_level() -> LevelFilter {
+    LEVEL_FILTERS[CONSOLE_LEVEL.load(Ordering::Relaxed)]
+}
+
+pub fn set_log_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+fn dmesg_level() -> LevelFilter {
+    LEVEL_FILTERS[DMESG_LEVEL.load(Ordering::Relaxed)]
+}
+
+pub fn set_dmesg_level(level: LevelFilter) {
+    DMESG_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Lets the `log` macros drop records nothing would keep before formatting
+/// them.
+fn update_max_level() {
+    let modules = MODULE_LEVELS.max().max(DMESG_MODULE_LEVELS.max());
+    log::set_max_level(log_level().max(dmesg_level()).max(modules));
+}
+
+/// `module` is a target such as `takos::pci`; it also covers its submodules.
+pub fn set_module_log_level(module: &str, level: LevelFilter) {
+    MODULE_LEVELS.set(module, level);
+    update_max_level();
+}
+
+/// Like `set_module_log_level`, for what dmesg keeps.
+pub fn set_dmesg_module_level(module: &str, level: LevelFilter) {
+    DMESG_MODULE_LEVELS.set(module, level);
+    update_max_level();
+}
+
+fn matches_module(target: &str, module: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+fn console_level(target: &str) -> LevelFilter {
+    MODULE_LEVELS.get(target).unwrap_or_else(log_level)
+}
+
+fn dmesg_module_level(target: &str) -> LevelFilter {
+    DMESG_MODULE_LEVELS.get(target).unwrap_or_else(dmesg_level)
+}
+
+/// Seconds and microseconds since the TSC started, i.e. roughly since reset.
+struct Timestamp(u64);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:5}.{:06}]", self.0 / 1_000_000, self.0 % 1_000_000)
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+struct TakosLogger;
+
+impl log::Log for TakosLogger {
+    /// dmesg keeps everything the console shows, and more.
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        let (level, target) = (metadata.level(), metadata.target());
+        without_interrupts(|| level <= dmesg_module_level(target) || level <= console_level(target))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+        if !self.enabled(record.metadata()) {
+            return;
         }
+        let timestamp = Timestamp(cycles_to_micros(rdtsc()).unwrap_or(0));
+        let level = level_name(record.level());
+        let target = record.target();
+        without_interrupts(|| {
+            let _ = writeln!(
+                DMESG.lock(),
+                "{} {} {}: {}",
+                timestamp,
+                level,
+                target,
+                record.args()
+            );
+            if record.level() <= console_level(target) {
+                println!("{} {} {}: {}", timestamp, level, target, record.args());
+            }
+        });
     }
 
     fn flush(&self) {}
 }
 
-static LOGGER: SimpleLogger = SimpleLogger;
+static LOGGER: TakosLogger = TakosLogger;
+
+pub fn init(
+    level: LevelFilter,
+    dmesg_level: LevelFilter,
+    module_levels: &[(String, LevelFilter)],
+    dmesg_module_levels: &[(String, LevelFilter)],
+) -> Result<(), SetLoggerError> {
+    set_log_level(level);
+    set_dmesg_level(dmesg_level);
+    for (module, level) in module_levels {
+        set_module_log_level(module, *level);
+    }
+    for (module, level) in dmesg_module_levels {
+        set_dmesg_module_level(module, *level);
+    }
+    log::set_logger(&LOGGER)
+}
+
+initcall! {
+    name: "logger",
+    stage: Console,
+    depends_on: ["framebuffer", "cmdline"],
+    optional: false,
+    run: |_| {
+        let cmdline = cmdline();
+        init(
+            cmdline.log_level,
+            cmdline.dmesg_level,
+            &cmdline.module_log_levels,
+            &cmdline.dmesg_module_levels,
+        )
+        .map_err(|_| "logger already set".into())
+    },
+}
+
+pub fn print_dmesg() {
+    let dmesg = without_interrupts(|| DMESG.lock().lines().collect::<Vec<String>>());
+    for line in dmesg {
+        println!("{}", line);
+    }
+}
+
+/// Copies the newest whole lines that fit into `buffer` and returns how
+/// many bytes that took. Gives up rather than wait if dmesg is locked, and
+/// doesn't allocate, since it's used when panicking.
+pub fn copy_recent_log(buffer: &mut [u8]) -> usize {
+    DMESG
+        .try_lock()
+        .map_or(0, |dmesg| dmesg.copy_newest(buffer))
+}
+
+/// Makes the console one level more verbose, wrapping from trace to errors.
+pub fn cycle_log_level() {
+    let level = match log_level() {
+        LevelFilter::Trace | LevelFilter::Off => LevelFilter::Error,
+        level => LEVEL_FILTERS[level as usize + 1],
+    };
+    set_log_level(level);
+    println!("Console log level: {}", level);
+}
+
+#[test_case]
+fn test_dmesg_drops_whole_lines() {
+    use crate::print;
+    print!("test_dmesg_drops_whole_lines... ");
+
+    let mut dmesg = Dmesg::<64>::new();
+    for i in 0..10 {
+        let _ = writeln!(dmesg, "line {:2}", i);
+    }
+    let lines: Vec<String> = dmesg.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0], "line  2");
+    assert_eq!(lines[7], "line  9");
+    let mut buffer = [0; 20];
+    let length = dmesg.copy_newest(&mut buffer);
+    assert_eq!(&buffer[..length], b"line  8\nline  9\n");
+    assert!(matches_module(
+        "takos::filesystem::fat",
+        "takos::filesystem"
+    ));
+    assert!(!matches_module(
+        "takos::filesystem_extra",
+        "takos::filesystem"
+    ));
 
-pub fn init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info))
+    println!("[ok]");
 }
@@ -7,16 +7,20 @@ use core::{arch::asm, panic::PanicInfo};
 extern crate alloc;
 
 use alloc::string::String;
-use log::info;
-use takobl_api::BootData;
+use log::{info, warn};
+use takobl_api::{BootData, FreeMemoryMap};
 
 use tako_async::{
     executor::Executor,
     timer::{timer_executor, Timer},
     Task,
 };
+use takos::cmdline::cmdline;
+use takos::console::{console_scroll_handler, serial_command_handler, try_print};
+use takos::crash::save_panic_report;
+use takos::gdb::{breakpoint, gdb_enabled};
 use takos::keyboard::{keyboard_driver, KeyboardEvent};
-use takos::{console::console_scroll_handler, RAMDISK_FILESYSTEM};
+use takos::RAMDISK_FILESYSTEM;
 use takos::{hlt_loop, println};
 use takos::{keyboard::get_keyboard_event_receiver, multitask::scheduler::SCHEDULER};
 
@@ -25,7 +29,13 @@ use thingbuf::mpsc::Receiver;
 // This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("Kernel Panic: {}", info);
+    // The panic may have happened with the console held, so the report is
+    // saved first and nothing here waits for a lock.
+    save_panic_report(info);
+    try_print(format_args!("Kernel Panic: {}\n", info));
+    if gdb_enabled() {
+        breakpoint();
+    }
     hlt_loop();
 }
 
@@ -74,8 +84,75 @@ fn empty_task() -> ! {
     }
 }
 
+fn list_directory(path: &str) {
+    println!("{}:", path);
+    for (i, entry) in RAMDISK_FILESYSTEM
+        .get()
+        .unwrap()
+        .dir_iter(path)
+        .unwrap()
+        .enumerate()
+    {
+        println!("{}: {:?}", i, entry);
+    }
+}
+
+fn test_ramdisk() {
+    list_directory("/");
+    list_directory("/efi");
+    list_directory("/efi/boot");
+
+    println!("/test.txt:");
+    println!(
+        "{}",
+        String::from_utf8(
+            RAMDISK_FILESYSTEM
+                .get()
+                .unwrap()
+                .read_file("/test.txt")
+                .unwrap()
+        )
+        .unwrap()
+    );
+}
+
+fn test_threads() {
+    let task_id = SCHEDULER.lock().new_task(5, empty_task);
+    info!("Created child thread");
+    SCHEDULER.lock().switch_to_task(task_id);
+    info!("From main thread");
+    SCHEDULER.lock().switch_to_task(task_id);
+}
+
+/// Runs the demo selected with `test=<name>` on the command line, or the
+/// threads demo if there is none.
+fn run_test(name: &str, free_memory_map: &FreeMemoryMap, executor: &mut Executor) {
+    match name {
+        "cat" => println!("{}", CAT),
+        "memmap" => {
+            println!("Free memory regions:");
+            for region in free_memory_map.iter() {
+                println!("{:016X}-{:016X}", region.start, region.end());
+            }
+        }
+        "ramdisk" => test_ramdisk(),
+        "threads" => test_threads(),
+        "pagefault" => unsafe {
+            *(0xdeadbeef as *mut u8) = 42;
+        },
+        "numbers" => executor.spawn(Task::new(print_numbers())),
+        "keyboard" => {
+            let receiver = get_keyboard_event_receiver();
+            executor.spawn(Task::new(print_keyboard_events(receiver)));
+        }
+        name => warn!("Unknown test `{}`", name),
+    }
+}
+
 #[export_name = "_start"]
 pub extern "C" fn _start(boot_data: &'static mut BootData) -> ! {
+    takos::boot::check_boot_data(boot_data);
+    let free_memory_map = boot_data.free_memory_map.clone();
     takos::init(boot_data);
 
     #[cfg(debug_assertions)]
@@ -83,87 +160,16 @@ pub extern "C" fn _start(boot_data: &'static mut BootData) -> ! {
         asm!("2: jmp 2b");
     }
 
-    // unsafe {
-    //     *(0xdeadbeef as *mut u8) = 42;
-    // }
-
-    // println!("Hello world!");
-    // println!("This is testing!");
-    // println!("{}", CAT);
-
-    // println!("Free memory regions:");
-    // for region in boot_data.free_memory_map.iter() {
-    //     println!("{:016X}-{:016X}", region.start, region.end());
-    // }
-
-    // println!("/:");
-    // for (i, entry) in RAMDISK_FILESYSTEM
-    //     .get()
-    //     .unwrap()
-    //     .dir_iter("/")
-    //     .unwrap()
-    //     .enumerate()
-    // {
-    //     println!("{}: {:?}", i, entry);
-    // }
-
-    // println!("/efi:");
-    // for (i, entry) in RAMDISK_FILESYSTEM
-    //     .get()
-    //     .unwrap()
-    //     .dir_iter("/efi")
-    //     .unwrap()
-    //     .enumerate()
-    // {
-    //     println!("{}: {:?}", i, entry);
-    // }
-
-    // println!("/efi/boot:");
-    // for (i, entry) in RAMDISK_FILESYSTEM
-    //     .get()
-    //     .unwrap()
-    //     .dir_iter("/efi/boot")
-    //     .unwrap()
-    //     .enumerate()
-    // {
-    //     println!("{}: {:?}", i, entry);
-    // }
-
-    // println!("/test.txt:");
-    // println!(
-    //     "{}",
-    //     String::from_utf8(
-    //         RAMDISK_FILESYSTEM
-    //             .get()
-    //             .unwrap()
-    //             .read_file("/test.txt")
-    //             .unwrap()
-    //     )
-    //     .unwrap()
-    // );
-
-    let task_id = SCHEDULER.lock().new_task(5, empty_task);
-    info!("Created child thread");
-    SCHEDULER.lock().switch_to_task(task_id);
-    info!("From main thread");
-    SCHEDULER.lock().switch_to_task(task_id);
+    if let Some(init) = cmdline().init.as_ref() {
+        warn!("No userspace yet, ignoring init={}", init);
+    }
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(timer_executor()));
-    // executor.spawn(Task::new(print_numbers()));
     executor.spawn(Task::new(keyboard_driver()));
     executor.spawn(Task::new(console_scroll_handler()));
-    // let reciever = get_keyboard_event_receiver();
-    // executor.spawn(Task::new(print_keyboard_events(reciever)));
+    executor.spawn(Task::new(serial_command_handler()));
+    let test = cmdline().test.as_deref().unwrap_or("threads");
+    run_test(test, &free_memory_map, &mut executor);
     executor.run();
-
-    // println!("Allocating more memory!");
-    // for i in 0..10000 {
-    //   let frame = FRAME_ALLOCATOR.lock().allocate_frame().expect("Couldn't allocate");
-    //   let addr = 0x4000_1000_0000 + i * 0x1000;
-    //   map_writable_page(addr, frame);
-    // }
-    // println!("Success!");
-
-    // hlt_loop();
 }
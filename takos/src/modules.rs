@@ -0,0 +1,79 @@
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use log::info;
+use takobl_api::tags::Tag;
+use takobl_api::{BootData, BootSlice};
+
+use crate::initcall::{initcall, InitResult};
+use crate::println;
+
+/// A file takobl loaded next to the kernel, such as the initrd. takobl
+/// decompresses modules before handing them over.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub name: &'static str,
+    data: BootSlice,
+}
+
+impl Module {
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { self.data.as_slice() }
+    }
+
+    /// # Safety
+    /// Only one user may hold the module's data mutably, and nobody else may
+    /// read it meanwhile.
+    pub unsafe fn data_mut(&self) -> &'static mut [u8] {
+        self.data.as_mut_slice()
+    }
+
+    pub fn address(&self) -> u64 {
+        self.data.addr
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len as usize
+    }
+}
+
+static MODULES: OnceCell<Vec<Module>> = OnceCell::uninit();
+
+pub fn init_modules(boot_data: &'static BootData) -> InitResult {
+    let modules: Vec<Module> = boot_data
+        .tags()
+        .filter_map(|tag| match tag {
+            Tag::Module { name, data } => Some(Module { name, data }),
+            _ => None,
+        })
+        .collect();
+    info!("{} boot module(s)", modules.len());
+    MODULES.init_once(|| modules);
+    Ok(())
+}
+
+initcall! {
+    name: "boot-modules",
+    stage: Firmware,
+    depends_on: [],
+    optional: false,
+    run: init_modules,
+}
+
+pub fn modules() -> &'static [Module] {
+    MODULES.get().map_or(&[], |modules| modules.as_slice())
+}
+
+pub fn find_module(name: &str) -> Option<&'static Module> {
+    modules().iter().find(|module| module.name == name)
+}
+
+pub fn print_modules() {
+    for module in modules() {
+        println!(
+            "{:16} {:016X} {:>10} bytes",
+            module.name,
+            module.address(),
+            module.size()
+        );
+    }
+}
@@ -7,12 +7,15 @@ use alloc::vec::Vec;
 use spin::Mutex;
 use x86_64::registers::control::Cr3;
 
+use crate::fpu::FpuState;
+
 type TaskId = usize;
 type Page = [u64; 512];
 
 struct TaskData {
     kernel_stack_top: u64,
     cr3_value: u64,
+    fpu_state: FpuState,
     #[allow(unused)]
     stack: Pin<Box<[Page]>>,
 }
@@ -34,6 +37,7 @@ impl Scheduler {
         self.tasks = vec![TaskData {
             kernel_stack_top: 0,
             cr3_value: Cr3::read().0.start_address().as_u64(),
+            fpu_state: FpuState::new(),
             stack: Box::pin([]),
         }];
     }
@@ -61,6 +65,7 @@ impl Scheduler {
         self.tasks.push(TaskData {
             kernel_stack_top,
             cr3_value: Cr3::read().0.start_address().as_u64(),
+            fpu_state: FpuState::new(),
             stack,
         });
         id
@@ -90,6 +95,11 @@ impl Scheduler {
 
     pub fn switch_to_task(&mut self, task_id: TaskId) {
         x86_64::instructions::interrupts::without_interrupts(|| {
+            // FPU state is switched eagerly; the kernel itself doesn't use
+            // it, so nothing clobbers it between here and the stack switch.
+            self.tasks[self.current_task].fpu_state.save();
+            self.tasks[task_id].fpu_state.restore();
+
             let new_cr3 = self.tasks[task_id].cr3_value;
             let new_rsp = self.tasks[task_id].kernel_stack_top;
             let old_rsp = &mut self.tasks[self.current_task].kernel_stack_top;
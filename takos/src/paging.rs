@@ -1,10 +1,17 @@
+use alloc::format;
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use lazy_static::lazy_static;
 use spin::Mutex;
 use takobl_api::{MemoryRegion, PHYSICAL_MEMORY_OFFSET};
 use x86_64::registers::control::Cr3;
 use x86_64::registers::model_specific::Msr;
-use x86_64::structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB};
-use x86_64::VirtAddr;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::cpu::{has_feature, CpuFeatures};
+use crate::initcall::{initcall, InitResult};
+use crate::random::random_u64;
 
 lazy_static! {
     pub static ref PAGE_TABLE: Mutex<OffsetPageTable<'static>> = unsafe {
@@ -39,7 +46,102 @@ pub fn map_writable_page(virtual_address: u64, frame: PhysFrame) {
     }
 }
 
-pub fn unmap_loader_code(loader_code: MemoryRegion) {
+const MMIO_REGION_START: u64 = 0xFFFF_F000_0000_0000;
+const MMIO_REGION_SIZE: u64 = 0x100_0000_0000;
+const MMIO_ALIGN: u64 = 2 * 1024 * 1024;
+/// Leaves the rest of the region for whatever is mapped after the base is chosen.
+const MMIO_RANDOM_RANGE: u64 = MMIO_REGION_SIZE / 2;
+
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_REGION_START);
+
+/// Picks a random base for `reserve_mmio`. Must run before the first mapping.
+pub fn init_mmio() -> InitResult {
+    let base = MMIO_REGION_START + random_u64() % (MMIO_RANDOM_RANGE / MMIO_ALIGN) * MMIO_ALIGN;
+    MMIO_NEXT
+        .compare_exchange(
+            MMIO_REGION_START,
+            base,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        )
+        .map_err(|_| "MMIO region already in use")?;
+    Ok(())
+}
+
+initcall! {
+    name: "mmio",
+    stage: Memory,
+    depends_on: ["frame-allocator"],
+    optional: false,
+    run: |_| init_mmio(),
+}
+
+/// Reserves `pages` pages of virtual address space in the MMIO region.
+pub fn reserve_mmio(pages: u64) -> u64 {
+    let virtual_start = MMIO_NEXT.fetch_add(pages * 0x1000, Ordering::Relaxed);
+    assert!(
+        virtual_start + pages * 0x1000 <= MMIO_REGION_START + MMIO_REGION_SIZE,
+        "MMIO region exhausted"
+    );
+    virtual_start
+}
+
+/// Maps `size` bytes of device memory at `physical_address` as uncacheable
+/// and returns the virtual address corresponding to `physical_address`.
+pub fn map_mmio(physical_address: u64, size: u64) -> u64 {
+    use crate::allocator::frame_allocator::FRAME_ALLOCATOR;
+    use x86_64::structures::paging::{Mapper, Page, PageTableFlags};
+
+    let offset = physical_address & 0xFFF;
+    let pages = (offset + size + 0xFFF) / 0x1000;
+    let virtual_start = reserve_mmio(pages);
+    let flags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::NO_CACHE)
+        .union(PageTableFlags::WRITE_THROUGH)
+        .union(PageTableFlags::NO_EXECUTE);
+    let mut page_table = PAGE_TABLE.lock();
+    for i in 0..pages {
+        let virt = virtual_start + i * 0x1000;
+        let phys = (physical_address & !0xFFF) + i * 0x1000;
+        unsafe {
+            page_table
+                .map_to(
+                    Page::<Size4KiB>::from_start_address(VirtAddr::new(virt)).unwrap(),
+                    PhysFrame::from_start_address(PhysAddr::new(phys)).unwrap(),
+                    flags,
+                    &mut *FRAME_ALLOCATOR.lock(),
+                )
+                .expect("Failed to map MMIO")
+                .flush();
+        }
+    }
+    virtual_start + offset
+}
+
+/// Whether every page of the range is mapped, for code that can't afford a
+/// page fault. Says no if the page table is locked.
+pub fn is_mapped(address: u64, length: u64) -> bool {
+    let Some(page_table) = PAGE_TABLE.try_lock() else {
+        return false;
+    };
+    let Some(end) = address.checked_add(length) else {
+        return false;
+    };
+    let mut page = address & !0xFFF;
+    while page < end {
+        let mapped = VirtAddr::try_new(page)
+            .map(|page| page_table.translate_addr(page).is_some())
+            .unwrap_or(false);
+        if !mapped {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+pub fn unmap_loader_code(loader_code: MemoryRegion) -> InitResult {
     use x86_64::structures::paging::{Mapper, Page};
 
     let mut page_table = PAGE_TABLE.lock();
@@ -47,17 +149,40 @@ pub fn unmap_loader_code(loader_code: MemoryRegion) {
         let addr = loader_code.start + page * 0x1000;
         page_table
             .unmap(Page::<Size4KiB>::from_start_address(VirtAddr::new(addr)).unwrap())
-            .expect("Failed to unmap")
+            .map_err(|error| format!("couldn't unmap {:016X}: {:?}", addr, error))?
             .1
             .flush();
     }
+    Ok(())
+}
+
+initcall! {
+    name: "unmap-loader",
+    stage: Firmware,
+    depends_on: ["boot-device", "boot-modules"],
+    optional: false,
+    run: |boot_data| unmap_loader_code(boot_data.loader_code),
 }
 
-pub fn init_pat() {
+pub fn init_pat() -> InitResult {
+    // Without PAT, PWT alone selects write-through, which is still usable
+    // for the frame buffer.
+    if !has_feature(CpuFeatures::PAT) {
+        return Ok(());
+    }
     let mut pat = Msr::new(0x277);
     unsafe {
         pat.write(0x00_07_04_06_00_07_01_06);
     }
+    Ok(())
+}
+
+initcall! {
+    name: "pat",
+    stage: Cpu,
+    depends_on: ["cpu", "idt"],
+    optional: false,
+    run: |_| init_pat(),
 }
 
 #[test_case]
@@ -6,9 +6,29 @@ use log::{error, info};
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
+use crate::apic::MSI_ADDRESS_BASE;
+use crate::initcall::{initcall, InitResult};
+use crate::interrupts::{allocate_irq, allocate_irq_block, free_irq, irq::irq_to_vector, Irq};
+use crate::paging::map_mmio;
 use crate::println;
 
-#[derive(Debug, Copy, Clone)]
+const COMMAND_REGISTER: u8 = 0x04;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_CONTROL_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 struct PciDeviceHandle {
     bus_number: u8,
     device_number: Option<u8>,
@@ -48,6 +68,20 @@ pub enum Header {
     Unknown(u8),
 }
 
+#[derive(Debug, Copy, Clone)]
+pub struct PciCapability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MsiError {
+    NotSupported,
+    TooManyVectors,
+    NoFreeVectors,
+    InvalidBar(u8),
+}
+
 #[derive(Debug, Clone)]
 pub struct PciDevice {
     handle: PciDeviceHandle,
@@ -101,6 +135,34 @@ impl PciDeviceHandle {
         }
     }
 
+    fn config_write(&self, address: u8, value: u32) {
+        assert!(address & 0x3 == 0);
+        let mut address_port = Port::<u32>::new(0xCF8);
+        let mut data_port = Port::<u32>::new(0xCFC);
+        let address_value = 0x80000000
+            | (self.bus_number as u32) << 16
+            | (self.device_number.unwrap_or(0) as u32) << 11
+            | (self.function_number.unwrap_or(0) as u32) << 8
+            | (address as u32);
+        unsafe {
+            address_port.write(address_value);
+            data_port.write(value);
+        }
+    }
+
+    fn config_read_u16(&self, address: u8) -> u16 {
+        assert!(address & 0x1 == 0);
+        (self.config_read(address & !0x3) >> ((address & 0x2) * 8)) as u16
+    }
+
+    fn config_write_u16(&self, address: u8, value: u16) {
+        assert!(address & 0x1 == 0);
+        let shift = (address & 0x2) * 8;
+        let old = self.config_read(address & !0x3);
+        let new = old & !(0xFFFF << shift) | (value as u32) << shift;
+        self.config_write(address & !0x3, new);
+    }
+
     fn header_type(&self) -> u8 {
         (self.config_read(0x0C) >> 16) as u8
     }
@@ -218,6 +280,184 @@ impl PciDeviceHandle {
     }
 }
 
+impl PciDevice {
+    pub fn capabilities(&self) -> Vec<PciCapability> {
+        let mut result = Vec::new();
+        let status = self.handle.config_read_u16(0x06);
+        if status & STATUS_CAPABILITIES_LIST == 0 {
+            return result;
+        }
+        let mut offset = self.handle.config_read(CAPABILITIES_POINTER) as u8 & !0x3;
+        // The list lives in the first 256 bytes, so 48 entries is a safe bound
+        // against malformed loops.
+        while offset != 0 && result.len() < 48 {
+            let header = self.handle.config_read(offset);
+            result.push(PciCapability {
+                id: header as u8,
+                offset,
+            });
+            offset = (header >> 8) as u8 & !0x3;
+        }
+        result
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<PciCapability> {
+        self.capabilities().into_iter().find(|cap| cap.id == id)
+    }
+
+    fn update_command(&self, set: u16, clear: u16) {
+        let command = self.handle.config_read_u16(COMMAND_REGISTER);
+        self.handle
+            .config_write_u16(COMMAND_REGISTER, command & !clear | set);
+    }
+
+    fn msi_message(apic_id: u8, irq: Irq) -> (u64, u32) {
+        (
+            MSI_ADDRESS_BASE | (apic_id as u64) << 12,
+            irq_to_vector(irq) as u32,
+        )
+    }
+
+    /// Enables MSI with `count` vectors delivered to the local APIC `apic_id`.
+    /// `count` is rounded up to a power of two; the returned IRQs are
+    /// consecutive, message N raises the N-th one.
+    pub fn enable_msi(&self, apic_id: u8, count: usize) -> Result<Vec<Irq>, MsiError> {
+        let cap = self
+            .find_capability(CAPABILITY_MSI)
+            .ok_or(MsiError::NotSupported)?;
+        let control_offset = cap.offset + 2;
+        let control = self.handle.config_read_u16(control_offset);
+        let count = count.max(1).next_power_of_two();
+        let max_count = 1usize << ((control >> 1) & 0x7);
+        if count > max_count {
+            return Err(MsiError::TooManyVectors);
+        }
+        let first_irq = allocate_irq_block(count).ok_or(MsiError::NoFreeVectors)?;
+
+        let (address, data) = Self::msi_message(apic_id, first_irq);
+        self.handle.config_write(cap.offset + 4, address as u32);
+        let data_offset = if control & MSI_CONTROL_64BIT != 0 {
+            self.handle
+                .config_write(cap.offset + 8, (address >> 32) as u32);
+            cap.offset + 0x0C
+        } else {
+            cap.offset + 0x08
+        };
+        self.handle.config_write_u16(data_offset, data as u16);
+
+        let multiple_message_enable = count.trailing_zeros() as u16;
+        let control = control & !(0x7 << 4) | multiple_message_enable << 4 | MSI_CONTROL_ENABLE;
+        self.handle.config_write_u16(control_offset, control);
+        self.update_command(COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE, 0);
+
+        Ok((first_irq..first_irq + count as Irq).collect())
+    }
+
+    pub fn disable_msi(&self, irqs: &[Irq]) {
+        if let Some(cap) = self.find_capability(CAPABILITY_MSI) {
+            let control = self.handle.config_read_u16(cap.offset + 2);
+            self.handle
+                .config_write_u16(cap.offset + 2, control & !MSI_CONTROL_ENABLE);
+        }
+        for &irq in irqs {
+            free_irq(irq);
+        }
+        self.update_command(0, COMMAND_INTERRUPT_DISABLE);
+    }
+
+    fn msix_table(&self, cap: PciCapability) -> Result<(u64, usize), MsiError> {
+        let control = self.handle.config_read_u16(cap.offset + 2);
+        let table_size = (control & 0x7FF) as usize + 1;
+        let table = self.handle.config_read(cap.offset + 4);
+        let bir = (table & 0x7) as u8;
+        let offset = (table & !0x7) as u64;
+        let bar = match &self.header {
+            Header::Header0(header) => header.bars.get(bir as usize).copied(),
+            _ => None,
+        };
+        let physical = match bar {
+            Some(BaseAddressRegister::Memory(addr)) if addr != 0 => addr + offset,
+            _ => return Err(MsiError::InvalidBar(bir)),
+        };
+        // Mappings are never undone, so each table is mapped once.
+        let mut tables = MSIX_TABLES.lock();
+        let table = match tables.iter_mut().find(|table| table.0 == self.handle) {
+            Some(table) if table.1 == physical => table.2,
+            table => {
+                let virt = map_mmio(physical, table_size as u64 * MSIX_ENTRY_SIZE);
+                match table {
+                    Some(table) => *table = (self.handle, physical, virt),
+                    None => tables.push((self.handle, physical, virt)),
+                }
+                virt
+            }
+        };
+        Ok((table, table_size))
+    }
+
+    /// Enables MSI-X with `count` vectors delivered to the local APIC
+    /// `apic_id`. Table entry N raises the N-th returned IRQ.
+    pub fn enable_msix(&self, apic_id: u8, count: usize) -> Result<Vec<Irq>, MsiError> {
+        let cap = self
+            .find_capability(CAPABILITY_MSIX)
+            .ok_or(MsiError::NotSupported)?;
+        let (table, table_size) = self.msix_table(cap)?;
+        if count > table_size {
+            return Err(MsiError::TooManyVectors);
+        }
+
+        let control_offset = cap.offset + 2;
+        let control = self.handle.config_read_u16(control_offset);
+        self.handle.config_write_u16(
+            control_offset,
+            control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK,
+        );
+
+        let mut irqs = Vec::with_capacity(count);
+        for entry in 0..table_size {
+            let entry_addr = (table + entry as u64 * MSIX_ENTRY_SIZE) as *mut u32;
+            if entry >= count {
+                unsafe { entry_addr.add(3).write_volatile(MSIX_VECTOR_CONTROL_MASKED) };
+                continue;
+            }
+            let Some(irq) = allocate_irq() else {
+                for &irq in irqs.iter() {
+                    free_irq(irq);
+                }
+                self.handle.config_write_u16(control_offset, control);
+                return Err(MsiError::NoFreeVectors);
+            };
+            let (address, data) = Self::msi_message(apic_id, irq);
+            unsafe {
+                entry_addr.write_volatile(address as u32);
+                entry_addr.add(1).write_volatile((address >> 32) as u32);
+                entry_addr.add(2).write_volatile(data);
+                entry_addr.add(3).write_volatile(0);
+            }
+            irqs.push(irq);
+        }
+
+        self.handle.config_write_u16(
+            control_offset,
+            (control | MSIX_CONTROL_ENABLE) & !MSIX_CONTROL_FUNCTION_MASK,
+        );
+        self.update_command(COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE, 0);
+        Ok(irqs)
+    }
+
+    pub fn disable_msix(&self, irqs: &[Irq]) {
+        if let Some(cap) = self.find_capability(CAPABILITY_MSIX) {
+            let control = self.handle.config_read_u16(cap.offset + 2);
+            self.handle
+                .config_write_u16(cap.offset + 2, control & !MSIX_CONTROL_ENABLE);
+        }
+        for &irq in irqs {
+            free_irq(irq);
+        }
+        self.update_command(0, COMMAND_INTERRUPT_DISABLE);
+    }
+}
+
 fn enumerate_all() -> Vec<PciDevice> {
     let main_bus = PciDeviceHandle::new_bus(0);
     let header_type = main_bus.header_type();
@@ -279,10 +519,21 @@ impl Display for PciDevice {
 }
 
 pub static PCI_DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(Vec::new());
+/// MSI-X tables mapped so far, as (device, physical, virtual) addresses.
+static MSIX_TABLES: Mutex<Vec<(PciDeviceHandle, u64, u64)>> = Mutex::new(Vec::new());
 
-pub fn init_pci() {
+pub fn init_pci() -> InitResult {
     *PCI_DEVICES.lock() = enumerate_all();
     for device in PCI_DEVICES.lock().iter() {
         info!("Found device {}", device);
     }
+    Ok(())
+}
+
+initcall! {
+    name: "pci",
+    stage: Devices,
+    depends_on: [],
+    optional: false,
+    run: |_| init_pci(),
 }
@@ -1,5 +1,7 @@
 use spin::Mutex;
-use x86_64::instructions::port::PortWriteOnly;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+use crate::initcall::{initcall, InitResult};
 
 const MASTER_PIC_COMMAND_PORT: u16 = 0x20;
 const MASTER_PIC_DATA_PORT: u16 = 0x21;
@@ -7,24 +9,48 @@ const SLAVE_PIC_COMMAND_PORT: u16 = 0xA0;
 const SLAVE_PIC_DATA_PORT: u16 = 0xA1;
 pub const MASTER_PIC_OFFSET: u8 = 32;
 pub const SLAVE_PIC_OFFSET: u8 = MASTER_PIC_OFFSET + 8;
+pub const PIC_IRQ_COUNT: u8 = 16;
+const CASCADE_IRQ: u8 = 2;
 
 pub struct PicChain {
-    master_command: PortWriteOnly<u8>,
+    master_command: Port<u8>,
     master_data: PortWriteOnly<u8>,
-    slave_command: PortWriteOnly<u8>,
+    slave_command: Port<u8>,
     slave_data: PortWriteOnly<u8>,
+    mask: u16,
 }
 
 impl PicChain {
     const fn new() -> Self {
         PicChain {
-            master_command: PortWriteOnly::new(MASTER_PIC_COMMAND_PORT),
+            master_command: Port::new(MASTER_PIC_COMMAND_PORT),
             master_data: PortWriteOnly::new(MASTER_PIC_DATA_PORT),
-            slave_command: PortWriteOnly::new(SLAVE_PIC_COMMAND_PORT),
+            slave_command: Port::new(SLAVE_PIC_COMMAND_PORT),
             slave_data: PortWriteOnly::new(SLAVE_PIC_DATA_PORT),
+            mask: !(1 << CASCADE_IRQ),
+        }
+    }
+
+    fn write_mask(&mut self) {
+        unsafe {
+            self.master_data.write(self.mask as u8);
+            self.slave_data.write((self.mask >> 8) as u8);
         }
     }
 
+    pub fn set_masked(&mut self, irq: u8, masked: bool) {
+        assert!(irq < PIC_IRQ_COUNT);
+        if irq == CASCADE_IRQ {
+            return;
+        }
+        if masked {
+            self.mask |= 1 << irq;
+        } else {
+            self.mask &= !(1 << irq);
+        }
+        self.write_mask();
+    }
+
     fn init(&mut self) {
         unsafe {
             self.master_command.write(0x11);
@@ -36,6 +62,34 @@ impl PicChain {
             self.master_data.write(1);
             self.slave_data.write(1);
         }
+        self.write_mask();
+    }
+
+    fn read_in_service(&mut self) -> u16 {
+        unsafe {
+            self.master_command.write(0x0B);
+            self.slave_command.write(0x0B);
+            (self.slave_command.read() as u16) << 8 | self.master_command.read() as u16
+        }
+    }
+
+    /// IRQ 7 and 15 are raised spuriously when a request goes away before it
+    /// is acknowledged, in which case the in-service bit stays clear.
+    pub fn is_spurious(&mut self, irq: u8) -> bool {
+        match irq {
+            7 | 15 => self.read_in_service() & (1 << irq) == 0,
+            _ => false,
+        }
+    }
+
+    /// The master still saw a real interrupt on the cascade line when the
+    /// slave's was spurious, so it needs its EOI.
+    pub fn notify_spurious(&mut self, irq: u8) {
+        if irq >= 8 {
+            unsafe {
+                self.master_command.write(0x20);
+            }
+        }
     }
 
     pub fn notify_end_of_interrupt(&mut self, irq: u8) {
@@ -50,6 +104,15 @@ impl PicChain {
 
 pub static PICS: Mutex<PicChain> = Mutex::new(PicChain::new());
 
-pub fn init_pics() {
+pub fn init_pics() -> InitResult {
     PICS.lock().init();
+    Ok(())
+}
+
+initcall! {
+    name: "pic",
+    stage: Interrupts,
+    depends_on: [],
+    optional: false,
+    run: |_| init_pics(),
 }
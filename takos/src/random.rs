@@ -0,0 +1,188 @@
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use log::info;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::cpu::{has_feature, CpuFeatures};
+use crate::initcall::{initcall, InitResult};
+
+use self::chacha20::{chacha20_block, BLOCK_SIZE, KEY_SIZE};
+
+mod chacha20;
+
+const HARDWARE_RETRIES: usize = 10;
+/// Bytes handed out before fresh entropy is mixed into the key.
+const RESEED_INTERVAL: u64 = 1024 * 1024;
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..HARDWARE_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..HARDWARE_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn hardware_random() -> Option<u64> {
+    if has_feature(CpuFeatures::RDSEED) {
+        if let Some(value) = rdseed() {
+            return Some(value);
+        }
+    }
+    if has_feature(CpuFeatures::RDRAND) {
+        return rdrand();
+    }
+    None
+}
+
+// Execution time of a few memory accesses varies with cache and pipeline
+// state; only the low bits of each measurement carry anything useful.
+fn tsc_jitter() -> u64 {
+    let buffer = [0u8; 256];
+    let mut result = 0u64;
+    for i in 0..64 {
+        let start = unsafe { _rdtsc() };
+        let index = (result as usize ^ i) % buffer.len();
+        unsafe { core::ptr::read_volatile(&buffer[index]) };
+        let delta = unsafe { _rdtsc() }.wrapping_sub(start);
+        result = result.rotate_left(7) ^ delta;
+    }
+    result
+}
+
+static INTERRUPT_POOL: AtomicU64 = AtomicU64::new(0);
+static INTERRUPT_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Called for every interrupt with its arrival time.
+pub fn add_interrupt_entropy(vector: u8, timestamp: u64) {
+    let sample = (timestamp ^ (vector as u64) << 56).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let pool = INTERRUPT_POOL.load(Ordering::Relaxed);
+    INTERRUPT_POOL.store(pool.rotate_left(13) ^ sample, Ordering::Relaxed);
+    INTERRUPT_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn collect_seed() -> [u8; KEY_SIZE] {
+    let mut seed = [0u8; KEY_SIZE];
+    for chunk in seed.chunks_exact_mut(8) {
+        let word =
+            hardware_random().unwrap_or(0) ^ tsc_jitter() ^ INTERRUPT_POOL.load(Ordering::Relaxed);
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    seed
+}
+
+/// ChaCha20 keystream generator. The key is replaced with keystream after
+/// every request, so a leaked state doesn't reveal earlier output.
+pub struct ChaCha20Rng {
+    key: [u8; KEY_SIZE],
+    counter: u32,
+    bytes_since_reseed: u64,
+}
+
+impl ChaCha20Rng {
+    pub fn from_seed(seed: [u8; KEY_SIZE]) -> Self {
+        Self {
+            key: seed,
+            counter: 0,
+            bytes_since_reseed: 0,
+        }
+    }
+
+    fn next_block(&mut self) -> [u8; BLOCK_SIZE] {
+        let block = chacha20_block(&self.key, self.counter, &[0; 12]);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[..KEY_SIZE]);
+        self.counter = 0;
+    }
+
+    pub fn reseed(&mut self, seed: [u8; KEY_SIZE]) {
+        for (key, seed) in self.key.iter_mut().zip(seed) {
+            *key ^= seed;
+        }
+        self.rekey();
+        self.bytes_since_reseed = 0;
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(BLOCK_SIZE) {
+            let block = self.next_block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.rekey();
+        self.bytes_since_reseed += dest.len() as u64;
+    }
+}
+
+lazy_static! {
+    static ref RNG: Mutex<ChaCha20Rng> = Mutex::new(ChaCha20Rng::from_seed(collect_seed()));
+}
+
+pub fn init_random() -> InitResult {
+    let source = if has_feature(CpuFeatures::RDSEED) {
+        "RDSEED"
+    } else if has_feature(CpuFeatures::RDRAND) {
+        "RDRAND"
+    } else {
+        "TSC jitter"
+    };
+    without_interrupts(|| RNG.lock().reseed(collect_seed()));
+    info!(
+        "Random: seeded from {} and {} interrupts",
+        source,
+        INTERRUPT_EVENTS.load(Ordering::Relaxed)
+    );
+    Ok(())
+}
+
+initcall! {
+    name: "random",
+    stage: Interrupts,
+    depends_on: ["interrupts"],
+    optional: false,
+    run: |_| init_random(),
+}
+
+pub fn fill_bytes(dest: &mut [u8]) {
+    without_interrupts(|| {
+        let mut rng = RNG.lock();
+        if rng.bytes_since_reseed >= RESEED_INTERVAL {
+            rng.reseed(collect_seed());
+        }
+        rng.fill_bytes(dest);
+    })
+}
+
+pub fn random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,70 @@
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+pub const KEY_SIZE: usize = 32;
+pub const BLOCK_SIZE: usize = 64;
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function from RFC 7539.
+pub fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; 12]) -> [u8; BLOCK_SIZE] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    for (i, word) in key.chunks_exact(4).enumerate() {
+        input[4 + i] = u32::from_le_bytes(word.try_into().unwrap());
+    }
+    input[12] = counter;
+    for (i, word) in nonce.chunks_exact(4).enumerate() {
+        input[13 + i] = u32::from_le_bytes(word.try_into().unwrap());
+    }
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    let mut output = [0u8; BLOCK_SIZE];
+    for (i, chunk) in output.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    output
+}
+
+#[test_case]
+fn test_chacha20_block() {
+    use crate::{print, println};
+    print!("test_chacha20_block... ");
+
+    // RFC 7539, section 2.3.2
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4A, 0, 0, 0, 0];
+    let block = chacha20_block(&key, 1, &nonce);
+    let expected: [u8; BLOCK_SIZE] = [
+        0x10, 0xF1, 0xE7, 0xE4, 0xD1, 0x3B, 0x59, 0x15, 0x50, 0x0F, 0xDD, 0x1F, 0xA3, 0x20, 0x71,
+        0xC4, 0xC7, 0xD1, 0xF4, 0xC7, 0x33, 0xC0, 0x68, 0x03, 0x04, 0x22, 0xAA, 0x9A, 0xC3, 0xD4,
+        0x6C, 0x4E, 0xD2, 0x82, 0x64, 0x46, 0x07, 0x9F, 0xAA, 0x09, 0x14, 0xC2, 0xD7, 0x05, 0xD9,
+        0x8B, 0x02, 0xA2, 0xB5, 0x12, 0x9C, 0xD1, 0xDE, 0x16, 0x4E, 0xB9, 0xCB, 0xD0, 0x83, 0xE8,
+        0xA2, 0x50, 0x3C, 0x4E,
+    ];
+    assert_eq!(block, expected);
+
+    println!("[ok]");
+}
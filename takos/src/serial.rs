@@ -0,0 +1,274 @@
+use alloc::format;
+use core::fmt::{self, Write};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use futures::Stream;
+use spin::Mutex;
+use thingbuf::StaticThingBuf;
+use x86_64::instructions::port::Port;
+
+use crate::gdb::{handle_interrupt as handle_gdb_interrupt, is_gdb_interrupt};
+use crate::initcall::initcall;
+use crate::interrupts::irq::IrqError;
+use crate::interrupts::{register_irq, InterruptFrame, IrqReturn};
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+const UART_CLOCK: u32 = 115_200;
+const BAUD_RATE: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+}
+
+impl ComPort {
+    const ALL: [ComPort; 2] = [ComPort::Com1, ComPort::Com2];
+
+    fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+        }
+    }
+
+    fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 => 4,
+            ComPort::Com2 => 3,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A 16550 compatible UART.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialPort {
+    port: ComPort,
+}
+
+impl SerialPort {
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.port.base() + offset)
+    }
+
+    /// Programs 8N1 at `BAUD_RATE` and checks that the UART echoes a byte in
+    /// loopback mode, which an absent port doesn't.
+    pub fn probe(port: ComPort) -> Option<Self> {
+        let serial = SerialPort { port };
+        let divisor = (UART_CLOCK / BAUD_RATE) as u16;
+        unsafe {
+            serial.register(INTERRUPT_ENABLE).write(0x00);
+            serial.register(LINE_CONTROL).write(0x80); // Divisor latch
+            serial.register(DATA).write(divisor as u8);
+            serial
+                .register(INTERRUPT_ENABLE)
+                .write((divisor >> 8) as u8);
+            serial.register(LINE_CONTROL).write(0x03); // 8N1
+            serial.register(FIFO_CONTROL).write(0xC7); // FIFO, 14 byte threshold
+            serial.register(MODEM_CONTROL).write(0x1E); // Loopback
+            serial.register(DATA).write(0xAE);
+            if serial.register(DATA).read() != 0xAE {
+                return None;
+            }
+            // DTR, RTS and OUT2, which gates the IRQ line.
+            serial.register(MODEM_CONTROL).write(0x0B);
+        }
+        Some(serial)
+    }
+
+    /// A port `init_serial` found, without reprogramming it.
+    pub fn open(port: ComPort) -> Option<Self> {
+        PRESENT[port.index()]
+            .load(Ordering::Relaxed)
+            .then_some(SerialPort { port })
+    }
+
+    pub fn com_port(&self) -> ComPort {
+        self.port
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            while self.register(LINE_STATUS).read() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.register(DATA).write(byte);
+        }
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            if self.register(LINE_STATUS).read() & LINE_STATUS_DATA_READY == 0 {
+                return None;
+            }
+            Some(self.register(DATA).read())
+        }
+    }
+
+    fn enable_receive_interrupt(&mut self) {
+        unsafe { self.register(INTERRUPT_ENABLE).write(0x01) };
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// The port console output is mirrored to.
+pub static SERIAL: Mutex<Option<SerialPort>> = Mutex::new(None);
+
+static PRESENT: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+static RECEIVE_QUEUES: [StaticThingBuf<u8, 256>; 2] = [const { StaticThingBuf::new() }; 2];
+static WAKERS: [AtomicWaker; 2] = [const { AtomicWaker::new() }; 2];
+static STREAM_TAKEN: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+
+/// Probes both ports; the first one that answers becomes the console.
+pub fn init_serial() -> Result<ComPort, &'static str> {
+    for port in ComPort::ALL {
+        if SerialPort::probe(port).is_some() {
+            PRESENT[port.index()].store(true, Ordering::Relaxed);
+        }
+    }
+    let port = ComPort::ALL
+        .into_iter()
+        .find(|port| PRESENT[port.index()].load(Ordering::Relaxed))
+        .ok_or("no UART found")?;
+    *SERIAL.lock() = Some(SerialPort { port });
+    Ok(port)
+}
+
+initcall! {
+    name: "serial",
+    stage: Memory,
+    depends_on: ["heap"],
+    optional: true,
+    run: |_| init_serial().map(|_| ()).map_err(Into::into),
+}
+
+/// Doesn't lock `SERIAL`, so fault handlers can ask.
+pub fn serial_ready() -> bool {
+    PRESENT
+        .iter()
+        .any(|present| present.load(Ordering::Relaxed))
+}
+
+pub fn console_port() -> Option<ComPort> {
+    SERIAL.lock().map(|serial| serial.com_port())
+}
+
+fn receive(port: ComPort, frame: &mut InterruptFrame) {
+    let mut serial = SerialPort { port };
+    let queue = &RECEIVE_QUEUES[port.index()];
+    while let Some(byte) = serial.read_byte() {
+        if is_gdb_interrupt(port, byte) {
+            handle_gdb_interrupt(frame);
+            continue;
+        }
+        // Drop input nobody reads rather than stall the UART.
+        let _ = queue.push(byte);
+    }
+    WAKERS[port.index()].wake();
+}
+
+fn com1_handler(frame: &mut InterruptFrame) -> IrqReturn {
+    receive(ComPort::Com1, frame);
+    IrqReturn::Handled
+}
+
+fn com2_handler(frame: &mut InterruptFrame) -> IrqReturn {
+    receive(ComPort::Com2, frame);
+    IrqReturn::Handled
+}
+
+/// Needs the PICs; bytes received before a `SerialStream` exists are queued.
+pub fn init_serial_irq() -> Result<(), IrqError> {
+    for port in ComPort::ALL {
+        if !PRESENT[port.index()].load(Ordering::Relaxed) {
+            continue;
+        }
+        let handler = match port {
+            ComPort::Com1 => com1_handler,
+            ComPort::Com2 => com2_handler,
+        };
+        register_irq(port.irq(), handler)?;
+        SerialPort { port }.enable_receive_interrupt();
+    }
+    Ok(())
+}
+
+initcall! {
+    name: "serial-irq",
+    stage: Interrupts,
+    depends_on: ["serial", "pic"],
+    optional: true,
+    run: |_| init_serial_irq().map_err(|error| format!("{:?}", error).into()),
+}
+
+/// Bytes received on a port, one stream per port.
+pub struct SerialStream {
+    port: ComPort,
+}
+
+impl SerialStream {
+    pub fn new(port: ComPort) -> Self {
+        assert!(
+            PRESENT[port.index()].load(Ordering::Relaxed),
+            "No UART at {:?}",
+            port
+        );
+        let prev = STREAM_TAKEN[port.index()].swap(true, Ordering::Relaxed);
+        assert!(!prev, "{:?} stream already taken", port);
+        SerialStream { port }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = &RECEIVE_QUEUES[self.port.index()];
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKERS[self.port.index()].register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKERS[self.port.index()].take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _serial_print(args: fmt::Arguments) {
+    if let Some(serial) = SERIAL.lock().as_mut() {
+        let _ = serial.write_fmt(args);
+    }
+}
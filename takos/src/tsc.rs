@@ -0,0 +1,93 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::cpu::{cpu_info, cpuid};
+use crate::initcall::initcall;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+const CALIBRATION_MS: u64 = 10;
+/// A port read takes about a microsecond, so this is far longer than
+/// `CALIBRATION_MS`.
+const MAX_GATE_READS: u32 = 1_000_000;
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Only Intel CPUs report the crystal clock, and not all of them.
+fn frequency_from_cpuid() -> Option<u64> {
+    if cpu_info().max_leaf < 0x15 {
+        return None;
+    }
+    let result = cpuid(0x15, 0);
+    if result.eax == 0 || result.ebx == 0 || result.ecx == 0 {
+        return None;
+    }
+    Some(result.ecx as u64 * result.ebx as u64 / result.eax as u64)
+}
+
+/// Counts TSC ticks while PIT channel 2 counts down `CALIBRATION_MS`.
+fn frequency_from_pit() -> Result<u64, &'static str> {
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    unsafe {
+        // Gate channel 2 on, keep the speaker off.
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+        // Channel 2, low then high byte, mode 0.
+        command.write(0xB0);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+        let start = rdtsc();
+        let mut reads = 0;
+        while gate.read() & 0x20 == 0 {
+            reads += 1;
+            if reads == MAX_GATE_READS {
+                return Err("PIT channel 2 never ran out");
+            }
+        }
+        let end = rdtsc();
+        Ok((end - start) * 1000 / CALIBRATION_MS)
+    }
+}
+
+pub fn calibrate_tsc() -> Result<(), &'static str> {
+    let frequency = match frequency_from_cpuid() {
+        Some(frequency) => frequency,
+        None => frequency_from_pit()?,
+    };
+    if frequency == 0 {
+        return Err("TSC doesn't tick");
+    }
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    Ok(())
+}
+
+initcall! {
+    name: "tsc",
+    stage: Memory,
+    depends_on: ["cpu", "heap"],
+    optional: true,
+    run: |_| calibrate_tsc().map_err(Into::into),
+}
+
+/// In Hz, 0 before `calibrate_tsc`.
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn cycles_to_micros(cycles: u64) -> Option<u64> {
+    match tsc_frequency() {
+        0 => None,
+        frequency => Some((cycles as u128 * 1_000_000 / frequency as u128) as u64),
+    }
+}